@@ -0,0 +1,41 @@
+#[path = "handshake/trace.rs"]
+mod trace;
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use anyhow::Result;
+use node::{BitcoinConnector, Crawler, RecordWriter};
+
+/// Crawl the network from the seeds in the `[crawler]` settings and write what we learned
+/// about every node to the configured output file.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let loader = settings::SettingsLoader::new().args(std::env::args().skip(1))?;
+    let log_level = loader.clone().load()?.log_level().to_string();
+    let log_level_handle = trace::init_tracing(&log_level)?;
+    let settings_updates = settings::SettingsWatcher::new(loader).spawn()?;
+    let settings = settings_updates.borrow().clone();
+    tokio::spawn(trace::follow_log_level(
+        settings_updates,
+        log_level_handle,
+        log_level,
+    ));
+
+    let crawler_settings = settings.crawler().clone();
+    let output = File::create(crawler_settings.output_path())?;
+    let mut writer = RecordWriter::new(BufWriter::new(output), crawler_settings.output_format());
+
+    let crawler = Crawler::new(BitcoinConnector::new(settings), crawler_settings);
+    let visited = crawler
+        .run(|record| {
+            if let Err(err) = writer.write(&record) {
+                tracing::error!("Failed to write the record of {}: {}", record.address, err);
+            }
+        })
+        .await;
+    writer.flush()?;
+
+    tracing::info!("Crawl finished, visited {} nodes", visited);
+    Ok(())
+}
@@ -8,12 +8,20 @@ use node::{BitcoinConnector, FromConnectionHandle};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Init settings
-    let settings = settings::Settings::new();
-
-    // Init tracing
-    trace::init_tracing();
-    tracing::info!("(set RUST_LOG=debug mode to view all transmitting messages)");
+    // Init tracing first, so the warnings of the settings watcher are printed
+    let loader = settings::SettingsLoader::new().args(std::env::args().skip(1))?;
+    let log_level = loader.clone().load()?.log_level().to_string();
+    let log_level_handle = trace::init_tracing(&log_level)?;
+
+    // Init settings, reloaded when the config file changes
+    let settings_updates = settings::SettingsWatcher::new(loader).spawn()?;
+    let settings = settings_updates.borrow().clone();
+    tokio::spawn(trace::follow_log_level(
+        settings_updates,
+        log_level_handle,
+        log_level,
+    ));
+    tracing::info!("(set NODE__LOG_LEVEL=debug to view all transmitting messages)");
 
     // Connect to the node
     let mut node = BitcoinConnector::new(settings)
@@ -1,13 +1,16 @@
-use tracing::metadata::LevelFilter;
+use anyhow::Result;
+use settings::Settings;
+use tokio::sync::watch;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{reload, EnvFilter, Registry};
 
-/// Construct a subscriber that prints JSON formatted traces to stdout
-pub fn init_tracing() {
-    let env = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .with_env_var("RUST_LOG")
-        .from_env_lossy();
+/// Changes the log filter of the subscriber at runtime.
+pub type LogLevelHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Construct a subscriber that prints compact traces to stdout, filtered by the `log_level`
+/// settings.
+pub fn init_tracing(log_level: &str) -> Result<LogLevelHandle> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(log_level)?);
 
     let fmt_layer = tracing_subscriber::fmt::layer()
         .compact()
@@ -16,7 +19,33 @@ pub fn init_tracing() {
         .with_thread_ids(false)
         .with_target(false);
     tracing_subscriber::registry()
+        .with(filter)
         .with(fmt_layer)
-        .with(env)
         .init();
+    Ok(handle)
+}
+
+/// Apply the `log_level` of the reloaded settings until the watcher stops. `log_level` is the
+/// filter the subscriber was initialized with, the watcher may already hold a newer one.
+pub async fn follow_log_level(
+    mut settings: watch::Receiver<Settings>,
+    handle: LogLevelHandle,
+    mut log_level: String,
+) {
+    loop {
+        let reloaded = settings.borrow_and_update().log_level().to_string();
+        if reloaded != log_level {
+            match EnvFilter::try_new(&reloaded) {
+                Ok(filter) => match handle.reload(filter) {
+                    Ok(()) => tracing::info!("Log level changed to {}", reloaded),
+                    Err(err) => tracing::error!("Failed to change the log level: {}", err),
+                },
+                Err(err) => tracing::error!("Invalid log level {}: {}", reloaded, err),
+            }
+            log_level = reloaded;
+        }
+        if settings.changed().await.is_err() {
+            break;
+        }
+    }
 }
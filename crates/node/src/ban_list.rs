@@ -0,0 +1,482 @@
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use thiserror::Error;
+
+/// A list of banned peers, keyed by IP address or subnet.
+///
+/// The list is cheap to clone, all clones share the same entries. When the list is backed by a
+/// file, every change is written to disk so the bans survive restarts. Both outgoing connections
+/// (`BitcoinConnector`) and listeners accepting incoming connections consult the list before
+/// talking to a peer.
+#[derive(Clone, Debug, Default)]
+pub struct BanList {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    path: Option<PathBuf>,
+    entries: Vec<BanEntry>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct BanEntry {
+    pub subnet: Subnet,
+    /// `None` means the ban never expires.
+    pub until: Option<DateTime<Utc>>,
+    pub reason: String,
+}
+
+/// An IP address with a prefix length, e.g. `192.168.0.0/16`.
+/// A plain address is a subnet with the full prefix length.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Subnet {
+    address: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Error)]
+pub enum BanListError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid ban list entry on line {line}: {reason}")]
+    InvalidEntry { line: usize, reason: String },
+    #[error("Invalid subnet {0:?}")]
+    InvalidSubnet(String),
+}
+
+impl BanList {
+    /// A ban list that only lives in memory.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Load the ban list from a file. A missing file is treated as an empty list and will be
+    /// created on the first ban. Expired entries are dropped.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, BanListError> {
+        let path = path.into();
+        let entries = read_entries(&path)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                path: Some(path),
+                entries,
+            })),
+        })
+    }
+
+    /// Switch to another file, e.g. after the settings were reloaded. The entries are replaced
+    /// by the ones in the file, `None` keeps the current entries in memory only.
+    pub fn switch_to(&self, path: Option<&Path>) -> Result<(), BanListError> {
+        let entries = match path {
+            Some(path) => read_entries(path)?,
+            None => self.entries(),
+        };
+        let mut inner = self.lock();
+        inner.path = path.map(Path::to_path_buf);
+        inner.entries = entries;
+        Ok(())
+    }
+
+    /// Ban a subnet. `duration` of `None` bans the subnet forever.
+    pub fn ban(
+        &self,
+        subnet: Subnet,
+        duration: Option<Duration>,
+        reason: impl Into<String>,
+    ) -> Result<(), BanListError> {
+        let entry = BanEntry {
+            subnet,
+            until: duration.map(|duration| Utc::now() + duration),
+            reason: reason.into(),
+        };
+        tracing::warn!(
+            "Banning {} until {:?}: {}",
+            subnet,
+            entry.until,
+            entry.reason
+        );
+        let mut inner = self.lock();
+        inner.entries.retain(|existing| existing.subnet != subnet);
+        inner.entries.push(entry);
+        inner.save()
+    }
+
+    pub fn unban(&self, subnet: Subnet) -> Result<(), BanListError> {
+        let mut inner = self.lock();
+        inner.entries.retain(|existing| existing.subnet != subnet);
+        inner.save()
+    }
+
+    /// Check whether the address falls into any banned subnet that has not expired yet.
+    pub fn is_banned(&self, address: IpAddr) -> bool {
+        let now = Utc::now();
+        self.lock()
+            .entries
+            .iter()
+            .any(|entry| entry.subnet.contains(address) && !entry.is_expired(now))
+    }
+
+    /// All entries, including the expired ones that have not been removed yet.
+    pub fn entries(&self) -> Vec<BanEntry> {
+        self.lock().entries.clone()
+    }
+
+    /// Remove the expired entries. This also happens on load and whenever the list is saved.
+    pub fn sweep(&self) -> Result<(), BanListError> {
+        let mut inner = self.lock();
+        if inner.sweep() {
+            inner.save()?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // A poisoned lock only means that another thread panicked while holding it,
+        // the entries themselves are always consistent.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl BanEntry {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.until, Some(until) if until <= now)
+    }
+}
+
+impl Inner {
+    /// Remove the expired entries, `true` when there were any.
+    fn sweep(&mut self) -> bool {
+        let now = Utc::now();
+        let before = self.entries.len();
+        self.entries.retain(|entry| !entry.is_expired(now));
+        self.entries.len() != before
+    }
+
+    /// Write the entries to disk, one `<subnet> <until> <reason>` line per entry. Expired
+    /// entries are removed first.
+    fn save(&mut self) -> Result<(), BanListError> {
+        self.sweep();
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        writeln!(file, "# subnet until(unix seconds, 0 = forever) reason")?;
+        for entry in &self.entries {
+            let until = entry.until.map_or(0, |until| until.timestamp());
+            writeln!(file, "{} {} {}", entry.subnet, until, entry.reason)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// A missing file is an empty list, expired entries are skipped.
+fn read_entries(path: &Path) -> Result<Vec<BanEntry>, BanListError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            let now = Utc::now();
+            let mut entries = parse_entries(&contents)?;
+            entries.retain(|entry| !entry.is_expired(now));
+            Ok(entries)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn parse_entries(contents: &str) -> Result<Vec<BanEntry>, BanListError> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(index, line)| {
+            let invalid = |reason: &str| BanListError::InvalidEntry {
+                line: index + 1,
+                reason: reason.to_string(),
+            };
+            let mut parts = line.trim().splitn(3, ' ');
+            let subnet = parts
+                .next()
+                .ok_or_else(|| invalid("missing subnet"))?
+                .parse()
+                .map_err(|_| invalid("invalid subnet"))?;
+            let until = parts
+                .next()
+                .ok_or_else(|| invalid("missing expiry"))?
+                .parse::<i64>()
+                .map_err(|_| invalid("invalid expiry"))?;
+            let until = match until {
+                0 => None,
+                until => Some(
+                    Utc.timestamp_opt(until, 0)
+                        .single()
+                        .ok_or_else(|| invalid("invalid expiry"))?,
+                ),
+            };
+            let reason = parts.next().unwrap_or_default().to_string();
+            Ok(BanEntry {
+                subnet,
+                until,
+                reason,
+            })
+        })
+        .collect()
+}
+
+impl Subnet {
+    /// IPv4-mapped IPv6 subnets within the mapped range are stored as the IPv4 subnet, e.g.
+    /// `::ffff:10.0.0.0/104` is `10.0.0.0/8`.
+    pub fn new(address: IpAddr, prefix: u8) -> Result<Self, BanListError> {
+        if prefix > max_prefix(address) {
+            return Err(BanListError::InvalidSubnet(format!("{address}/{prefix}")));
+        }
+        let (address, prefix) = match normalize(address) {
+            IpAddr::V4(v4) if address.is_ipv6() && prefix >= MAPPED_PREFIX => {
+                (IpAddr::V4(v4), prefix - MAPPED_PREFIX)
+            }
+            IpAddr::V4(_) if address.is_ipv6() => (address, prefix),
+            normalized => (normalized, prefix),
+        };
+        Ok(Self { address, prefix })
+    }
+
+    /// A subnet that contains exactly one address.
+    pub fn single(address: IpAddr) -> Self {
+        let address = normalize(address);
+        Self {
+            address,
+            prefix: max_prefix(address),
+        }
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, normalize(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for Subnet {
+    fn from(address: IpAddr) -> Self {
+        Self::single(address)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = BanListError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BanListError::InvalidSubnet(s.to_string());
+        match s.split_once('/') {
+            Some((address, prefix)) => {
+                let address = address.parse().map_err(|_| invalid())?;
+                let prefix = prefix.parse().map_err(|_| invalid())?;
+                Self::new(address, prefix)
+            }
+            None => Ok(Self::from(s.parse::<IpAddr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+fn max_prefix(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// The length of the `::ffff:0:0/96` prefix of IPv4-mapped IPv6 addresses.
+const MAPPED_PREFIX: u8 = 96;
+
+/// IPv4-mapped IPv6 addresses are treated as the IPv4 address they contain.
+fn normalize(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        address => address,
+    }
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix: u8) -> bool {
+    let full_bytes = usize::from(prefix / 8);
+    let remaining_bits = prefix % 8;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xFFu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+/// Convenience for callers that only have a path to a ban list.
+pub(crate) fn load_or_in_memory(path: Option<&Path>) -> BanList {
+    let Some(path) = path else {
+        return BanList::in_memory();
+    };
+    match BanList::load(path) {
+        Ok(ban_list) => ban_list,
+        Err(err) => {
+            tracing::error!(
+                "Failed to load the ban list from {}: {}. Bans will not be persisted.",
+                path.display(),
+                err
+            );
+            BanList::in_memory()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn subnets_are_parsed() {
+        let subnet: Subnet = "192.168.0.0/16".parse().unwrap();
+        assert!(subnet.contains("192.168.1.2".parse().unwrap()));
+        assert!(!subnet.contains("192.169.0.1".parse().unwrap()));
+        assert_eq!(subnet.to_string(), "192.168.0.0/16");
+
+        let subnet: Subnet = "2001:db8::/33".parse().unwrap();
+        assert!(subnet.contains("2001:db8:7fff::1".parse().unwrap()));
+        assert!(!subnet.contains("2001:db8:8000::1".parse().unwrap()));
+
+        assert_eq!(
+            "10.0.0.1".parse::<Subnet>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        for invalid in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "",
+        ] {
+            assert!(invalid.parse::<Subnet>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_normalized() {
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped());
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        assert_eq!(Subnet::single(mapped), Subnet::single(v4));
+        assert_eq!(Subnet::from(mapped), Subnet::single(v4));
+        assert_eq!(
+            Subnet::new(mapped, 104).unwrap(),
+            Subnet::new(v4, 8).unwrap()
+        );
+        assert_eq!(
+            "::ffff:10.0.0.0/104".parse::<Subnet>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert!(Subnet::single(mapped).contains(v4));
+        assert!(Subnet::single(v4).contains(mapped));
+
+        // Wider than the mapped range, stays an IPv6 subnet
+        let wide = Subnet::new(mapped, 64).unwrap();
+        assert!(wide.contains(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 1, 0, 0, 1))));
+    }
+
+    #[test]
+    fn entries_are_parsed() {
+        let contents =
+            "# subnet until reason\n\n10.0.0.0/8 0 manual\n::1/128 1700000000 Flooding of peer\n";
+        let entries = parse_entries(contents).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].until, None);
+        assert_eq!(entries[0].reason, "manual");
+        assert_eq!(entries[1].until.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(entries[1].reason, "Flooding of peer");
+
+        for (contents, line) in [("10.0.0.0/8 soon", 1), ("# header\n10.0.0.0/40 0", 2)] {
+            match parse_entries(contents) {
+                Err(BanListError::InvalidEntry { line: invalid, .. }) => assert_eq!(invalid, line),
+                result => panic!("Unexpected {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn expired_bans_are_swept() {
+        let ban_list = BanList::in_memory();
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        ban_list
+            .ban(
+                Subnet::from(address),
+                Some(Duration::seconds(-1)),
+                "old".to_string(),
+            )
+            .unwrap();
+        ban_list
+            .ban(
+                "192.168.0.0/16".parse().unwrap(),
+                None,
+                "forever".to_string(),
+            )
+            .unwrap();
+        assert!(ban_list.is_banned("192.168.3.4".parse().unwrap()));
+        ban_list.sweep().unwrap();
+        assert!(!ban_list.is_banned(address));
+        assert_eq!(ban_list.entries().len(), 1);
+    }
+
+    #[test]
+    fn expired_bans_are_dropped_on_load_and_save() {
+        let path = std::env::temp_dir().join(format!("banlist-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "10.0.0.0/8 0 forever
+10.0.0.1/32 1700000000 expired
+",
+        )
+        .unwrap();
+        let ban_list = BanList::load(&path).unwrap();
+        assert_eq!(ban_list.entries().len(), 1);
+
+        ban_list
+            .ban(
+                "192.168.0.1".parse().unwrap(),
+                Some(Duration::seconds(-1)),
+                "expired".to_string(),
+            )
+            .unwrap();
+        let saved = fs::read_to_string(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(!saved.unwrap().contains("expired"));
+        assert_eq!(ban_list.entries().len(), 1);
+    }
+}
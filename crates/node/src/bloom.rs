@@ -0,0 +1,176 @@
+//! BIP37 bloom filters.
+use std::f64::consts::LN_2;
+
+use bitcoin::network::message_bloom::{BloomFlags, FilterLoad};
+
+/// Limits of the protocol, peers reject larger filters.
+const MAX_FILTER_SIZE: usize = 36_000;
+const MAX_HASH_FUNCS: u32 = 50;
+
+/// Multiplier of the hash function index in the murmur3 seed.
+const SEED_MULTIPLIER: u32 = 0xFBA4_C795;
+
+/// A BIP37 bloom filter, loaded into a peer with `filterload`.
+///
+/// The peer only relays the transactions that match the filter. A filter never has false
+/// negatives, the false positive rate hides which transactions we are actually interested in.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+    flags: BloomFlags,
+}
+
+impl BloomFilter {
+    /// A filter sized for `elements` items with the given false positive rate.
+    /// `tweak` randomizes the hash functions, use a random value per filter.
+    pub fn new(elements: usize, false_positive_rate: f64, tweak: u32, flags: BloomFlags) -> Self {
+        let elements = elements.max(1) as f64;
+        let size = (-1.0 / LN_2.powi(2) * elements * false_positive_rate.ln() / 8.0) as usize;
+        let size = size.clamp(1, MAX_FILTER_SIZE);
+        let hash_funcs = (size as f64 * 8.0 / elements * LN_2) as u32;
+        Self {
+            data: vec![0; size],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+            flags,
+        }
+    }
+
+    /// Add an element, e.g. a script pubkey, a public key or a serialized outpoint.
+    pub fn insert(&mut self, element: &[u8]) {
+        for hash_num in 0..self.hash_funcs {
+            let index = self.bit_index(hash_num, element);
+            self.data[index >> 3] |= 1 << (index & 7);
+        }
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|hash_num| {
+            let index = self.bit_index(hash_num, element);
+            self.data[index >> 3] & (1 << (index & 7)) != 0
+        })
+    }
+
+    /// The `filterload` message for this filter.
+    pub fn filter_load(&self) -> FilterLoad {
+        FilterLoad {
+            filter: self.data.clone(),
+            hash_funcs: self.hash_funcs,
+            tweak: self.tweak,
+            flags: self.flags,
+        }
+    }
+
+    fn bit_index(&self, hash_num: u32, element: &[u8]) -> usize {
+        let seed = hash_num
+            .wrapping_mul(SEED_MULTIPLIER)
+            .wrapping_add(self.tweak);
+        murmur3(seed, element) as usize % (self.data.len() * 8)
+    }
+}
+
+/// 32-bit MurmurHash3, the hash function prescribed by BIP37.
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut hash = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |k, byte| (k << 8) | u32::from(*byte));
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::hex::FromHex;
+
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        Vec::from_hex(s).unwrap()
+    }
+
+    #[test]
+    fn murmur3_matches_bitcoin_core() {
+        // (expected, seed, data) from bitcoin core's hash_tests.cpp
+        let vectors = [
+            (0x00000000, 0x00000000, ""),
+            (0x6a396f08, 0xFBA4C795, ""),
+            (0x81f16f39, 0xffffffff, ""),
+            (0x514e28b7, 0x00000000, "00"),
+            (0xea3f0b17, 0xFBA4C795, "00"),
+            (0xfd6cf10d, 0x00000000, "ff"),
+            (0x16c6b7ab, 0x00000000, "0011"),
+            (0x8eb51c3d, 0x00000000, "001122"),
+            (0xb4471bf8, 0x00000000, "00112233"),
+            (0xe2301fa8, 0x00000000, "0011223344"),
+            (0xfc2e4a15, 0x00000000, "001122334455"),
+            (0xb074502c, 0x00000000, "00112233445566"),
+            (0x8034d2a0, 0x00000000, "0011223344556677"),
+            (0xb4698def, 0x00000000, "001122334455667788"),
+        ];
+        for (expected, seed, data) in vectors {
+            assert_eq!(murmur3(seed, &hex(data)), expected, "{data}");
+        }
+    }
+
+    fn filter(tweak: u32) -> BloomFilter {
+        let mut filter = BloomFilter::new(3, 0.01, tweak, BloomFlags::All);
+        filter.insert(&hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+        assert!(filter.contains(&hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        // One bit different in the first byte
+        assert!(!filter.contains(&hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        filter.insert(&hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee"));
+        assert!(filter.contains(&hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee")));
+        filter.insert(&hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5"));
+        assert!(filter.contains(&hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5")));
+        filter
+    }
+
+    #[test]
+    fn filters_serialize_like_bitcoin_core() {
+        // bloom_create_insert_serialize(_with_tweak) of bitcoin core's bloom_tests.cpp
+        assert_eq!(
+            serialize(&filter(0).filter_load()),
+            hex("03614e9b050000000000000001")
+        );
+        assert_eq!(
+            serialize(&filter(2147483649).filter_load()),
+            hex("03ce4299050000000100008001")
+        );
+    }
+
+    #[test]
+    fn filters_are_within_the_protocol_limits() {
+        let filter = BloomFilter::new(1_000_000, 0.000_001, 0, BloomFlags::None);
+        let filter_load = filter.filter_load();
+        assert_eq!(filter_load.filter.len(), MAX_FILTER_SIZE);
+        assert!(filter_load.hash_funcs <= MAX_HASH_FUNCS);
+    }
+}
@@ -1,42 +1,363 @@
 mod actor;
+mod announced;
+mod compact_blocks;
+mod custom_message;
+mod default_handlers;
+mod filter_server;
+mod frame;
 mod handle;
+mod happy_eyeballs;
 mod incoming_receiver;
+mod message_handler;
+mod misbehavior;
+mod outbound_queue;
+mod outbound_writer;
+mod pcap;
 mod protocol_driver;
+mod reconciliation;
+mod socks5;
+mod stats;
+mod tip_tracker;
+mod version_builder;
 
 use std::marker::PhantomData;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_bloom::FilterAdd;
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::{BlockHash, Transaction};
+pub use custom_message::{AnyMessage, CustomMessage, MessageRegistry};
 use error::Error;
-pub use handle::{ConnectionHandle, FromConnectionHandle};
-use settings::Settings;
+pub use frame::FrameError;
+use futures::Stream;
+pub use handle::{ConnectionHandle, ConnectionOptions, FromConnectionHandle};
+use message_handler::HandlerFactory;
+pub use message_handler::{HandlerAction, HandlerContext, MessageHandler};
+pub use misbehavior::{DisconnectReason, Misbehavior};
+pub use outbound_queue::{Priority, QueueDepth};
+use settings::{PeerAddress, PeerSettings, Settings, SettingsError};
+pub use stats::{CommandStats, CompactBlockStats, ConnectionStats, StatsRecorder, OTHER_COMMAND};
+pub(crate) use tip_tracker::new_tips;
+pub use tip_tracker::NewTip;
+use tokio::sync::watch;
 use tracing::instrument;
+pub use version_builder::VersionBuilder;
 
+use crate::ban_list::{self, BanList};
+use crate::bloom::BloomFilter;
 use crate::error;
+use crate::filter_index::FilterIndex;
+use crate::mempool::Mempool;
+use crate::network_time::NetworkTime;
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+/// Inventory type of a block filtered by the bloom filter (BIP37), unknown to rust-bitcoin.
+const MSG_FILTERED_BLOCK: u32 = 3;
+
+#[derive(Clone, Debug)]
 pub struct BitcoinConnector {
     settings: Settings,
+    ban_list: BanList,
+    mempool: Mempool,
+    network_time: NetworkTime,
+    filter_index: Option<FilterIndex>,
+    version: VersionBuilder,
+    /// Publishes updated settings to the connections of the connector.
+    settings_updates: Arc<watch::Sender<Settings>>,
+    message_handlers: Vec<HandlerFactory>,
+    message_registry: MessageRegistry,
 }
 
 impl BitcoinConnector {
+    /// Create a new connector. The ban list is loaded from the path in the settings, or kept in
+    /// memory when no path is configured.
     pub fn new(settings: Settings) -> Self {
-        Self { settings }
+        let ban_list = ban_list::load_or_in_memory(settings.misbehavior().ban_list_path());
+        Self {
+            network_time: NetworkTime::new(settings.network_time().clone()),
+            version: VersionBuilder::new(settings.handshake()),
+            settings_updates: Arc::new(watch::channel(settings.clone()).0),
+            message_handlers: Vec::new(),
+            message_registry: MessageRegistry::new(),
+            settings,
+            ban_list,
+            mempool: Mempool::new(),
+            filter_index: None,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Switch to reloaded settings, e.g. from a [`settings::SettingsWatcher`]. Live connections
+    /// of this connector and its clones pick up the new ping interval, a changed ban list file
+    /// is loaded. Nothing changes when the settings can't be applied live, see
+    /// [`Settings::check_live_update`].
+    pub fn update_settings(&mut self, settings: Settings) -> Result<(), SettingsError> {
+        self.settings.check_live_update(&settings)?;
+        let ban_list_path = settings.misbehavior().ban_list_path();
+        if ban_list_path != self.settings.misbehavior().ban_list_path() {
+            if let Err(err) = self.ban_list.switch_to(ban_list_path) {
+                tracing::error!("Failed to switch the ban list: {}", err);
+            }
+        }
+        self.network_time
+            .set_settings(settings.network_time().clone());
+        self.settings_updates.send_replace(settings.clone());
+        self.settings = settings;
+        Ok(())
+    }
+
+    /// Share a ban list between multiple connectors (and listeners).
+    pub fn with_ban_list(mut self, ban_list: BanList) -> Self {
+        self.ban_list = ban_list;
+        self
+    }
+
+    pub fn ban_list(&self) -> &BanList {
+        &self.ban_list
+    }
+
+    /// Share a mempool between multiple connectors, so that transactions relayed by one peer
+    /// help to rebuild the compact blocks of another.
+    pub fn with_mempool(mut self, mempool: Mempool) -> Self {
+        self.mempool = mempool;
+        self
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// Share the network-adjusted time between multiple connectors, so that it is the median
+    /// of all their peers.
+    pub fn with_network_time(mut self, network_time: NetworkTime) -> Self {
+        self.network_time = network_time;
+        self
+    }
+
+    /// The clock offsets of the peers we connected to.
+    pub fn network_time(&self) -> &NetworkTime {
+        &self.network_time
+    }
+
+    /// Serve compact block filters from the index and advertise `NODE_COMPACT_FILTERS`. Only
+    /// the blocks indexed by [`FilterIndex::sync`] are served, a request for a later block
+    /// starts a sync in the background.
+    pub fn with_filter_index(mut self, filter_index: FilterIndex) -> Self {
+        self.filter_index = Some(filter_index);
+        self
+    }
+
+    /// Change how we introduce ourselves to the peer, e.g. to test different node profiles.
+    pub fn with_version_builder(mut self, version: VersionBuilder) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn version_builder(&self) -> &VersionBuilder {
+        &self.version
+    }
+
+    /// Add a message handler to the connections of this connector. Every connection gets a
+    /// clone of the handler, handlers are called in the order they were added.
+    pub fn with_message_handler<H>(mut self, handler: H) -> Self
+    where
+        H: MessageHandler + Clone + Sync + 'static,
+    {
+        self.message_handlers.push(HandlerFactory::new(handler));
+        self
+    }
+
+    /// Receive messages with the command of `M` as [`FromConnectionHandle::Custom`] on the
+    /// connections of this connector.
+    ///
+    /// # Panics
+    ///
+    /// Panics when [`CustomMessage::COMMAND`] is not a valid command.
+    pub fn with_custom_message<M: CustomMessage>(mut self) -> Self {
+        self.message_registry.register::<M>();
+        self
+    }
+
+    pub fn message_registry(&self) -> &MessageRegistry {
+        &self.message_registry
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            capture: self.settings.capture().cloned(),
+            misbehavior: self.settings.misbehavior().clone(),
+            ban_list: self.ban_list.clone(),
+            send_buffer_limit: self.settings.send_buffer_limit(),
+            ping_interval: self.settings.ping_interval(),
+            compact_blocks: self.settings.compact_blocks(),
+            mempool: self.mempool.clone(),
+            network_time: self.network_time.clone(),
+            filter_index: self.filter_index.clone(),
+            version: self.version.clone(),
+            proxy: None,
+            host: None,
+            settings_updates: Some(self.settings_updates.subscribe()),
+            message_handlers: self.message_handlers.clone(),
+            message_registry: self.message_registry.clone(),
+            reconciliation: self.settings.reconciliation().clone(),
+            fee_filter: self.settings.fee_filter().clone(),
+            inbound: false,
+        }
     }
 
     /// Start a new connection to the bitcoin node.
     #[instrument(skip(self), err)]
     pub async fn connect(self) -> Result<BitcoinConnection<PreHandshake>, Error> {
         let peer_address = self.settings.peer_address();
+        self.connect_to(peer_address).await
+    }
+
+    /// Start a new connection to another node than the configured one, using the same options.
+    #[instrument(skip(self), err)]
+    pub async fn connect_to(
+        &self,
+        peer_address: SocketAddr,
+    ) -> Result<BitcoinConnection<PreHandshake>, Error> {
+        self.open(peer_address, self.settings.peer_network(), self.options())
+            .await
+    }
+
+    /// Resolve `host:port` and handshake with the first of its addresses that answers, see
+    /// [`Self::connect_any`].
+    #[instrument(skip(self), err)]
+    pub async fn connect_host(&self, host: &str) -> Result<BitcoinConnection<Connected>, Error> {
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host(host).await?.collect();
+        self.connect_any(&addresses).await
+    }
+
+    /// Race connection attempts to the addresses, e.g. the IPv6 and IPv4 addresses of a peer
+    /// (happy eyeballs, RFC 8305). The families take turns and a new attempt is started every
+    /// 250 ms while the earlier ones are still pending. The first connection to complete the
+    /// handshake is returned, the others are closed.
+    #[instrument(skip(self), err)]
+    pub async fn connect_any(
+        &self,
+        addresses: &[SocketAddr],
+    ) -> Result<BitcoinConnection<Connected>, Error> {
+        let connect_timeout = self.settings.connections().connect_timeout();
+        happy_eyeballs::race(addresses, |address| async move {
+            let handshake = async { self.connect_to(address).await?.perform_handshake().await };
+            tokio::time::timeout(connect_timeout, handshake)
+                .await
+                .unwrap_or(Err(Error::Timeout))
+        })
+        .await
+    }
+
+    /// Start a new connection to a configured peer (see [`Settings::peers`]), with its
+    /// network, proxy and handshake overrides.
+    #[instrument(skip(self), err)]
+    pub async fn connect_peer(
+        &self,
+        peer: &PeerSettings,
+    ) -> Result<BitcoinConnection<PreHandshake>, Error> {
+        let mut options = self.options();
+        options.proxy = peer.proxy();
+        options.version = options
+            .version
+            .with_overrides(self.settings.handshake(), peer.handshake());
+        let network = peer.network().unwrap_or(self.settings.peer_network());
+        let peer_address = match (peer.address(), peer.proxy()) {
+            (PeerAddress::Socket(address), _) => *address,
+            // The proxy resolves the host, we never learn its IP address
+            (host @ PeerAddress::Host { port, .. }, Some(_)) => {
+                options.host = Some(host.clone());
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port)
+            }
+            (host @ PeerAddress::Host { .. }, None) => {
+                options.host = Some(host.clone());
+                tokio::net::lookup_host(host.to_string())
+                    .await?
+                    .next()
+                    .ok_or(Error::NoAddress)?
+            }
+        };
+        self.open(peer_address, network, options).await
+    }
+
+    async fn open(
+        &self,
+        peer_address: SocketAddr,
+        peer_network: bitcoin::Network,
+        options: ConnectionOptions,
+    ) -> Result<BitcoinConnection<PreHandshake>, Error> {
         let sender_address = self.settings.sender_address();
-        let peer_network = self.settings.peer_network();
+        if self.ban_list.is_banned(peer_address.ip()) {
+            return Err(Error::Banned(peer_address.ip()));
+        }
 
         let connection_handle =
-            ConnectionHandle::new(peer_address, sender_address, peer_network).await?;
-        let connection = BitcoinConnection::<PreHandshake>::new(self.settings, connection_handle);
+            ConnectionHandle::new(peer_address, sender_address, peer_network, options).await?;
+        let connection =
+            BitcoinConnection::<PreHandshake>::new(self.settings.clone(), connection_handle);
 
         Ok(connection)
     }
 }
 
+/// Accepts connections from inbound peers. Peers on the ban list are refused, every accepted
+/// connection uses the options of the connector it was created from.
+#[derive(Debug)]
+pub struct BitcoinListener {
+    listener: tokio::net::TcpListener,
+    connector: BitcoinConnector,
+}
+
+impl BitcoinListener {
+    pub async fn bind(address: SocketAddr, connector: BitcoinConnector) -> Result<Self, Error> {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        tracing::info!("Listening for peers on {}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            connector,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Wait for the next peer that is not banned. The handshake still has to be performed.
+    pub async fn accept(&self) -> Result<BitcoinConnection<PreHandshake>, Error> {
+        loop {
+            let (stream, peer_address) = self.listener.accept().await?;
+            if self.connector.ban_list.is_banned(peer_address.ip()) {
+                tracing::info!("Refusing banned peer {}", peer_address);
+                continue;
+            }
+            tracing::info!("Accepted peer {}", peer_address);
+            // The connection tasks work on blocking sockets
+            let stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+
+            let mut options = self.connector.options();
+            options.inbound = true;
+            let connection_handle = ConnectionHandle::from_stream(
+                stream,
+                self.listener.local_addr()?,
+                self.connector.settings.peer_network(),
+                options,
+            )?;
+            return Ok(BitcoinConnection::<PreHandshake>::new(
+                self.connector.settings.clone(),
+                connection_handle,
+            ));
+        }
+    }
+}
+
 /// Typestate pattern enforced connection. To ensure that the handshake is performed before sending any subsequent messages
 #[derive(Debug)]
 pub struct BitcoinConnection<T> {
@@ -80,6 +401,153 @@ impl BitcoinConnection<Connected> {
         self.connection.send_get_addr().await
     }
 
+    /// The IP address of the peer. Peers reached by a host name through a proxy have no IP
+    /// address we know of, it is unspecified with the port of the peer.
+    pub fn peer_address(&self) -> SocketAddr {
+        self.connection.peer_address()
+    }
+
+    /// The address the peer was dialed by, e.g. the `host:port` of a configured peer.
+    pub fn peer_target(&self) -> &PeerAddress {
+        self.connection.peer_target()
+    }
+
+    /// The version message the peer sent during the handshake.
+    pub fn peer_version(&self) -> &VersionMessage {
+        self.connection
+            .peer_version()
+            .expect("The peer version is known after the handshake")
+    }
+
+    /// How far the clock of the peer is ahead of ours, `None` when the timestamp of its version
+    /// is out of range.
+    pub fn peer_clock_skew(&self) -> Option<chrono::Duration> {
+        self.connection.peer_clock_skew()
+    }
+
+    /// The services the peer advertised during the handshake.
+    pub fn peer_services(&self) -> ServiceFlags {
+        self.peer_version().services
+    }
+
+    /// Whether the peer announces transactions by wtxid (BIP339 `wtxidrelay` was exchanged
+    /// during the handshake). Transactions are then announced and requested with
+    /// [`Inventory::WTx`], otherwise by txid.
+    pub fn wtxid_relay(&self) -> bool {
+        self.stats().wtxid_relay
+    }
+
+    /// Send a message, waiting while the peer can't keep up with our messages.
+    pub async fn send(&self, message: NetworkMessage) -> Result<(), Error> {
+        self.connection.send(message).await
+    }
+
+    /// Send a message without waiting, fails with [`Error::Backpressure`] when the outbound
+    /// queue is full.
+    pub fn try_send(&self, message: NetworkMessage) -> Result<(), Error> {
+        self.connection.try_send(message)
+    }
+
+    /// Send a custom message, waiting while the peer can't keep up with our messages.
+    pub async fn send_custom<M: CustomMessage>(&self, message: &M) -> Result<(), Error> {
+        self.connection.send_custom(message).await
+    }
+
+    /// Announce a transaction to the peer and answer its `getdata` for it. Peers that
+    /// negotiated BIP330 reconciliation learn about it in the next reconciliation, the other
+    /// peers get an `inv` right away.
+    pub async fn announce_transaction(&self, transaction: Transaction) -> Result<(), Error> {
+        self.connection
+            .announce_transaction(transaction, None)
+            .await
+    }
+
+    /// Announce a transaction that pays `fee` satoshis. It is not announced when its fee rate
+    /// is below the BIP133 fee filter of the peer.
+    pub async fn announce_transaction_with_fee(
+        &self,
+        transaction: Transaction,
+        fee: u64,
+    ) -> Result<(), Error> {
+        self.connection
+            .announce_transaction(transaction, Some(fee))
+            .await
+    }
+
+    /// Ask the peer for a block as a BIP152 compact block. The rebuilt block is delivered as
+    /// [`FromConnectionHandle::ReconstructedBlock`], or as a full block when the reconstruction
+    /// fails.
+    pub async fn request_compact_block(&self, block_hash: BlockHash) -> Result<(), Error> {
+        self.connection
+            .send(NetworkMessage::GetData(vec![Inventory::CompactBlock(
+                block_hash,
+            )]))
+            .await
+    }
+
+    /// Load a BIP37 bloom filter, the peer will only relay matching transactions.
+    pub async fn load_bloom_filter(&self, filter: &BloomFilter) -> Result<(), Error> {
+        self.require_service(ServiceFlags::BLOOM)?;
+        self.connection
+            .send(NetworkMessage::FilterLoad(filter.filter_load()))
+            .await
+    }
+
+    /// Add an element to the loaded bloom filter.
+    pub async fn add_to_bloom_filter(&self, element: Vec<u8>) -> Result<(), Error> {
+        self.require_service(ServiceFlags::BLOOM)?;
+        self.connection
+            .send(NetworkMessage::FilterAdd(FilterAdd { data: element }))
+            .await
+    }
+
+    /// Remove the bloom filter, the peer relays all transactions again.
+    pub async fn clear_bloom_filter(&self) -> Result<(), Error> {
+        self.require_service(ServiceFlags::BLOOM)?;
+        self.connection.send(NetworkMessage::FilterClear).await
+    }
+
+    /// Ask for a block filtered by the loaded bloom filter. The matching transactions are
+    /// delivered as [`FromConnectionHandle::FilteredTransaction`].
+    pub async fn request_filtered_block(&self, block_hash: BlockHash) -> Result<(), Error> {
+        self.require_service(ServiceFlags::BLOOM)?;
+        self.connection
+            .send(NetworkMessage::GetData(vec![Inventory::Unknown {
+                inv_type: MSG_FILTERED_BLOCK,
+                hash: block_hash.into_inner(),
+            }]))
+            .await
+    }
+
+    fn require_service(&self, service: ServiceFlags) -> Result<(), Error> {
+        if !self.peer_services().has(service) {
+            return Err(Error::MissingService(service));
+        }
+        Ok(())
+    }
+
+    /// How much data is waiting to be written to the peer.
+    pub fn outbound_queue_depth(&self) -> QueueDepth {
+        self.connection.outbound_queue_depth()
+    }
+
+    /// A snapshot of the traffic statistics of the connection.
+    pub fn stats(&self) -> ConnectionStats {
+        self.connection.stats()
+    }
+
+    /// The live statistics of the connection, e.g. to register them with a metrics exporter.
+    pub fn stats_recorder(&self) -> StatsRecorder {
+        self.connection.stats_recorder()
+    }
+
+    /// The blocks the peer announces that are higher than its best block before, from now on.
+    /// Heights are counted from the genesis block, the headers of a peer that is ahead are
+    /// synced first.
+    pub fn new_tip(&self) -> impl Stream<Item = NewTip> {
+        new_tips(self.connection.subscribe(), self.peer_address())
+    }
+
     /// Expose receive method from the connection handle for demo purposes. In a real application this would be replaced by a more abstract interface.
     pub async fn receive(&mut self) -> Result<FromConnectionHandle, Error> {
         self.connection.receive().await
@@ -1,33 +1,92 @@
 use std::net::Shutdown;
+use std::sync::Arc;
 
 use bitcoin::network::constants;
+use settings::{CompactBlockSettings, FeeFilterSettings, Settings};
 
-use super::handle::ToConnectionHandle;
+use super::compact_blocks::CompactBlockReconstructor;
+use super::custom_message::MessageRegistry;
+use super::handle::{ConnectionOptions, ToConnectionHandle};
 use super::incoming_receiver::IncomingReceiver;
+use super::message_handler::HandlerFactory;
+use super::misbehavior::MisbehaviorTracker;
+use super::outbound_queue::OutboundQueue;
+use super::outbound_writer::OutboundWriter;
+use super::pcap::{PcapWriter, SharedCapture};
 use super::protocol_driver::ProtocolDriver;
+use super::reconciliation::Reconciliation;
+use super::stats::StatsRecorder;
 use super::{protocol_driver, FromConnectionHandle};
 use crate::error::Error;
+use crate::filter_index::FilterIndex;
+use crate::mempool::Mempool;
+use crate::network_time::NetworkTime;
+
+/// Messages read from the peer that the driver has not handled yet.
+const READ_CHANNEL_SIZE: usize = 1024;
 
 pub struct ConnectionActor {
     stream: std::net::TcpStream,
     incoming_commands: tokio::sync::mpsc::Receiver<ToConnectionHandle>,
     from_node: tokio::sync::broadcast::Sender<FromConnectionHandle>,
     network: constants::Network,
+    capture: Option<SharedCapture>,
+    misbehavior: MisbehaviorTracker,
+    outbound: Arc<OutboundQueue>,
+    stats: StatsRecorder,
+    ping_interval: std::time::Duration,
+    compact_blocks: CompactBlockSettings,
+    mempool: Mempool,
+    filter_index: Option<FilterIndex>,
+    settings_updates: Option<tokio::sync::watch::Receiver<Settings>>,
+    message_handlers: Vec<HandlerFactory>,
+    message_registry: MessageRegistry,
+    reconciliation: Reconciliation,
+    fee_filter: FeeFilterSettings,
+    network_time: NetworkTime,
 }
 
 impl ConnectionActor {
     pub(super) fn new(
-        address: std::net::SocketAddr,
+        stream: std::net::TcpStream,
         incoming_commands: tokio::sync::mpsc::Receiver<ToConnectionHandle>,
         from_node: tokio::sync::broadcast::Sender<FromConnectionHandle>,
         network: constants::Network,
+        outbound: Arc<OutboundQueue>,
+        stats: StatsRecorder,
+        options: ConnectionOptions,
     ) -> Result<Self, Error> {
-        let stream = std::net::TcpStream::connect(address)?;
+        // Not the address of the stream, that is the proxy when there is one
+        let address = stats.peer_address();
+        let capture = match options.capture {
+            Some(capture) => {
+                let writer = PcapWriter::new(&capture, stream.local_addr()?, address)?;
+                tracing::info!("Capturing traffic to {}", writer.path().display());
+                Some(writer.shared())
+            }
+            None => None,
+        };
+        let misbehavior =
+            MisbehaviorTracker::new(address.ip(), options.ban_list, &options.misbehavior);
         Ok(Self {
             stream,
             incoming_commands,
             from_node,
             network,
+            capture,
+            misbehavior,
+            outbound,
+            stats,
+            ping_interval: options.ping_interval,
+            compact_blocks: options.compact_blocks,
+            mempool: options.mempool,
+            filter_index: options.filter_index,
+            settings_updates: options.settings_updates,
+            message_handlers: options.message_handlers,
+            message_registry: options.message_registry,
+            reconciliation: Reconciliation::new(options.reconciliation, options.inbound),
+            fee_filter: options.fee_filter,
+            network_time: options.network_time,
         })
     }
 
@@ -38,33 +97,64 @@ impl ConnectionActor {
         let read_stream = self.stream.try_clone().expect("Failed to clone stream");
         let write_stream = self.stream.try_clone().expect("Failed to clone stream");
 
+        // Messages read from the peer pass the driver before they reach the outside world
+        let (read_sender, read_receiver) = tokio::sync::mpsc::channel(READ_CHANNEL_SIZE);
         let mut protocol_driver_task = Self::init_protocol_driver(
-            ProtocolDriver::new(write_stream, self.network),
+            ProtocolDriver::new(
+                self.outbound.clone(),
+                self.network,
+                self.from_node.clone(),
+                self.misbehavior,
+                self.stats.clone(),
+                self.ping_interval,
+                CompactBlockReconstructor::new(
+                    self.compact_blocks,
+                    self.mempool,
+                    self.stats.clone(),
+                ),
+            )
+            .with_filter_index(self.filter_index)
+            .with_settings_updates(self.settings_updates)
+            .with_message_handlers(&self.message_handlers)
+            .with_message_registry(self.message_registry)
+            .with_reconciliation(self.reconciliation)
+            .with_fee_filter(self.fee_filter)
+            .with_network_time(self.network_time),
             self.incoming_commands,
-            self.from_node.subscribe(),
+            read_receiver,
         );
-        let mut broadcast_task =
-            Self::init_node_message_broadcast(IncomingReceiver::new(self.from_node, read_stream));
-        loop {
-            tokio::select! {
-                _ =(&mut broadcast_task) => {
-                    tracing::warn!("Read task closed");
-                    break;
-                }
-                _ =(&mut protocol_driver_task) => {
-                    tracing::warn!("Read task closed");
-                    break;
-                }
-                else => {
-                    tracing::warn!("Both streams are closed");
-                    break;
-                }
-            };
-        }
+        let mut broadcast_task = Self::init_node_message_broadcast(IncomingReceiver::new(
+            read_sender,
+            read_stream,
+            self.network,
+            self.capture.clone(),
+            self.stats.clone(),
+        ));
+        let mut write_task = Self::init_outbound_writer(OutboundWriter::new(
+            self.outbound.clone(),
+            write_stream,
+            self.capture,
+            self.stats,
+        ));
+        tokio::select! {
+            _ = (&mut broadcast_task) => {
+                tracing::warn!("Read task closed");
+                // Let the driver pass on the messages that were read before
+                let _ = (&mut protocol_driver_task).await;
+            }
+            _ = (&mut protocol_driver_task) => {
+                tracing::warn!("Protocol driver task closed");
+            }
+            _ = (&mut write_task) => {
+                tracing::warn!("Write task closed");
+            }
+        };
 
-        // Abort the tasks
+        // Abort the tasks and wake up everyone waiting for the outbound queue
         broadcast_task.abort();
         protocol_driver_task.abort();
+        write_task.abort();
+        self.outbound.close();
 
         tracing::warn!("Failed to process stream messages, closing connection");
         let _ = self.stream.shutdown(Shutdown::Both);
@@ -76,22 +166,84 @@ impl ConnectionActor {
     fn init_protocol_driver(
         mut protocol_driver: ProtocolDriver,
         incoming_commands: tokio::sync::mpsc::Receiver<ToConnectionHandle>,
-        from_node: tokio::sync::broadcast::Receiver<FromConnectionHandle>,
+        from_node: tokio::sync::mpsc::Receiver<FromConnectionHandle>,
     ) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
             protocol_driver
                 .drive(
                     protocol_driver::mpsc_to_stream(incoming_commands),
-                    protocol_driver::broadcast_to_stream(from_node),
+                    protocol_driver::mpsc_to_stream(from_node),
                 )
                 .await;
         })
     }
 
-    /// Receive messages from the outside world and write them to the stream
+    /// Write the queued messages to the stream
+    fn init_outbound_writer(mut writer: OutboundWriter) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            writer.write_to_node().await;
+        })
+    }
+
+    /// Read messages from the stream and broadcast them to the outside world
     fn init_node_message_broadcast(mut receiver: IncomingReceiver) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
             receiver.broadcast_from_node().await;
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode;
+    use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+    use settings::SettingsLoader;
+
+    use super::*;
+    use crate::ban_list::BanList;
+    use crate::BitcoinConnector;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_last_message_before_the_peer_closes_is_scored() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = std::thread::spawn(move || {
+            // Easier than the regtest proof of work limit allows
+            let mut header = genesis_block(constants::Network::Regtest).header;
+            header.bits = 0x2100ffff;
+            let headers = RawNetworkMessage {
+                magic: constants::Network::Regtest.magic(),
+                payload: NetworkMessage::Headers(vec![header]),
+            };
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&encode::serialize(&headers)).unwrap();
+        });
+
+        let settings = SettingsLoader::new()
+            .env(Vec::<(String, String)>::new())
+            .set("peer_address", address.to_string())
+            .set("peer_network", "regtest")
+            .load()
+            .unwrap();
+        let ban_list = BanList::in_memory();
+        let _connection = BitcoinConnector::new(settings)
+            .with_ban_list(ban_list.clone())
+            .connect()
+            .await
+            .unwrap();
+        peer.join().unwrap();
+
+        let banned = async {
+            while !ban_list.is_banned(address.ip()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(5), banned)
+            .await
+            .is_ok());
+    }
+}
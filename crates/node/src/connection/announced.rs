@@ -0,0 +1,69 @@
+//! The transactions we announced to a peer, the only ones it may ask for.
+use std::collections::{HashSet, VecDeque};
+
+use bitcoin::network::message_blockdata::Inventory;
+
+/// Announcements that are remembered, older ones are forgotten. Same as the default mempool
+/// capacity.
+const MAX_ANNOUNCED: usize = 50_000;
+
+/// Serving any mempool transaction would tell the peer which transactions other peers relayed
+/// to us, so only announced transactions are served.
+#[derive(Debug, Default)]
+pub(crate) struct Announced {
+    items: HashSet<Inventory>,
+    /// Announcement order, used to forget the oldest announcements first.
+    order: VecDeque<Inventory>,
+}
+
+impl Announced {
+    /// Remember the transactions of an `inv` we send.
+    pub(crate) fn record(&mut self, inventory: &[Inventory]) {
+        for item in inventory.iter().filter_map(key) {
+            if !self.items.insert(item) {
+                continue;
+            }
+            self.order.push_back(item);
+            if self.order.len() > MAX_ANNOUNCED {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.items.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn contains(&self, item: &Inventory) -> bool {
+        matches!(key(item), Some(item) if self.items.contains(&item))
+    }
+}
+
+/// Transactions are requested by txid with or without witness, the key is the same.
+fn key(item: &Inventory) -> Option<Inventory> {
+    match item {
+        Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+            Some(Inventory::Transaction(*txid))
+        }
+        Inventory::WTx(wtxid) => Some(Inventory::WTx(*wtxid)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Txid, Wtxid};
+
+    use super::*;
+
+    #[test]
+    fn only_announced_transactions_are_known() {
+        let txid = Txid::from_inner([1; 32]);
+        let wtxid = Wtxid::from_inner([2; 32]);
+        let mut announced = Announced::default();
+        announced.record(&[Inventory::Transaction(txid), Inventory::WTx(wtxid)]);
+        assert!(announced.contains(&Inventory::WitnessTransaction(txid)));
+        assert!(announced.contains(&Inventory::WTx(wtxid)));
+        assert!(!announced.contains(&Inventory::WTx(Wtxid::from_inner([3; 32]))));
+        assert!(!announced.contains(&Inventory::Block(Hash::all_zeros())));
+    }
+}
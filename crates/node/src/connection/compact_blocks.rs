@@ -0,0 +1,392 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::network::message_compact_blocks::GetBlockTxn;
+use bitcoin::util::bip152::{
+    BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, ShortId,
+};
+use bitcoin::{Block, BlockHash, BlockHeader, Transaction, Wtxid};
+use settings::CompactBlockSettings;
+
+use super::stats::StatsRecorder;
+use crate::mempool::Mempool;
+
+/// Compact block version 2 uses wtxids for the short IDs (BIP152 with segwit).
+pub(crate) const COMPACT_BLOCK_VERSION: u64 = 2;
+/// Blocks of a peer waiting for their missing transactions, the oldest is given up first.
+const MAX_PENDING_BLOCKS: usize = 3;
+/// No valid block has more transactions: the maximum block weight divided by the weight of
+/// the smallest serializable transaction, same as bitcoin core.
+const MAX_BLOCK_TRANSACTIONS: usize = 4_000_000 / 40;
+
+/// Outcome of processing a `cmpctblock` or `blocktxn` message.
+#[derive(Debug)]
+pub(crate) enum Reconstruction {
+    /// All transactions were found, the block is complete.
+    Complete(Block),
+    /// Some transactions are missing and have to be requested with `getblocktxn`.
+    Incomplete(GetBlockTxn),
+    /// The block cannot be reconstructed (e.g. short ID collision), it has to be
+    /// downloaded in full.
+    Failed(BlockHash),
+    /// The compact block can't describe a valid block, see [`has_valid_size`].
+    Invalid(BlockHash),
+}
+
+/// A block with some transactions still missing.
+#[derive(Debug)]
+struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+/// Rebuilds blocks announced with BIP152 compact blocks from the transactions in the mempool.
+#[derive(Debug)]
+pub(crate) struct CompactBlockReconstructor {
+    settings: CompactBlockSettings,
+    mempool: Mempool,
+    stats: StatsRecorder,
+    /// Blocks waiting for a `blocktxn` response, oldest first.
+    pending: VecDeque<(BlockHash, PartialBlock)>,
+}
+
+impl CompactBlockReconstructor {
+    pub(crate) fn new(
+        settings: CompactBlockSettings,
+        mempool: Mempool,
+        stats: StatsRecorder,
+    ) -> Self {
+        Self {
+            settings,
+            mempool,
+            stats,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn settings(&self) -> CompactBlockSettings {
+        self.settings
+    }
+
+    pub(crate) fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    pub(crate) fn on_compact_block(&mut self, compact: HeaderAndShortIds) -> Reconstruction {
+        let block_hash = compact.header.block_hash();
+        self.stats.record_compact_block_received();
+        if !has_valid_size(&compact) {
+            tracing::warn!("Compact block {} has too many transactions", block_hash);
+            return Reconstruction::Invalid(block_hash);
+        }
+        let total = compact.short_ids.len() + compact.prefilled_txs.len();
+        let mut transactions: Vec<Option<Transaction>> = vec![None; total];
+
+        // Prefilled indexes are differentially encoded
+        let mut next_index = 0usize;
+        for prefilled in compact.prefilled_txs {
+            let index = next_index + usize::from(prefilled.idx);
+            let Some(slot) = transactions.get_mut(index) else {
+                tracing::warn!(
+                    "Compact block {} has an invalid prefilled index",
+                    block_hash
+                );
+                return self.failed(block_hash);
+            };
+            *slot = Some(prefilled.tx);
+            next_index = index + 1;
+        }
+
+        let short_ids = self.mempool_short_ids(&compact.header, compact.nonce);
+        let mut hits = 0;
+        let mut short_id_iter = compact.short_ids.iter();
+        for slot in transactions.iter_mut().filter(|slot| slot.is_none()) {
+            let Some(short_id) = short_id_iter.next() else {
+                break;
+            };
+            // Short IDs shared by multiple mempool transactions are requested from the peer
+            if let Some(Some(wtxid)) = short_ids.get(short_id) {
+                *slot = self.mempool.get(wtxid);
+                hits += u64::from(slot.is_some());
+            }
+        }
+
+        let missing: Vec<u64> = transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u64)
+            .collect();
+        self.stats
+            .record_compact_block_transactions(hits, missing.len() as u64);
+
+        let partial = PartialBlock {
+            header: compact.header,
+            transactions,
+        };
+        if missing.is_empty() {
+            return self.finish(partial, false);
+        }
+
+        tracing::debug!(
+            "Compact block {} is missing {} transactions",
+            block_hash,
+            missing.len()
+        );
+        self.pending.retain(|(pending, _)| *pending != block_hash);
+        if self.pending.len() == MAX_PENDING_BLOCKS {
+            if let Some((oldest, _)) = self.pending.pop_front() {
+                tracing::debug!("Giving up on compact block {}", oldest);
+                self.stats.record_compact_block_failed();
+            }
+        }
+        self.pending.push_back((block_hash, partial));
+        Reconstruction::Incomplete(GetBlockTxn {
+            txs_request: BlockTransactionsRequest {
+                block_hash,
+                indexes: missing,
+            },
+        })
+    }
+
+    /// Fill in the transactions that were missing. Returns `None` for blocks we did not ask for.
+    pub(crate) fn on_block_transactions(
+        &mut self,
+        response: BlockTransactions,
+    ) -> Option<Reconstruction> {
+        let position = self
+            .pending
+            .iter()
+            .position(|(block_hash, _)| *block_hash == response.block_hash)?;
+        let (_, mut partial) = self.pending.remove(position)?;
+
+        let mut received = response.transactions.into_iter();
+        for slot in partial
+            .transactions
+            .iter_mut()
+            .filter(|slot| slot.is_none())
+        {
+            *slot = received.next();
+        }
+        if received.next().is_some() {
+            tracing::warn!(
+                "Peer sent too many transactions for {}",
+                response.block_hash
+            );
+            return Some(self.failed(response.block_hash));
+        }
+        Some(self.finish(partial, true))
+    }
+
+    fn finish(&mut self, partial: PartialBlock, round_trip: bool) -> Reconstruction {
+        let block_hash = partial.header.block_hash();
+        let Some(txdata) = partial.transactions.into_iter().collect::<Option<Vec<_>>>() else {
+            tracing::warn!(
+                "Peer did not send all missing transactions for {}",
+                block_hash
+            );
+            return self.failed(block_hash);
+        };
+        let block = Block {
+            header: partial.header,
+            txdata,
+        };
+        // A short ID collision results in a wrong transaction, which the merkle root reveals
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            tracing::warn!(
+                "Reconstructed block {} does not match its header",
+                block_hash
+            );
+            return self.failed(block_hash);
+        }
+        self.stats.record_compact_block_reconstructed(round_trip);
+        self.mempool.remove_all(&block.txdata);
+        Reconstruction::Complete(block)
+    }
+
+    fn failed(&mut self, block_hash: BlockHash) -> Reconstruction {
+        self.pending.retain(|(pending, _)| *pending != block_hash);
+        self.stats.record_compact_block_failed();
+        Reconstruction::Failed(block_hash)
+    }
+
+    /// Short IDs of the mempool transactions for this block. Colliding short IDs map to `None`.
+    /// Only the wtxids are collected, the matching transactions are cloned afterwards.
+    fn mempool_short_ids(
+        &self,
+        header: &BlockHeader,
+        nonce: u64,
+    ) -> HashMap<ShortId, Option<Wtxid>> {
+        let keys = ShortId::calculate_siphash_keys(header, nonce);
+        let mut short_ids = HashMap::with_capacity(self.mempool.len());
+        self.mempool.for_each(|wtxid, _| {
+            let short_id = ShortId::with_siphash_keys(&wtxid.as_hash(), keys);
+            short_ids
+                .entry(short_id)
+                .and_modify(|existing| *existing = None)
+                .or_insert(Some(*wtxid));
+        });
+        short_ids
+    }
+}
+
+/// The compact block has no more transactions than a block can hold.
+pub(crate) fn has_valid_size(compact: &HeaderAndShortIds) -> bool {
+    compact.short_ids.len() + compact.prefilled_txs.len() <= MAX_BLOCK_TRANSACTIONS
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::Hash;
+    use bitcoin::network::constants::Network;
+    use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut, Txid, Witness};
+
+    use super::*;
+
+    fn transaction(n: u8) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([n; 32]), 0),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: u64::from(n),
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    /// A block with a coinbase and the transactions `1..=count`, the coinbase is prefilled.
+    fn compact_block(tag: u8, count: u8) -> (Block, HeaderAndShortIds) {
+        let mut coinbase = transaction(0);
+        coinbase.input[0].previous_output = OutPoint::null();
+        coinbase.output[0].value = u64::from(tag);
+        let mut block = genesis_block(Network::Regtest);
+        block.header.prev_blockhash = block.block_hash();
+        block.txdata = std::iter::once(coinbase)
+            .chain((1..=count).map(transaction))
+            .collect();
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let compact = HeaderAndShortIds::from_block(&block, 7, 2, &[]).unwrap();
+        (block, compact)
+    }
+
+    fn reconstructor(transactions: impl IntoIterator<Item = u8>) -> CompactBlockReconstructor {
+        let mempool = Mempool::new();
+        transactions
+            .into_iter()
+            .for_each(|n| mempool.insert(transaction(n)));
+        let stats = StatsRecorder::new("127.0.0.1:8333".parse().unwrap());
+        CompactBlockReconstructor::new(CompactBlockSettings::default(), mempool, stats)
+    }
+
+    #[test]
+    fn short_ids_match_bip152() {
+        // Computed independently: SipHash-2-4 keyed with SHA256(header || nonce), low 6 bytes
+        let header = genesis_block(Network::Regtest).header;
+        let mut bytes = [0; 32];
+        bytes.iter_mut().zip(0..).for_each(|(byte, i)| *byte = i);
+        let wtxid = Wtxid::from_inner(bytes);
+        let keys = ShortId::calculate_siphash_keys(&header, 7);
+        assert_eq!(keys, (0x8871_dc90_e8a6_a008, 0xcc75_4500_d753_762f));
+        assert_eq!(
+            serialize(&ShortId::with_siphash_keys(&wtxid.as_hash(), keys)),
+            vec![0xf9, 0x9d, 0xe4, 0x86, 0xd8, 0xa2]
+        );
+
+        let mempool = Mempool::new();
+        mempool.insert(transaction(1));
+        let reconstructor = CompactBlockReconstructor::new(
+            CompactBlockSettings::default(),
+            mempool,
+            StatsRecorder::new("127.0.0.1:8333".parse().unwrap()),
+        );
+        let wtxid = transaction(1).wtxid();
+        let short_id = ShortId::with_siphash_keys(&wtxid.as_hash(), keys);
+        assert_eq!(
+            reconstructor.mempool_short_ids(&header, 7)[&short_id],
+            Some(wtxid)
+        );
+    }
+
+    #[test]
+    fn blocks_are_rebuilt_from_the_mempool() {
+        let mut reconstructor = reconstructor(1..=5);
+        let (block, compact) = compact_block(1, 3);
+        match reconstructor.on_compact_block(compact) {
+            Reconstruction::Complete(reconstructed) => assert_eq!(reconstructed, block),
+            reconstruction => panic!("Unexpected {reconstruction:?}"),
+        }
+        // The confirmed transactions left the mempool
+        assert_eq!(reconstructor.mempool().len(), 2);
+    }
+
+    #[test]
+    fn missing_transactions_are_requested() {
+        let mut reconstructor = reconstructor([1, 3]);
+        let (block, compact) = compact_block(1, 3);
+        let Reconstruction::Incomplete(request) = reconstructor.on_compact_block(compact) else {
+            panic!("The block is incomplete");
+        };
+        assert_eq!(request.txs_request.indexes, vec![2]);
+
+        let response = BlockTransactions {
+            block_hash: block.block_hash(),
+            transactions: vec![transaction(2)],
+        };
+        match reconstructor.on_block_transactions(response) {
+            Some(Reconstruction::Complete(reconstructed)) => assert_eq!(reconstructed, block),
+            reconstruction => panic!("Unexpected {reconstruction:?}"),
+        }
+    }
+
+    #[test]
+    fn wrong_transactions_fail_the_reconstruction() {
+        let mut reconstructor = reconstructor([]);
+        let (block, compact) = compact_block(1, 1);
+        reconstructor.on_compact_block(compact);
+        let response = BlockTransactions {
+            block_hash: block.block_hash(),
+            transactions: vec![transaction(9)],
+        };
+        assert!(matches!(
+            reconstructor.on_block_transactions(response),
+            Some(Reconstruction::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn pending_blocks_are_bounded() {
+        let mut reconstructor = reconstructor([]);
+        let blocks: Vec<(Block, HeaderAndShortIds)> = (1..=MAX_PENDING_BLOCKS as u8 + 1)
+            .map(|tag| compact_block(tag, 1))
+            .collect();
+        for (_, compact) in &blocks {
+            reconstructor.on_compact_block(compact.clone());
+        }
+        assert_eq!(reconstructor.pending.len(), MAX_PENDING_BLOCKS);
+        let response = BlockTransactions {
+            block_hash: blocks[0].0.block_hash(),
+            transactions: vec![transaction(1)],
+        };
+        assert!(reconstructor.on_block_transactions(response).is_none());
+    }
+
+    #[test]
+    fn compact_blocks_with_too_many_transactions_are_invalid() {
+        let mut reconstructor = reconstructor([]);
+        let (block, mut compact) = compact_block(1, 1);
+        assert!(has_valid_size(&compact));
+        compact.short_ids = vec![compact.short_ids[0]; MAX_BLOCK_TRANSACTIONS];
+        assert!(!has_valid_size(&compact));
+        assert!(matches!(
+            reconstructor.on_compact_block(compact),
+            Reconstruction::Invalid(block_hash) if block_hash == block.block_hash()
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+//! P2P messages that rust-bitcoin does not know, e.g. experimental ones such as package relay.
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use bitcoin::consensus::encode;
+use bitcoin::network::message::{CommandString, NetworkMessage};
+
+/// A message type defined outside of rust-bitcoin. Register it with
+/// [`BitcoinConnector::with_custom_message`](crate::BitcoinConnector::with_custom_message) to
+/// receive it as [`FromConnectionHandle::Custom`](crate::FromConnectionHandle::Custom).
+pub trait CustomMessage: fmt::Debug + Send + Sync + Sized + 'static {
+    /// The command on the wire, at most 12 ASCII characters.
+    const COMMAND: &'static str;
+
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(payload: &[u8]) -> Result<Self, encode::Error>;
+
+    /// The message as rust-bitcoin sends it, so it can be sent anywhere a
+    /// [`NetworkMessage`] is accepted.
+    ///
+    /// # Panics
+    ///
+    /// Panics when [`Self::COMMAND`] is not a valid command.
+    fn to_network_message(&self) -> NetworkMessage {
+        NetworkMessage::Unknown {
+            command: command::<Self>(),
+            payload: self.encode(),
+        }
+    }
+}
+
+fn command<M: CustomMessage>() -> CommandString {
+    CommandString::try_from_static(M::COMMAND)
+        .unwrap_or_else(|err| panic!("Invalid command of {}: {}", std::any::type_name::<M>(), err))
+}
+
+/// A received custom message, decoded by the type registered for its command.
+#[derive(Clone)]
+pub struct AnyMessage {
+    command: CommandString,
+    payload: Vec<u8>,
+    message: Arc<dyn DecodedMessage>,
+}
+
+impl AnyMessage {
+    pub fn command(&self) -> &CommandString {
+        &self.command
+    }
+
+    /// The payload exactly as it was received.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn is<M: CustomMessage>(&self) -> bool {
+        self.downcast_ref::<M>().is_some()
+    }
+
+    /// The decoded message, `None` when it is of another type.
+    pub fn downcast_ref<M: CustomMessage>(&self) -> Option<&M> {
+        self.message.as_any().downcast_ref()
+    }
+}
+
+impl PartialEq for AnyMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.command == other.command && self.payload == other.payload
+    }
+}
+
+impl Eq for AnyMessage {}
+
+impl fmt::Debug for AnyMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+/// The object safe part of [`CustomMessage`].
+trait DecodedMessage: fmt::Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<M: CustomMessage> DecodedMessage for M {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+type Decoder = fn(&[u8]) -> Result<Arc<dyn DecodedMessage>, encode::Error>;
+
+/// Maps commands to the custom message types that decode them.
+#[derive(Clone, Default)]
+pub struct MessageRegistry {
+    /// Keyed by the command, `CommandString` is not `Hash`.
+    decoders: HashMap<String, Decoder>,
+}
+
+impl MessageRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode messages with the command of `M` as `M`, replacing the type registered before.
+    /// Commands that rust-bitcoin decodes itself never reach the registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics when [`CustomMessage::COMMAND`] is not a valid command.
+    pub fn register<M: CustomMessage>(&mut self) {
+        let decoder: Decoder = |payload| Ok(Arc::new(M::decode(payload)?));
+        self.decoders.insert(command::<M>().to_string(), decoder);
+    }
+
+    pub fn is_registered(&self, command: &CommandString) -> bool {
+        self.decoders.contains_key(command.as_ref())
+    }
+
+    /// Decode the payload with the type registered for the command, `None` when no type is
+    /// registered.
+    pub(crate) fn decode(
+        &self,
+        command: &CommandString,
+        payload: &[u8],
+    ) -> Option<Result<AnyMessage, encode::Error>> {
+        let decoder = self.decoders.get(command.as_ref())?;
+        Some(decoder(payload).map(|message| AnyMessage {
+            command: command.clone(),
+            payload: payload.to_vec(),
+            message,
+        }))
+    }
+}
+
+impl fmt::Debug for MessageRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.decoders.keys()).finish()
+    }
+}
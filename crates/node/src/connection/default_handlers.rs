@@ -0,0 +1,119 @@
+//! The handlers every connection starts with.
+use std::time::Instant;
+
+use bitcoin::network::message::NetworkMessage;
+
+use super::message_handler::{HandlerAction, HandlerContext, MessageHandler};
+
+/// The first protocol version that knows `wtxidrelay`.
+const WTXID_RELAY_VERSION: u32 = 70016;
+
+/// Exchanges the version and verack messages. The verack is sent once both versions were
+/// exchanged, inbound peers send their version before we send ours. `wtxidrelay` (BIP339)
+/// goes right before the verack when both sides know it.
+#[derive(Clone, Debug)]
+pub(crate) struct HandshakeHandler;
+
+impl HandshakeHandler {
+    fn send_verack(context: &mut HandlerContext<'_>) {
+        let peer_version = context
+            .state
+            .peer_version
+            .as_ref()
+            .map_or(0, |version| version.version);
+        if context.state.protocol_version >= WTXID_RELAY_VERSION
+            && peer_version >= WTXID_RELAY_VERSION
+        {
+            context.state.wtxid_relay_sent = true;
+            context.send(NetworkMessage::WtxidRelay);
+        }
+        context.send(NetworkMessage::Verack);
+    }
+}
+
+impl MessageHandler for HandshakeHandler {
+    fn on_inbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> HandlerAction {
+        match &message {
+            NetworkMessage::Version(version) => {
+                // NOTE: we can add extra version validation here
+                context.state.peer_version = Some(version.clone());
+                if context.state.version_sent {
+                    Self::send_verack(context);
+                }
+            }
+            NetworkMessage::WtxidRelay if context.state.verack_received => {
+                tracing::debug!("Ignoring wtxidrelay after the verack");
+            }
+            NetworkMessage::WtxidRelay => context.state.peer_wtxid_relay = true,
+            NetworkMessage::Verack => {
+                context.state.verack_received = true;
+                let wtxid_relay = context.state.wtxid_relay();
+                context.stats_recorder().record_wtxid_relay(wtxid_relay);
+            }
+            _ => {}
+        }
+        HandlerAction::Pass(message)
+    }
+
+    fn on_outbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> HandlerAction {
+        if let NetworkMessage::Version(version) = &message {
+            context.state.version_sent = true;
+            context.state.protocol_version = version.version;
+            for negotiation in std::mem::take(&mut context.state.negotiation) {
+                context.send(negotiation);
+            }
+            if context.state.peer_version.is_some() {
+                Self::send_verack(context);
+            }
+        }
+        HandlerAction::Pass(message)
+    }
+}
+
+/// Answers pings and measures the latency of our pings, one ping at a time.
+#[derive(Clone, Debug)]
+pub(crate) struct PingHandler;
+
+impl MessageHandler for PingHandler {
+    fn on_inbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> HandlerAction {
+        match message {
+            NetworkMessage::Ping(nonce) => context.send(NetworkMessage::Pong(nonce)),
+            NetworkMessage::Pong(nonce) => match context.state.pending_ping {
+                Some((expected, sent_at)) if expected == nonce => {
+                    let latency = sent_at.elapsed();
+                    tracing::debug!("Ping latency {:?}", latency);
+                    context.stats_recorder().record_ping_latency(latency);
+                    context.state.pending_ping = None;
+                }
+                _ => tracing::debug!("Received pong with unexpected nonce {}", nonce),
+            },
+            _ => {}
+        }
+        HandlerAction::Pass(message)
+    }
+
+    fn on_outbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> HandlerAction {
+        if let NetworkMessage::Ping(nonce) = message {
+            if context.state.pending_ping.is_none() {
+                context.state.pending_ping = Some((nonce, Instant::now()));
+            }
+        }
+        HandlerAction::Pass(message)
+    }
+}
@@ -0,0 +1,127 @@
+//! Answers BIP157 filter requests from a [`FilterIndex`].
+use bitcoin::hashes::Hash;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
+use bitcoin::{BlockHash, FilterHeader};
+use thiserror::Error;
+
+use crate::filter_client::{
+    BASIC_FILTER_TYPE, MAX_FILTERS_PER_REQUEST, MAX_FILTER_HEADERS_PER_REQUEST,
+};
+use crate::filter_index::{FilterIndex, FilterIndexError};
+
+/// Distance between the filter headers of a `cfcheckpt` response.
+const CHECKPOINT_INTERVAL: u32 = 1000;
+
+#[derive(Debug, Error)]
+pub(crate) enum FilterRequestError {
+    #[error("Unsupported filter type {0}")]
+    UnsupportedFilterType(u8),
+    #[error("Start height {start_height} is above the stop height {stop_height}")]
+    InvalidRange { start_height: u32, stop_height: u32 },
+    #[error("Requested {requested} entries, the limit is {limit}")]
+    TooManyRequested { requested: u32, limit: u32 },
+    #[error(transparent)]
+    Index(#[from] FilterIndexError),
+}
+
+/// One `cfilter` per block from the start height up to the stop hash.
+pub(crate) fn filters(
+    index: &FilterIndex,
+    request: &GetCFilters,
+) -> Result<Vec<NetworkMessage>, FilterRequestError> {
+    check_filter_type(request.filter_type)?;
+    let stop_height = indexed_height(index, &request.stop_hash)?;
+    check_range(
+        request.start_height,
+        stop_height,
+        MAX_FILTERS_PER_REQUEST as u32,
+    )?;
+    (request.start_height..=stop_height)
+        .map(|height| {
+            let block_hash = index.indexed_block_hash(height)?;
+            Ok(NetworkMessage::CFilter(CFilter {
+                filter_type: request.filter_type,
+                block_hash,
+                filter: index.filter(&block_hash)?.content,
+            }))
+        })
+        .collect()
+}
+
+pub(crate) fn filter_headers(
+    index: &FilterIndex,
+    request: &GetCFHeaders,
+) -> Result<NetworkMessage, FilterRequestError> {
+    check_filter_type(request.filter_type)?;
+    let stop_height = indexed_height(index, &request.stop_hash)?;
+    check_range(
+        request.start_height,
+        stop_height,
+        MAX_FILTER_HEADERS_PER_REQUEST as u32,
+    )?;
+    let previous_filter_header = match request.start_height.checked_sub(1) {
+        Some(previous_height) => index.filter_header(previous_height)?,
+        None => FilterHeader::all_zeros(),
+    };
+    let filter_hashes = (request.start_height..=stop_height)
+        .map(|height| index.filter_hash(height))
+        .collect::<Result<_, _>>()?;
+    Ok(NetworkMessage::CFHeaders(CFHeaders {
+        filter_type: request.filter_type,
+        stop_hash: request.stop_hash,
+        previous_filter_header,
+        filter_hashes,
+    }))
+}
+
+/// The filter headers at every [`CHECKPOINT_INTERVAL`] blocks up to the stop hash.
+pub(crate) fn checkpoints(
+    index: &FilterIndex,
+    request: &GetCFCheckpt,
+) -> Result<NetworkMessage, FilterRequestError> {
+    check_filter_type(request.filter_type)?;
+    let stop_height = indexed_height(index, &request.stop_hash)?;
+    let filter_headers = (1..=stop_height / CHECKPOINT_INTERVAL)
+        .map(|checkpoint| index.filter_header(checkpoint * CHECKPOINT_INTERVAL))
+        .collect::<Result<_, _>>()?;
+    Ok(NetworkMessage::CFCheckpt(CFCheckpt {
+        filter_type: request.filter_type,
+        stop_hash: request.stop_hash,
+        filter_headers,
+    }))
+}
+
+/// The height of the stop hash, requests beyond the indexed chain are not answered. The entry at
+/// that height must be indexed for the stop hash, so the answer is not mixed from the entries of
+/// a chain the store reorged away from before the next sync.
+fn indexed_height(index: &FilterIndex, stop_hash: &BlockHash) -> Result<u32, FilterIndexError> {
+    let stop_height = index.height(stop_hash)?;
+    if index.indexed_block_hash(stop_height)? != *stop_hash {
+        return Err(FilterIndexError::NotIndexed(stop_height));
+    }
+    Ok(stop_height)
+}
+
+fn check_filter_type(filter_type: u8) -> Result<(), FilterRequestError> {
+    if filter_type != BASIC_FILTER_TYPE {
+        return Err(FilterRequestError::UnsupportedFilterType(filter_type));
+    }
+    Ok(())
+}
+
+fn check_range(start_height: u32, stop_height: u32, limit: u32) -> Result<(), FilterRequestError> {
+    if start_height > stop_height {
+        return Err(FilterRequestError::InvalidRange {
+            start_height,
+            stop_height,
+        });
+    }
+    let requested = stop_height - start_height + 1;
+    if requested > limit {
+        return Err(FilterRequestError::TooManyRequested { requested, limit });
+    }
+    Ok(())
+}
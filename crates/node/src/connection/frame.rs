@@ -0,0 +1,256 @@
+use std::io::{self, Read};
+
+use bitcoin::consensus::encode;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::message::{CommandString, NetworkMessage, RawNetworkMessage, MAX_MSG_SIZE};
+
+/// magic (4) + command (12) + payload length (4) + checksum (4)
+pub(crate) const HEADER_SIZE: usize = 24;
+
+/// Reasons why a frame received from the node could not be turned into a message.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum FrameError {
+    /// The header announced a payload larger than the protocol allows.
+    /// The payload is skipped without keeping it in memory.
+    Oversized { length: u32 },
+    /// The frame belongs to another network.
+    WrongMagic(u32),
+    /// The command is not null padded printable ASCII.
+    InvalidCommand,
+    /// The payload does not match the checksum in the header.
+    BadChecksum,
+    /// The command is known, but the payload could not be decoded.
+    InvalidPayload(String),
+}
+
+/// The fixed size header that precedes every message on the wire.
+#[derive(Clone, Debug)]
+pub(crate) struct FrameHeader {
+    pub(crate) magic: u32,
+    pub(crate) command: CommandString,
+    pub(crate) length: u32,
+    pub(crate) checksum: [u8; 4],
+}
+
+/// A complete frame: the header plus the raw payload bytes.
+#[derive(Clone, Debug)]
+pub(crate) struct Frame {
+    pub(crate) header: FrameHeader,
+    /// The bytes exactly as they were read from the stream (header included).
+    pub(crate) raw: Vec<u8>,
+}
+
+impl FrameHeader {
+    pub(crate) fn parse(bytes: &[u8; HEADER_SIZE]) -> Self {
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let command = encode::deserialize(&bytes[4..16]).expect("Any 12 bytes are a command");
+        let length = u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]);
+        let checksum = [bytes[20], bytes[21], bytes[22], bytes[23]];
+        Self {
+            magic,
+            command,
+            length,
+            checksum,
+        }
+    }
+
+    /// Check the header against the protocol limits before reading the payload.
+    pub(crate) fn check_length(&self) -> Result<(), FrameError> {
+        if self.length as usize > MAX_MSG_SIZE {
+            return Err(FrameError::Oversized {
+                length: self.length,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Printable ASCII padded with zeros, like bitcoin core expects.
+fn is_valid_command(bytes: &[u8]) -> bool {
+    let length = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    bytes[..length]
+        .iter()
+        .all(|byte| (b' '..=b'~').contains(byte))
+        && bytes[length..].iter().all(|byte| *byte == 0)
+}
+
+/// Serialize a message for the wire. rust-bitcoin prefixes the payload of unknown messages
+/// with its length, which the peer would read as part of the payload.
+pub(crate) fn serialize(message: &RawNetworkMessage) -> Vec<u8> {
+    let NetworkMessage::Unknown { command, payload } = &message.payload else {
+        return encode::serialize(message);
+    };
+    let checksum = sha256d::Hash::hash(payload);
+    let mut raw = Vec::with_capacity(HEADER_SIZE + payload.len());
+    raw.extend(message.magic.to_le_bytes());
+    raw.extend(encode::serialize(command));
+    raw.extend((payload.len() as u32).to_le_bytes());
+    raw.extend(&checksum[..4]);
+    raw.extend(payload);
+    raw
+}
+
+/// The command of a serialized message.
+pub(crate) fn command_of(frame: &[u8]) -> Option<CommandString> {
+    let header: &[u8; HEADER_SIZE] = frame.get(..HEADER_SIZE)?.try_into().ok()?;
+    Some(FrameHeader::parse(header).command)
+}
+
+impl Frame {
+    /// Read the header and the payload of the next frame from the stream.
+    ///
+    /// # Errors
+    ///
+    /// The outer error means the stream is broken (closed or truncated) and it cannot be read
+    /// any further. The inner error means that only this frame is broken, its payload was
+    /// skipped and the stream is still in sync.
+    pub(crate) fn read_from(
+        reader: &mut impl Read,
+        magic: u32,
+    ) -> io::Result<Result<Self, (FrameHeader, FrameError)>> {
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header_bytes)?;
+        let header = FrameHeader::parse(&header_bytes);
+        let invalid = if header.magic != magic {
+            Some(FrameError::WrongMagic(header.magic))
+        } else if !is_valid_command(&header_bytes[4..16]) {
+            Some(FrameError::InvalidCommand)
+        } else {
+            header.check_length().err()
+        };
+        if let Some(err) = invalid {
+            let length = u64::from(header.length);
+            if io::copy(&mut reader.take(length), &mut io::sink())? != length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(Err((header, err)));
+        }
+
+        let mut raw = Vec::with_capacity(HEADER_SIZE + header.length as usize);
+        raw.extend_from_slice(&header_bytes);
+        reader
+            .take(u64::from(header.length))
+            .read_to_end(&mut raw)?;
+        if raw.len() != HEADER_SIZE + header.length as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let frame = Self { header, raw };
+        if frame.payload_checksum() != frame.header.checksum {
+            return Ok(Err((frame.header, FrameError::BadChecksum)));
+        }
+        Ok(Ok(frame))
+    }
+
+    pub(crate) fn payload(&self) -> &[u8] {
+        &self.raw[HEADER_SIZE..]
+    }
+
+    fn payload_checksum(&self) -> [u8; 4] {
+        let hash = sha256d::Hash::hash(self.payload());
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+
+    /// Decode the payload. Unknown commands are not an error, they are decoded
+    /// as [`bitcoin::network::message::NetworkMessage::Unknown`].
+    pub(crate) fn decode(&self) -> Result<RawNetworkMessage, FrameError> {
+        encode::deserialize(&self.raw).map_err(|err| FrameError::InvalidPayload(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Regtest
+    const MAGIC: u32 = 0xdab5bffa;
+
+    fn raw_frame(magic: u32, command: &[u8], length: u32, payload: &[u8]) -> Vec<u8> {
+        let mut raw = magic.to_le_bytes().to_vec();
+        let mut padded = [0; 12];
+        padded[..command.len()].copy_from_slice(command);
+        raw.extend(padded);
+        raw.extend(length.to_le_bytes());
+        raw.extend(&sha256d::Hash::hash(payload)[..4]);
+        raw.extend(payload);
+        raw
+    }
+
+    fn ping() -> Vec<u8> {
+        serialize(&RawNetworkMessage {
+            magic: MAGIC,
+            payload: NetworkMessage::Ping(7),
+        })
+    }
+
+    /// Read the broken frame followed by a valid ping.
+    fn read_both(broken: Vec<u8>) -> (Result<Frame, (FrameHeader, FrameError)>, NetworkMessage) {
+        let mut stream = broken;
+        stream.extend(ping());
+        let mut reader = stream.as_slice();
+        let first = Frame::read_from(&mut reader, MAGIC).unwrap();
+        let second = Frame::read_from(&mut reader, MAGIC).unwrap().unwrap();
+        assert!(reader.is_empty());
+        (first, second.decode().unwrap().payload)
+    }
+
+    #[test]
+    fn frames_with_a_bad_checksum_are_skipped() {
+        let mut broken = raw_frame(MAGIC, b"ping", 8, &[1; 8]);
+        broken[20] ^= 0xff;
+        let (first, next) = read_both(broken);
+        assert!(matches!(first, Err((_, FrameError::BadChecksum))));
+        assert_eq!(next, NetworkMessage::Ping(7));
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped() {
+        let length = MAX_MSG_SIZE as u32 + 1;
+        let broken = raw_frame(MAGIC, b"block", length, &vec![0; length as usize]);
+        let (first, next) = read_both(broken);
+        assert!(matches!(first, Err((_, FrameError::Oversized { length: l })) if l == length));
+        assert_eq!(next, NetworkMessage::Ping(7));
+    }
+
+    #[test]
+    fn frames_of_other_networks_are_skipped() {
+        let broken = raw_frame(0xd9b4bef9, b"ping", 8, &[1; 8]);
+        let (first, next) = read_both(broken);
+        assert!(matches!(
+            first,
+            Err((_, FrameError::WrongMagic(0xd9b4bef9)))
+        ));
+        assert_eq!(next, NetworkMessage::Ping(7));
+    }
+
+    #[test]
+    fn invalid_commands_are_skipped() {
+        for command in [&b"pi\0ng"[..], b"ping\x7f", b"\xffping"] {
+            let (first, next) = read_both(raw_frame(MAGIC, command, 8, &[1; 8]));
+            assert!(matches!(first, Err((_, FrameError::InvalidCommand))));
+            assert_eq!(next, NetworkMessage::Ping(7));
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_decoded() {
+        let (first, next) = read_both(raw_frame(MAGIC, b"newcommand", 3, &[1, 2, 3]));
+        let message = first.unwrap().decode().unwrap().payload;
+        assert!(matches!(
+            message,
+            NetworkMessage::Unknown { command, payload }
+                if command.as_ref() == "newcommand" && payload == [1, 2, 3]
+        ));
+        assert_eq!(next, NetworkMessage::Ping(7));
+    }
+
+    #[test]
+    fn truncated_frames_break_the_stream() {
+        let mut truncated = raw_frame(MAGIC, b"block", MAX_MSG_SIZE as u32 + 1, &[0; 8]);
+        truncated.truncate(HEADER_SIZE + 4);
+        assert!(Frame::read_from(&mut truncated.as_slice(), MAGIC).is_err());
+    }
+}
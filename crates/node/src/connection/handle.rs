@@ -1,14 +1,32 @@
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use bitcoin::network::constants::{self, ServiceFlags};
-use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message::{CommandString, NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_network::VersionMessage;
-use bitcoin::network::Address;
-use rand::Rng;
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{Block, BlockHash, Transaction};
+use settings::{
+    CaptureSettings, CompactBlockSettings, FeeFilterSettings, MisbehaviorSettings, PeerAddress,
+    ReconciliationSettings, Settings,
+};
 use tracing::instrument;
 
 use super::actor::ConnectionActor;
+use super::custom_message::{AnyMessage, CustomMessage, MessageRegistry};
+use super::frame::FrameError;
+use super::message_handler::HandlerFactory;
+use super::misbehavior::DisconnectReason;
+use super::outbound_queue::{OutboundQueue, QueueDepth};
+use super::socks5;
+use super::stats::{ConnectionStats, StatsRecorder};
+use super::version_builder::VersionBuilder;
+use crate::ban_list::BanList;
 use crate::error::Error;
+use crate::filter_index::FilterIndex;
+use crate::mempool::Mempool;
+use crate::network_time::NetworkTime;
 
 #[derive(Debug)]
 pub struct ConnectionHandle {
@@ -16,7 +34,22 @@ pub struct ConnectionHandle {
     from_actor_receiver: tokio::sync::broadcast::Receiver<FromConnectionHandle>,
     network: constants::Network,
     peer_address: SocketAddr,
+    /// The address the peer is configured with, a host name or the peer address.
+    peer_target: PeerAddress,
     sender_address: SocketAddr,
+    /// Builds the version message we send during the handshake.
+    version: VersionBuilder,
+    outbound: Arc<OutboundQueue>,
+    stats: StatsRecorder,
+    /// Only used to shut the connection down when the handle is dropped.
+    stream: std::net::TcpStream,
+    /// The version message of the peer, known once the handshake is done.
+    peer_version: Option<VersionMessage>,
+    peer_version_received_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Samples the clock offset of the peer, `None` for inbound peers.
+    network_time: Option<NetworkTime>,
+    /// Messages received during the handshake that were not part of it.
+    buffered: VecDeque<FromConnectionHandle>,
     #[allow(dead_code)]
     actor_handle: tokio::task::JoinHandle<()>,
 }
@@ -25,6 +58,10 @@ impl Drop for ConnectionHandle {
     fn drop(&mut self) {
         tracing::info!("Dropping connection handle");
         self.actor_handle.abort();
+        // The writer task outlives the aborted actor, closing the queue stops it
+        self.outbound.close();
+        // The reader task is blocked on the socket, shutting it down wakes it up
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
     }
 }
 
@@ -35,7 +72,15 @@ impl Drop for ConnectionHandle {
 #[derive(Debug)]
 pub enum ToConnectionHandle {
     ToBitcoinNode(RawNetworkMessage),
-    InitHandshake { version: VersionMessage },
+    InitHandshake {
+        version: VersionMessage,
+    },
+    /// Announce a transaction to the peer, see [`crate::BitcoinConnection::announce_transaction`].
+    /// The fee in satoshis, when known, is checked against the fee filter of the peer.
+    AnnounceTransaction {
+        transaction: Transaction,
+        fee: Option<u64>,
+    },
 }
 
 /// Represents messages that are received from the actor.
@@ -46,49 +91,193 @@ pub enum FromConnectionHandle {
     /// Message from the bitcoin node. We are not interested in the magic number,
     /// therefore we are using NetworkMessage instead of RawNetworkMessage.
     FromBitcoinNode(NetworkMessage),
+    /// A message with a command that we do not know how to decode.
+    Unknown {
+        command: CommandString,
+        payload: Vec<u8>,
+    },
+    /// A message decoded by a type registered for its command, see
+    /// [`CustomMessage`](crate::CustomMessage).
+    Custom(AnyMessage),
+    /// A frame that could not be decoded, it was skipped and the connection stays open.
+    Malformed {
+        command: CommandString,
+        error: FrameError,
+        /// Total number of frames that could not be decoded on this connection.
+        decode_errors: u64,
+    },
+    /// A block rebuilt from a compact block and the transactions in the mempool.
+    ReconstructedBlock(Block),
+    /// A transaction that matched the bloom filter, together with the merkle block proving
+    /// that it is part of the block.
+    FilteredTransaction {
+        transaction: Transaction,
+        merkle_block: MerkleBlock,
+    },
+    /// The peer announced a block that is higher than its best block before, see
+    /// [`crate::BitcoinConnection::new_tip`].
+    NewTip { block_hash: BlockHash, height: u32 },
+    /// We closed the connection, no more messages will follow.
+    Disconnected(DisconnectReason),
+    /// A supervised connection was lost, the next attempt to redial follows after `delay`.
+    Reconnecting {
+        attempt: u32,
+        delay: std::time::Duration,
+    },
+    /// A supervised connection completed the handshake with the peer again.
+    Reconnected,
+}
+
+/// Optional behavior of a connection, taken from the settings.
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    pub capture: Option<CaptureSettings>,
+    pub misbehavior: MisbehaviorSettings,
+    pub ban_list: BanList,
+    /// Bytes that may be queued for the node before senders are pushed back.
+    pub send_buffer_limit: usize,
+    /// How often the node is pinged to measure the latency.
+    pub ping_interval: std::time::Duration,
+    pub compact_blocks: CompactBlockSettings,
+    /// Transactions relayed by the peer end up here and are used to rebuild compact blocks.
+    pub mempool: Mempool,
+    /// The clock offset of outbound peers is sampled here, headers from too far in the future
+    /// are ignored.
+    pub network_time: NetworkTime,
+    /// Serve compact block filters to the peer when set.
+    pub filter_index: Option<FilterIndex>,
+    pub version: VersionBuilder,
+    /// SOCKS5 proxy the connection is made through.
+    pub proxy: Option<SocketAddr>,
+    /// The `host:port` the peer is configured with. Behind a proxy the proxy resolves it,
+    /// otherwise it was resolved to the peer address.
+    pub host: Option<PeerAddress>,
+    /// Reloaded settings, the parts that can change on a live connection are applied.
+    pub settings_updates: Option<tokio::sync::watch::Receiver<Settings>>,
+    /// Create the custom message handlers of the connection.
+    pub message_handlers: Vec<HandlerFactory>,
+    /// The custom message types the messages of the peer are decoded with.
+    pub message_registry: MessageRegistry,
+    pub reconciliation: ReconciliationSettings,
+    pub fee_filter: FeeFilterSettings,
+    /// The peer connected to us.
+    pub inbound: bool,
+}
+
+impl ConnectionOptions {
+    /// The services we advertise to the peer: the configured ones, plus the ones these
+    /// options let us offer.
+    pub fn services(&self) -> ServiceFlags {
+        let services = self.version.advertised_services();
+        match self.filter_index {
+            Some(_) => services | ServiceFlags::COMPACT_FILTERS,
+            None => services,
+        }
+    }
 }
 
 impl ConnectionHandle {
-    #[instrument(err, fields(peer_address, sender_address, network))]
+    #[instrument(err, skip(options), fields(peer_address, sender_address, network))]
     pub async fn new(
         peer_address: SocketAddr,
         sender_address: SocketAddr,
         network: constants::Network,
+        options: ConnectionOptions,
     ) -> Result<Self, Error> {
         tracing::info!("Creating connection to node");
+        // Connect asynchronously, so that unreachable peers can be timed out by the caller.
+        // The connection tasks work on blocking sockets.
+        let stream = match (options.proxy, &options.host) {
+            (Some(proxy), Some(target)) => socks5::connect(proxy, target).await?,
+            (Some(proxy), None) => socks5::connect(proxy, &peer_address.into()).await?,
+            (None, _) => tokio::net::TcpStream::connect(peer_address).await?,
+        }
+        .into_std()?;
+        stream.set_nonblocking(false)?;
+        Self::spawn(stream, peer_address, sender_address, network, options)
+    }
+
+    /// Take over an established connection, e.g. one accepted from an inbound peer.
+    pub fn from_stream(
+        stream: std::net::TcpStream,
+        sender_address: SocketAddr,
+        network: constants::Network,
+        options: ConnectionOptions,
+    ) -> Result<Self, Error> {
+        let peer_address = stream.peer_addr()?;
+        Self::spawn(stream, peer_address, sender_address, network, options)
+    }
+
+    /// The peer address is passed in, behind a proxy the stream is connected to the proxy.
+    fn spawn(
+        stream: std::net::TcpStream,
+        peer_address: SocketAddr,
+        sender_address: SocketAddr,
+        network: constants::Network,
+        options: ConnectionOptions,
+    ) -> Result<Self, Error> {
+        let shutdown_stream = stream.try_clone()?;
         // The size of mpsc channels before they start blocking
         const CHANNEL_SIZE: usize = 10;
+        // Messages from the node arrive in bursts (e.g. a thousand `cfilter` responses),
+        // the receivers must not fall behind that quickly
+        const EVENT_CHANNEL_SIZE: usize = 4096;
 
         // Init communication primitives with the actor
         let (to_actor_sender, to_actor_receiver) = tokio::sync::mpsc::channel(CHANNEL_SIZE);
         let (from_actor_sender, from_actor_receiver) =
-            tokio::sync::broadcast::channel(CHANNEL_SIZE);
+            tokio::sync::broadcast::channel(EVENT_CHANNEL_SIZE);
+
+        let outbound = Arc::new(OutboundQueue::new(options.send_buffer_limit));
+        let stats = StatsRecorder::new(peer_address);
+        let version = options.version.clone().services(options.services());
+        let network_time = (!options.inbound).then(|| options.network_time.clone());
+        let peer_target = options
+            .host
+            .clone()
+            .unwrap_or(PeerAddress::Socket(peer_address));
 
         // Spawn the actor
-        let actor =
-            ConnectionActor::new(peer_address, to_actor_receiver, from_actor_sender, network)?;
+        let actor = ConnectionActor::new(
+            stream,
+            to_actor_receiver,
+            from_actor_sender,
+            network,
+            outbound.clone(),
+            stats.clone(),
+            options,
+        )?;
         let actor_handle = tokio::spawn(async move {
             actor.run().await;
         });
         Ok(Self {
             network,
             peer_address,
+            peer_target,
             sender_address,
+            version,
+            outbound,
+            stats,
+            stream: shutdown_stream,
+            peer_version: None,
+            peer_version_received_at: None,
+            network_time,
+            buffered: VecDeque::new(),
             actor_handle,
             to_actor_sender,
             from_actor_receiver,
         })
     }
 
-    /// Common send method for all messages, will properly map the error types
+    /// Common send method for all messages, will properly map the error types.
+    /// Waits until the outbound queue has space for the message.
     #[instrument(skip(self), err)]
     async fn send_to_node(&self, message: NetworkMessage) -> Result<(), Error> {
         tracing::debug!("Sending message to node: {:?}", message);
-        let message = RawNetworkMessage {
-            magic: self.network.magic(),
-            payload: message,
-        };
-        let message = ToConnectionHandle::ToBitcoinNode(message);
+        if !self.outbound.wait_for_space().await {
+            return Err(Error::ActorUnavailable);
+        }
+        let message = self.to_actor_message(message);
         self.to_actor_sender
             .send(message)
             .await
@@ -96,12 +285,117 @@ impl ConnectionHandle {
         Ok(())
     }
 
+    /// Send a message to the node, waiting while the node can't keep up with our messages.
+    pub async fn send(&self, message: NetworkMessage) -> Result<(), Error> {
+        self.send_to_node(message).await
+    }
+
+    /// Send a custom message to the node, waiting while the node can't keep up.
+    pub async fn send_custom<M: CustomMessage>(&self, message: &M) -> Result<(), Error> {
+        self.send_to_node(message.to_network_message()).await
+    }
+
+    /// Send a message to the node without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Backpressure`] when the outbound queue is full.
+    pub fn try_send(&self, message: NetworkMessage) -> Result<(), Error> {
+        if !self.outbound.has_space() {
+            return Err(Error::Backpressure);
+        }
+        let message = self.to_actor_message(message);
+        self.to_actor_sender
+            .try_send(message)
+            .map_err(|err| match err {
+                tokio::sync::mpsc::error::TrySendError::Full(_) => Error::Backpressure,
+                tokio::sync::mpsc::error::TrySendError::Closed(_) => Error::ActorSendError,
+            })
+    }
+
+    /// Announce a transaction to the node, by reconciliation when the node supports it.
+    /// Transactions with a `fee` (in satoshis) below the fee filter of the node are not
+    /// announced.
+    pub async fn announce_transaction(
+        &self,
+        transaction: Transaction,
+        fee: Option<u64>,
+    ) -> Result<(), Error> {
+        self.to_actor_sender
+            .send(ToConnectionHandle::AnnounceTransaction { transaction, fee })
+            .await
+            .map_err(|_| Error::ActorSendError)
+    }
+
+    /// How much data is waiting to be written to the node.
+    pub fn outbound_queue_depth(&self) -> QueueDepth {
+        self.outbound.depth()
+    }
+
+    /// A snapshot of the traffic statistics.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    /// The live statistics, e.g. to register them with a metrics exporter.
+    pub fn stats_recorder(&self) -> StatsRecorder {
+        self.stats.clone()
+    }
+
+    pub fn peer_address(&self) -> SocketAddr {
+        self.peer_address
+    }
+
+    pub fn peer_target(&self) -> &PeerAddress {
+        &self.peer_target
+    }
+
+    pub fn peer_version(&self) -> Option<&VersionMessage> {
+        self.peer_version.as_ref()
+    }
+
+    /// How far the clock of the peer is ahead of ours, judged by the timestamp of its version
+    /// message. `None` before the handshake and when the timestamp is out of range.
+    pub fn peer_clock_skew(&self) -> Option<chrono::Duration> {
+        let version = self.peer_version.as_ref()?;
+        let received_at = self.peer_version_received_at?;
+        let skew = version.timestamp.checked_sub(received_at.timestamp())?;
+        chrono::Duration::try_seconds(skew)
+    }
+
+    /// Another receiver of the messages and events from now on.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<FromConnectionHandle> {
+        self.from_actor_receiver.resubscribe()
+    }
+
+    fn to_actor_message(&self, message: NetworkMessage) -> ToConnectionHandle {
+        ToConnectionHandle::ToBitcoinNode(RawNetworkMessage {
+            magic: self.network.magic(),
+            payload: message,
+        })
+    }
+
     #[instrument(level = "debug", skip(self), ret, err)]
     pub async fn receive(&mut self) -> Result<FromConnectionHandle, Error> {
-        self.from_actor_receiver
-            .recv()
-            .await
-            .map_err(|_| Error::ActorUnavailable)
+        if let Some(message) = self.buffered.pop_front() {
+            return Ok(message);
+        }
+        self.receive_from_actor().await
+    }
+
+    async fn receive_from_actor(&mut self) -> Result<FromConnectionHandle, Error> {
+        loop {
+            match self.from_actor_receiver.recv().await {
+                Ok(message) => return Ok(message),
+                // The connection is still alive, only this receiver fell behind
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Receiver fell behind, skipped {} messages", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    return Err(Error::ActorUnavailable)
+                }
+            }
+        }
     }
 
     pub async fn send_get_addr(&self) -> Result<(), Error> {
@@ -111,7 +405,7 @@ impl ConnectionHandle {
     }
 
     pub async fn init_handshake(&mut self) -> Result<(), Error> {
-        let version = build_version_message(&self.peer_address, &self.sender_address);
+        let version = self.version.build(&self.peer_address, &self.sender_address);
         let message = ToConnectionHandle::InitHandshake { version };
 
         self.to_actor_sender
@@ -119,52 +413,86 @@ impl ConnectionHandle {
             .await
             .map_err(|_| Error::ActorSendError)?;
 
-        self.receive_version().await?;
+        let peer_version = self.receive_version().await?;
         self.receive_verack().await?;
+        self.peer_version = Some(peer_version);
+        if let (Some(network_time), Some(skew)) = (&self.network_time, self.peer_clock_skew()) {
+            network_time.add_sample(self.peer_address.ip(), skew);
+        }
 
         Ok(())
     }
 
     pub async fn receive_version(&mut self) -> Result<VersionMessage, Error> {
-        let message = self.receive().await?;
-        let FromConnectionHandle::FromBitcoinNode(NetworkMessage::Version(message)) = message else {
-            return Err(Error::UnexpectedConnectionMessage(Box::new(message)))
+        let message = self.receive_from_actor().await?;
+        let FromConnectionHandle::FromBitcoinNode(NetworkMessage::Version(message)) = message
+        else {
+            return Err(Error::UnexpectedConnectionMessage(Box::new(message)));
         };
+        self.peer_version_received_at = Some(chrono::Utc::now());
 
         Ok(message)
     }
 
+    /// Wait for the verack. Feature negotiation messages (`wtxidrelay`, `sendaddrv2` and
+    /// `sendtxrcncl`) arrive before it, they are kept and returned by the next calls to
+    /// [`Self::receive`]. Anything else, or more negotiation messages than there are features,
+    /// fails the handshake.
     pub async fn receive_verack(&mut self) -> Result<(), Error> {
-        let message = self.receive().await?;
-        let FromConnectionHandle::FromBitcoinNode(NetworkMessage::Verack) = message else {
-            return Err(Error::UnexpectedConnectionMessage(Box::new(message)))
-        };
+        loop {
+            match self.receive_from_actor().await? {
+                FromConnectionHandle::FromBitcoinNode(NetworkMessage::Verack) => return Ok(()),
+                message
+                    if is_feature_negotiation(&message)
+                        && self.buffered.len() < MAX_NEGOTIATION_MESSAGES =>
+                {
+                    self.buffered.push_back(message)
+                }
+                message => return Err(Error::UnexpectedConnectionMessage(Box::new(message))),
+            }
+        }
+    }
+}
 
-        Ok(())
+/// `wtxidrelay`, `sendaddrv2` and `sendtxrcncl`, each sent once.
+const MAX_NEGOTIATION_MESSAGES: usize = 3;
+
+/// Messages that may arrive between `version` and `verack`.
+fn is_feature_negotiation(message: &FromConnectionHandle) -> bool {
+    match message {
+        FromConnectionHandle::FromBitcoinNode(
+            NetworkMessage::WtxidRelay | NetworkMessage::SendAddrV2,
+        ) => true,
+        FromConnectionHandle::FromBitcoinNode(NetworkMessage::Unknown { command, .. })
+        | FromConnectionHandle::Unknown { command, .. } => command.as_ref() == "sendtxrcncl",
+        _ => false,
     }
 }
 
-fn build_version_message(peer_address: &SocketAddr, sender_address: &SocketAddr) -> VersionMessage {
-    /// The height of the block that the node is currently at.
-    /// We are always at the genesis block. because our implementation is not a real node.
-    const START_HEIGHT: i32 = 0;
-    const USER_AGENT: &str = "bitcoin-handshake";
-    const SERVICES: ServiceFlags = ServiceFlags::NONE;
-
-    let sender = Address::new(sender_address, SERVICES);
-    let timestamp = chrono::Utc::now().timestamp();
-    let receiver = Address::new(peer_address, SERVICES);
-    let nonce = rand::thread_rng().gen();
-    let user_agent = USER_AGENT.to_string();
-
-    // Construct the message
-    VersionMessage::new(
-        SERVICES,
-        timestamp,
-        receiver,
-        sender,
-        nonce,
-        user_agent,
-        START_HEIGHT,
-    )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_feature_negotiation_is_buffered_before_verack() {
+        let sendtxrcncl = CommandString::try_from_static("sendtxrcncl").unwrap();
+        for message in [NetworkMessage::WtxidRelay, NetworkMessage::SendAddrV2] {
+            assert!(is_feature_negotiation(
+                &FromConnectionHandle::FromBitcoinNode(message)
+            ));
+        }
+        assert!(is_feature_negotiation(&FromConnectionHandle::Unknown {
+            command: sendtxrcncl,
+            payload: Vec::new(),
+        }));
+        for message in [NetworkMessage::Ping(1), NetworkMessage::Inv(Vec::new())] {
+            assert!(!is_feature_negotiation(
+                &FromConnectionHandle::FromBitcoinNode(message)
+            ));
+        }
+        assert!(!is_feature_negotiation(&FromConnectionHandle::Unknown {
+            command: CommandString::try_from_static("junk").unwrap(),
+            payload: Vec::new(),
+        }));
+    }
 }
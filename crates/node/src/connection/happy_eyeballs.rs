@@ -0,0 +1,88 @@
+//! Racing connection attempts to the addresses of a peer, as described in RFC 8305.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+
+use crate::error::Error;
+
+/// How long an attempt gets before the next one is started in parallel (RFC 8305, section 5).
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Sort the addresses so that the families alternate, starting with the family of the first
+/// address. The order within a family is kept, duplicates are removed.
+pub(super) fn interleave(addresses: &[SocketAddr]) -> VecDeque<SocketAddr> {
+    let mut unique: Vec<SocketAddr> = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        if !unique.contains(address) {
+            unique.push(*address);
+        }
+    }
+    let first_is_ipv6 = matches!(unique.first(), Some(SocketAddr::V6(_)));
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = unique
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_ipv6);
+
+    let mut sorted = VecDeque::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        sorted.extend(preferred.pop_front());
+        sorted.extend(other.pop_front());
+    }
+    sorted
+}
+
+/// Start an attempt for the first address, and one more every 250 ms or as soon as an attempt
+/// fails. The first attempt that succeeds wins, the others are dropped, which closes their
+/// sockets. Fails with the error of the last attempt when all of them fail.
+pub(super) async fn race<T, F, Fut>(addresses: &[SocketAddr], attempt: F) -> Result<T, Error>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut queue = interleave(addresses);
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = Error::NoAddress;
+    loop {
+        if attempts.is_empty() {
+            let Some(address) = queue.pop_front() else {
+                return Err(last_error);
+            };
+            attempts.push(attempt_with_address(address, &attempt));
+        }
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(connection) => {
+                    tracing::debug!("Connected to {}", address);
+                    return Ok(connection);
+                }
+                Err(err) => {
+                    tracing::debug!("Attempt to connect to {} failed: {}", address, err);
+                    last_error = err;
+                    // The next attempt starts right away
+                    if let Some(address) = queue.pop_front() {
+                        attempts.push(attempt_with_address(address, &attempt));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if !queue.is_empty() => {
+                if let Some(address) = queue.pop_front() {
+                    attempts.push(attempt_with_address(address, &attempt));
+                }
+            }
+        }
+    }
+}
+
+async fn attempt_with_address<T, F, Fut>(
+    address: SocketAddr,
+    attempt: &F,
+) -> (SocketAddr, Result<T, Error>)
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    (address, attempt(address).await)
+}
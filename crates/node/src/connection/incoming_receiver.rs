@@ -1,57 +1,104 @@
 use std::io::BufReader;
 use std::net::TcpStream;
 
-use bitcoin::consensus::{self, Decodable};
-use bitcoin::network::message::RawNetworkMessage;
+use bitcoin::network::constants;
+use bitcoin::network::message::NetworkMessage;
 
+use super::frame::{Frame, FrameError, FrameHeader};
+use super::pcap::{self, Direction, SharedCapture};
+use super::stats::StatsRecorder;
 use crate::FromConnectionHandle;
 
 /// The incoming receiver is responsible for reading messages from the node and
-/// propagating them to the outside world.
+/// passing them to the protocol driver.
 #[derive(Debug)]
 pub(crate) struct IncomingReceiver {
-    from_node: tokio::sync::broadcast::Sender<FromConnectionHandle>,
+    from_node: tokio::sync::mpsc::Sender<FromConnectionHandle>,
     read_stream: BufReader<TcpStream>,
+    network: constants::Network,
+    capture: Option<SharedCapture>,
+    stats: StatsRecorder,
+    /// Number of frames that could not be decoded so far.
+    decode_errors: u64,
 }
 
 impl IncomingReceiver {
     pub(crate) fn new(
-        from_node: tokio::sync::broadcast::Sender<FromConnectionHandle>,
+        from_node: tokio::sync::mpsc::Sender<FromConnectionHandle>,
         read_stream: TcpStream,
+        network: constants::Network,
+        capture: Option<SharedCapture>,
+        stats: StatsRecorder,
     ) -> Self {
         Self {
             from_node,
             read_stream: BufReader::new(read_stream),
+            network,
+            capture,
+            stats,
+            decode_errors: 0,
         }
     }
 
-    /// Read messages from the stream and propagate them to the outside world
+    /// Read messages from the stream and pass them to the protocol driver. The driver falling
+    /// behind stops the reading, which pushes back on the peer.
+    ///
+    /// Frames are read header first, so a single frame that cannot be decoded does not
+    /// break the connection: it is reported and the next frame is read.
     pub(crate) async fn broadcast_from_node(&mut self) {
         loop {
-            match self.blocking_read() {
-                Ok(msg) => {
-                    let sent = self
-                        .from_node
-                        .send(FromConnectionHandle::FromBitcoinNode(msg.payload));
-                    if sent.is_err() {
-                        tracing::warn!("Outgoing message channel is closed");
-                        break;
+            let message = match self.blocking_read() {
+                Ok(Ok(frame)) => {
+                    pcap::record(&self.capture, Direction::Inbound, &frame.raw);
+                    self.stats
+                        .record_inbound(frame.header.command.as_ref(), frame.raw.len());
+                    match frame.decode() {
+                        Ok(msg) => match msg.payload {
+                            NetworkMessage::Unknown { command, payload } => {
+                                tracing::debug!("Received unknown command {}", command);
+                                FromConnectionHandle::Unknown { command, payload }
+                            }
+                            payload => FromConnectionHandle::FromBitcoinNode(payload),
+                        },
+                        Err(err) => self.malformed(frame.header, err),
                     }
                 }
+                Ok(Err((header, err))) => self.malformed(header, err),
                 Err(err) => {
-                    // https://www.reddit.com/r/rust/comments/b095ag/failed_to_fill_whole_buffer_error_with_bufreader/
-                    // https://stackoverflow.com/questions/70739158/failed-to-fill-whole-buffer-error-message-when-trying-to-deserialise-an-object
                     tracing::error!(
-                            "Failed to read-decode message from the stream: {:?}. This usually happens when the node sends some data that we cannot deserialize.",
-                            err
-                        );
+                        "Failed to read message from the stream: {}. The connection can not be read any further.",
+                        err
+                    );
                     break;
                 }
+            };
+
+            let sent = self.from_node.send(message).await;
+            if sent.is_err() {
+                tracing::warn!("Outgoing message channel is closed");
+                break;
             }
         }
     }
 
-    fn blocking_read(&mut self) -> Result<RawNetworkMessage, consensus::encode::Error> {
-        tokio::task::block_in_place(|| RawNetworkMessage::consensus_decode(&mut self.read_stream))
+    fn malformed(&mut self, header: FrameHeader, error: FrameError) -> FromConnectionHandle {
+        self.decode_errors += 1;
+        self.stats.record_decode_error();
+        tracing::warn!(
+            "Failed to decode {} message: {:?} ({} decode errors so far)",
+            header.command,
+            error,
+            self.decode_errors
+        );
+        FromConnectionHandle::Malformed {
+            command: header.command,
+            error,
+            decode_errors: self.decode_errors,
+        }
+    }
+
+    fn blocking_read(&mut self) -> std::io::Result<Result<Frame, (FrameHeader, FrameError)>> {
+        let magic = self.network.magic();
+        tokio::task::block_in_place(|| Frame::read_from(&mut self.read_stream, magic))
     }
 }
@@ -0,0 +1,333 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bitcoin::network::constants;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_network::VersionMessage;
+
+use super::default_handlers::{HandshakeHandler, PingHandler};
+use super::stats::{ConnectionStats, StatsRecorder};
+
+/// Custom behavior of a connection, e.g. logging some commands, answering requests or
+/// rewriting the messages we send.
+///
+/// Every connection gets its own handlers, created by the factory registered with
+/// [`BitcoinConnector::with_message_handler`](crate::BitcoinConnector::with_message_handler).
+/// Inbound messages pass the built-in handlers first and then the registered handlers in
+/// registration order, before the connection acts on them and the subscribers receive them.
+/// Outbound messages pass the handlers in reverse order, the built-in ones last. Messages a
+/// handler sends, with [`HandlerContext::send`] or [`HandlerAction::Reply`], pass the
+/// outbound handlers too, except the one that sent them.
+pub trait MessageHandler: Send {
+    /// A message from the peer.
+    fn on_inbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> HandlerAction {
+        let _ = context;
+        HandlerAction::Pass(message)
+    }
+
+    /// A message on its way to the peer.
+    fn on_outbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> HandlerAction {
+        let _ = context;
+        HandlerAction::Pass(message)
+    }
+}
+
+/// What happens to a message after a handler has seen it.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum HandlerAction {
+    /// Hand the message, possibly rewritten, to the next handler.
+    Pass(NetworkMessage),
+    /// The message goes no further.
+    Consume,
+    /// The message goes no further, the messages are sent to the peer instead. They pass the
+    /// outbound handlers, except this one.
+    Reply(Vec<NetworkMessage>),
+}
+
+/// What a handler knows about the connection.
+pub struct HandlerContext<'a> {
+    peer_address: SocketAddr,
+    network: constants::Network,
+    stats: &'a StatsRecorder,
+    pub(crate) state: &'a mut ConnectionState,
+    outbox: &'a mut Vec<HandlerMessage>,
+    /// The index of the handler that is running.
+    handler: Option<usize>,
+}
+
+/// A message sent by a handler, or by the driver when `sender` is `None`.
+#[derive(Debug)]
+pub(crate) struct HandlerMessage {
+    pub(crate) sender: Option<usize>,
+    pub(crate) message: NetworkMessage,
+}
+
+/// Protocol state shared by the built-in handlers and the driver.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionState {
+    pub(crate) version_sent: bool,
+    /// The protocol version we sent.
+    pub(crate) protocol_version: u32,
+    /// Feature negotiation messages that follow our version, before the verack.
+    pub(crate) negotiation: Vec<NetworkMessage>,
+    /// The version message of the peer, the verack may only be sent once both versions were
+    /// exchanged.
+    pub(crate) peer_version: Option<VersionMessage>,
+    /// We sent `wtxidrelay` (BIP339) before our verack.
+    pub(crate) wtxid_relay_sent: bool,
+    /// The peer sent `wtxidrelay` before its verack.
+    pub(crate) peer_wtxid_relay: bool,
+    /// Pings may only be sent after the peer acknowledged our version.
+    pub(crate) verack_received: bool,
+    /// The nonce and the send time of the ping that has not been answered yet.
+    pub(crate) pending_ping: Option<(u64, Instant)>,
+}
+
+impl ConnectionState {
+    /// Both sides announce transactions by wtxid.
+    pub(crate) fn wtxid_relay(&self) -> bool {
+        self.wtxid_relay_sent && self.peer_wtxid_relay
+    }
+}
+
+impl<'a> HandlerContext<'a> {
+    pub(crate) fn new(
+        network: constants::Network,
+        stats: &'a StatsRecorder,
+        state: &'a mut ConnectionState,
+        outbox: &'a mut Vec<HandlerMessage>,
+    ) -> Self {
+        Self {
+            peer_address: stats.peer_address(),
+            network,
+            stats,
+            state,
+            outbox,
+            handler: None,
+        }
+    }
+
+    pub fn peer_address(&self) -> SocketAddr {
+        self.peer_address
+    }
+
+    pub fn network(&self) -> constants::Network {
+        self.network
+    }
+
+    /// The version message of the peer, once it was received.
+    pub fn peer_version(&self) -> Option<&VersionMessage> {
+        self.state.peer_version.as_ref()
+    }
+
+    /// Whether the peer acknowledged our version.
+    pub fn handshake_complete(&self) -> bool {
+        self.state.verack_received
+    }
+
+    /// Whether both sides negotiated to announce transactions by wtxid (BIP339).
+    pub fn wtxid_relay(&self) -> bool {
+        self.state.wtxid_relay()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    pub(crate) fn stats_recorder(&self) -> &StatsRecorder {
+        self.stats
+    }
+
+    /// Send a message to the peer once the current message was handled. It passes the
+    /// outbound handlers, except the one sending it.
+    pub fn send(&mut self, message: NetworkMessage) {
+        self.outbox.push(HandlerMessage {
+            sender: self.handler,
+            message,
+        });
+    }
+}
+
+/// Creates the handler of every new connection.
+#[derive(Clone)]
+pub(crate) struct HandlerFactory(Arc<dyn Fn() -> Box<dyn MessageHandler> + Send + Sync>);
+
+impl HandlerFactory {
+    pub(crate) fn new<H>(handler: H) -> Self
+    where
+        H: MessageHandler + Clone + Sync + 'static,
+    {
+        Self(Arc::new(move || Box::new(handler.clone())))
+    }
+
+    pub(crate) fn create(&self) -> Box<dyn MessageHandler> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for HandlerFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerFactory").finish_non_exhaustive()
+    }
+}
+
+/// The handlers of a connection, the built-in ones first.
+pub(crate) struct MessageHandlers {
+    handlers: Vec<Box<dyn MessageHandler>>,
+}
+
+impl MessageHandlers {
+    pub(crate) fn new(factories: &[HandlerFactory]) -> Self {
+        let mut handlers: Vec<Box<dyn MessageHandler>> =
+            vec![Box::new(HandshakeHandler), Box::new(PingHandler)];
+        handlers.extend(factories.iter().map(HandlerFactory::create));
+        Self { handlers }
+    }
+
+    /// Pass the message through the handlers, `None` when a handler consumed it.
+    pub(crate) fn inbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: NetworkMessage,
+    ) -> Option<NetworkMessage> {
+        let handlers = self.handlers.iter_mut().enumerate();
+        run(handlers, context, message, |handler, context, message| {
+            handler.on_inbound(context, message)
+        })
+    }
+
+    /// Pass the message through the handlers in reverse order, skipping the handler that sent
+    /// it.
+    pub(crate) fn outbound(
+        &mut self,
+        context: &mut HandlerContext<'_>,
+        message: HandlerMessage,
+    ) -> Option<NetworkMessage> {
+        let handlers = self
+            .handlers
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .filter(|(index, _)| Some(*index) != message.sender);
+        run(
+            handlers,
+            context,
+            message.message,
+            |handler, context, message| handler.on_outbound(context, message),
+        )
+    }
+}
+
+fn run<'h>(
+    handlers: impl Iterator<Item = (usize, &'h mut Box<dyn MessageHandler>)>,
+    context: &mut HandlerContext<'_>,
+    mut message: NetworkMessage,
+    handle: impl Fn(&mut dyn MessageHandler, &mut HandlerContext<'_>, NetworkMessage) -> HandlerAction,
+) -> Option<NetworkMessage> {
+    for (index, handler) in handlers {
+        context.handler = Some(index);
+        let action = handle(handler.as_mut(), context, message);
+        context.handler = None;
+        match action {
+            HandlerAction::Pass(passed) => message = passed,
+            HandlerAction::Consume => return None,
+            HandlerAction::Reply(replies) => {
+                context
+                    .outbox
+                    .extend(replies.into_iter().map(|message| HandlerMessage {
+                        sender: Some(index),
+                        message,
+                    }));
+                return None;
+            }
+        }
+    }
+    Some(message)
+}
+
+impl fmt::Debug for MessageHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageHandlers")
+            .field("handlers", &self.handlers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Answers `getaddr` with an empty `addr` and records the outbound messages it sees.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<NetworkMessage>>>);
+
+    impl MessageHandler for Recorder {
+        fn on_inbound(
+            &mut self,
+            _context: &mut HandlerContext<'_>,
+            message: NetworkMessage,
+        ) -> HandlerAction {
+            match message {
+                NetworkMessage::GetAddr => HandlerAction::Reply(vec![NetworkMessage::Addr(vec![])]),
+                message => HandlerAction::Pass(message),
+            }
+        }
+
+        fn on_outbound(
+            &mut self,
+            _context: &mut HandlerContext<'_>,
+            message: NetworkMessage,
+        ) -> HandlerAction {
+            self.0.lock().unwrap().push(message.clone());
+            HandlerAction::Pass(message)
+        }
+    }
+
+    #[test]
+    fn sent_messages_pass_the_other_outbound_handlers() {
+        let recorder = Recorder::default();
+        let mut handlers = MessageHandlers::new(&[HandlerFactory::new(recorder.clone())]);
+        let stats = StatsRecorder::new("127.0.0.1:8333".parse().unwrap());
+        let mut state = ConnectionState::default();
+        let mut outbox = Vec::new();
+        let mut context =
+            HandlerContext::new(constants::Network::Regtest, &stats, &mut state, &mut outbox);
+        // The pong of the built-in ping handler passes the recorder
+        assert!(handlers
+            .inbound(&mut context, NetworkMessage::Ping(7))
+            .is_some());
+        // The reply of the recorder does not pass the recorder again
+        assert!(handlers
+            .inbound(&mut context, NetworkMessage::GetAddr)
+            .is_none());
+
+        let mut nothing_sent = Vec::new();
+        let mut context = HandlerContext::new(
+            constants::Network::Regtest,
+            &stats,
+            &mut state,
+            &mut nothing_sent,
+        );
+        let sent: Vec<NetworkMessage> = outbox
+            .into_iter()
+            .filter_map(|message| handlers.outbound(&mut context, message))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![NetworkMessage::Pong(7), NetworkMessage::Addr(vec![])]
+        );
+        assert_eq!(*recorder.0.lock().unwrap(), vec![NetworkMessage::Pong(7)]);
+    }
+}
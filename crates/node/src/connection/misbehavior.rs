@@ -0,0 +1,272 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use bitcoin::consensus::params::Params;
+use bitcoin::network::constants::Network;
+use bitcoin::BlockHeader;
+use settings::MisbehaviorSettings;
+
+use super::frame::FrameError;
+use crate::ban_list::{BanList, Subnet};
+
+/// The score drops by one point each interval, a peer has to misbehave repeatedly within a
+/// short time to be banned. With the default threshold a score of 100 is forgotten in an hour.
+const SCORE_DECAY_INTERVAL_SECS: u64 = 36;
+
+/// Protocol violations that count towards the misbehavior score of a peer.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Misbehavior {
+    /// A message larger than the protocol allows.
+    OversizedMessage,
+    /// A message whose payload does not match the checksum.
+    BadChecksum,
+    /// A message with a known command that cannot be decoded, an invalid command or a
+    /// message of another network.
+    InvalidPayload,
+    /// A block that we never asked for.
+    UnsolicitedBlock,
+    /// Headers with invalid proof of work or that do not form a chain.
+    InvalidHeaders,
+    /// A merkle block whose partial merkle tree does not match the header.
+    InvalidMerkleBlock,
+    /// More messages per second than we are willing to process.
+    Flooding,
+}
+
+impl Misbehavior {
+    /// How much the violation adds to the score of the peer.
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::OversizedMessage => 100,
+            Misbehavior::InvalidHeaders => 100,
+            Misbehavior::InvalidMerkleBlock => 100,
+            Misbehavior::Flooding => 50,
+            Misbehavior::UnsolicitedBlock => 20,
+            Misbehavior::BadChecksum => 10,
+            Misbehavior::InvalidPayload => 10,
+        }
+    }
+}
+
+impl From<&FrameError> for Misbehavior {
+    fn from(error: &FrameError) -> Self {
+        match error {
+            FrameError::Oversized { .. } => Misbehavior::OversizedMessage,
+            FrameError::BadChecksum => Misbehavior::BadChecksum,
+            FrameError::WrongMagic(_)
+            | FrameError::InvalidCommand
+            | FrameError::InvalidPayload(_) => Misbehavior::InvalidPayload,
+        }
+    }
+}
+
+/// Why the connection was closed by us.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum DisconnectReason {
+    /// The misbehavior score of the peer crossed the ban threshold.
+    Misbehavior {
+        score: u32,
+        /// The violation that pushed the score over the threshold.
+        last: Misbehavior,
+    },
+}
+
+/// Keeps the misbehavior score of a single peer and bans it once the threshold is crossed.
+#[derive(Debug)]
+pub(crate) struct MisbehaviorTracker {
+    peer: IpAddr,
+    ban_list: BanList,
+    ban_duration: chrono::Duration,
+    score: u32,
+    /// Decay of the score is accounted up to this time.
+    score_updated: Instant,
+    ban_threshold: u32,
+    max_messages_per_second: u32,
+    window_start: Instant,
+    messages_in_window: u32,
+}
+
+impl MisbehaviorTracker {
+    pub(crate) fn new(peer: IpAddr, ban_list: BanList, settings: &MisbehaviorSettings) -> Self {
+        Self {
+            peer,
+            ban_list,
+            ban_duration: chrono::Duration::seconds(settings.ban_duration_secs() as i64),
+            score: 0,
+            score_updated: Instant::now(),
+            ban_threshold: settings.ban_threshold(),
+            max_messages_per_second: settings.max_messages_per_second(),
+            window_start: Instant::now(),
+            messages_in_window: 0,
+        }
+    }
+
+    /// Add the violation to the score. Returns the reason to disconnect once the score
+    /// crosses the threshold.
+    pub(crate) fn record(&mut self, misbehavior: Misbehavior) -> Result<(), DisconnectReason> {
+        self.decay(Instant::now());
+        self.score = self.score.saturating_add(misbehavior.score());
+        tracing::warn!(
+            "Peer misbehaved: {:?}, score {}/{}",
+            misbehavior,
+            self.score,
+            self.ban_threshold
+        );
+        if self.score >= self.ban_threshold {
+            return Err(DisconnectReason::Misbehavior {
+                score: self.score,
+                last: misbehavior,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn score(&self) -> u32 {
+        self.score
+    }
+
+    /// Lower the score by the points that decayed since the last update.
+    fn decay(&mut self, now: Instant) {
+        let decayed =
+            now.saturating_duration_since(self.score_updated).as_secs() / SCORE_DECAY_INTERVAL_SECS;
+        if decayed == 0 {
+            return;
+        }
+        self.score = u64::from(self.score).saturating_sub(decayed) as u32;
+        self.score_updated += Duration::from_secs(decayed * SCORE_DECAY_INTERVAL_SECS);
+    }
+
+    /// Count an unsolicited incoming message, the peer is flooding us when there are too many
+    /// within a second. Answers to our requests are not counted, see
+    /// `ProtocolDriver::score_message`.
+    pub(crate) fn record_message(&mut self) -> Result<(), DisconnectReason> {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.messages_in_window = 0;
+        }
+        self.messages_in_window += 1;
+        // Only score once per window
+        if self.messages_in_window == self.max_messages_per_second.saturating_add(1) {
+            return self.record(Misbehavior::Flooding);
+        }
+        Ok(())
+    }
+
+    /// Put the peer on the ban list. Peers reached by a host name through a proxy have no IP
+    /// address to ban, they are only disconnected.
+    pub(crate) fn ban(&self, reason: &DisconnectReason) {
+        if self.peer.is_unspecified() {
+            tracing::warn!("Not banning a peer without an IP address: {:?}", reason);
+            return;
+        }
+        let banned = self.ban_list.ban(
+            Subnet::from(self.peer),
+            Some(self.ban_duration),
+            format!("{reason:?}"),
+        );
+        if let Err(err) = banned {
+            tracing::error!("Failed to persist the ban of {}: {}", self.peer, err);
+        }
+    }
+}
+
+/// Headers must have a valid proof of work, at least the minimum difficulty of the network, and
+/// connect to each other.
+pub(crate) fn headers_are_valid(headers: &[BlockHeader], network: Network) -> bool {
+    let pow_limit = Params::new(network).pow_limit;
+    let valid_pow = headers.iter().all(|header| {
+        let target = header.target();
+        target <= pow_limit && header.validate_pow(&target).is_ok()
+    });
+    let connected = headers
+        .windows(2)
+        .all(|pair| pair[1].prev_blockhash == pair[0].block_hash());
+    valid_pow && connected
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::BlockHash;
+
+    use super::*;
+
+    fn tracker() -> MisbehaviorTracker {
+        MisbehaviorTracker::new(
+            IpAddr::from([127, 0, 0, 1]),
+            BanList::in_memory(),
+            &MisbehaviorSettings::default(),
+        )
+    }
+
+    #[test]
+    fn the_score_decays() {
+        let mut tracker = tracker();
+        assert!(tracker.record(Misbehavior::Flooding).is_ok());
+        assert_eq!(tracker.score, 50);
+
+        let start = tracker.score_updated;
+        tracker.decay(start + Duration::from_secs(SCORE_DECAY_INTERVAL_SECS * 20 + 1));
+        assert_eq!(tracker.score, 30);
+        tracker.decay(start + Duration::from_secs(SCORE_DECAY_INTERVAL_SECS * 21));
+        assert_eq!(tracker.score, 29);
+        tracker.decay(start + Duration::from_secs(SCORE_DECAY_INTERVAL_SECS * 1000));
+        assert_eq!(tracker.score, 0);
+
+        // Flooding twice within the decay still crosses the threshold
+        assert!(tracker.record(Misbehavior::Flooding).is_ok());
+        assert!(tracker.record(Misbehavior::Flooding).is_err());
+    }
+
+    fn mine(prev_blockhash: BlockHash, bits: u32) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 4,
+            prev_blockhash,
+            merkle_root: Hash::all_zeros(),
+            time: 1_700_000_000,
+            bits,
+            nonce: 0,
+        };
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn headers_must_connect() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let first = mine(genesis, 0x207fffff);
+        let second = mine(first.block_hash(), 0x207fffff);
+        assert!(headers_are_valid(&[first, second], Network::Regtest));
+        assert!(!headers_are_valid(&[second, first], Network::Regtest));
+    }
+
+    #[test]
+    fn targets_above_the_pow_limit_are_invalid() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        // Easier than regtest allows, the proof of work matches the header's own bits
+        let easy = mine(genesis, 0x2100ffff);
+        assert!(easy.validate_pow(&easy.target()).is_ok());
+        assert!(!headers_are_valid(&[easy], Network::Regtest));
+
+        // Valid on regtest but far below the mainnet minimum difficulty
+        let regtest = mine(genesis, 0x207fffff);
+        assert!(headers_are_valid(&[regtest], Network::Regtest));
+        assert!(!headers_are_valid(&[regtest], Network::Bitcoin));
+    }
+
+    #[test]
+    fn the_genesis_header_is_valid() {
+        for network in [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            assert!(headers_are_valid(&[genesis_block(network).header], network));
+        }
+    }
+}
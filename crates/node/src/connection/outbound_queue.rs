@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+use bitcoin::network::message::NetworkMessage;
+use tokio::sync::Notify;
+
+/// Message classes, written to the node in this order.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Priority {
+    /// Small messages that keep the connection healthy (pong, verack, ...).
+    Control = 0,
+    /// Requests and announcements.
+    Normal = 1,
+    /// Large data transfers like blocks, transactions and filters.
+    Bulk = 2,
+}
+
+const PRIORITIES: usize = 3;
+
+impl Priority {
+    pub fn of(message: &NetworkMessage) -> Self {
+        match message {
+            NetworkMessage::Version(_)
+            | NetworkMessage::Verack
+            | NetworkMessage::Ping(_)
+            | NetworkMessage::Pong(_)
+            | NetworkMessage::SendHeaders
+            | NetworkMessage::SendCmpct(_)
+            | NetworkMessage::FeeFilter(_)
+            | NetworkMessage::WtxidRelay
+            | NetworkMessage::SendAddrV2
+            | NetworkMessage::Reject(_)
+            | NetworkMessage::FilterLoad(_)
+            | NetworkMessage::FilterAdd(_)
+            | NetworkMessage::FilterClear => Priority::Control,
+            // Feature negotiation must not be overtaken by the verack
+            NetworkMessage::Unknown { command, .. } if command.as_ref() == "sendtxrcncl" => {
+                Priority::Control
+            }
+            NetworkMessage::Block(_)
+            | NetworkMessage::Tx(_)
+            | NetworkMessage::Headers(_)
+            | NetworkMessage::CmpctBlock(_)
+            | NetworkMessage::BlockTxn(_)
+            | NetworkMessage::MerkleBlock(_)
+            | NetworkMessage::CFilter(_)
+            | NetworkMessage::CFHeaders(_)
+            | NetworkMessage::CFCheckpt(_) => Priority::Bulk,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// How much data is waiting to be written to the node.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct QueueDepth {
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+/// Serialized messages waiting to be written to the node, grouped by priority.
+///
+/// The queue is shared between the handle (which checks for backpressure), the protocol driver
+/// (which enqueues) and the writer task (which drains the queue into the socket).
+#[derive(Debug)]
+pub(crate) struct OutboundQueue {
+    state: Mutex<State>,
+    /// Soft limit of queued bytes. A message is always accepted when the queue is empty,
+    /// so a single message larger than the limit does not block forever.
+    limit: usize,
+    /// Wakes up the writer when a message is enqueued.
+    message_available: Notify,
+    /// Wakes up senders waiting for the buffer to drain.
+    space_available: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queues: [VecDeque<Vec<u8>>; PRIORITIES],
+    depth: QueueDepth,
+    closed: bool,
+}
+
+impl OutboundQueue {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            limit,
+            message_available: Notify::new(),
+            space_available: Notify::new(),
+        }
+    }
+
+    pub(crate) fn depth(&self) -> QueueDepth {
+        self.lock().depth
+    }
+
+    /// Whether the queue can take more messages without exceeding the limit.
+    pub(crate) fn has_space(&self) -> bool {
+        let state = self.lock();
+        state.depth.messages == 0 || state.depth.bytes < self.limit
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Wait until the queue has space. Returns `false` if the queue was closed in the meantime.
+    pub(crate) async fn wait_for_space(&self) -> bool {
+        loop {
+            // Register interest before checking, so that a wakeup between the check and the
+            // await is not lost.
+            let notified = self.space_available.notified();
+            if self.is_closed() {
+                return false;
+            }
+            if self.has_space() {
+                return true;
+            }
+            notified.await;
+        }
+    }
+
+    /// Enqueue a serialized message. Messages enqueued by the protocol itself bypass the limit,
+    /// the limit is enforced by the senders via [`Self::has_space`] and [`Self::wait_for_space`].
+    pub(crate) fn push(&self, priority: Priority, frame: Vec<u8>) {
+        {
+            let mut state = self.lock();
+            if state.closed {
+                return;
+            }
+            state.depth.messages += 1;
+            state.depth.bytes += frame.len();
+            state.queues[priority as usize].push_back(frame);
+        }
+        self.message_available.notify_one();
+    }
+
+    /// Wait for the next message, highest priority first. Returns `None` once the queue is closed.
+    pub(crate) async fn pop(&self) -> Option<Vec<u8>> {
+        loop {
+            let notified = self.message_available.notified();
+            {
+                let mut state = self.lock();
+                if state.closed {
+                    return None;
+                }
+                let frame = state.queues.iter_mut().find_map(|queue| queue.pop_front());
+                if let Some(frame) = frame {
+                    state.depth.messages -= 1;
+                    state.depth.bytes -= frame.len();
+                    drop(state);
+                    self.space_available.notify_waiters();
+                    return Some(frame);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Drop everything that is queued and wake up everyone that is waiting.
+    pub(crate) fn close(&self) {
+        {
+            let mut state = self.lock();
+            state.closed = true;
+            state.queues.iter_mut().for_each(VecDeque::clear);
+            state.depth = QueueDepth::default();
+        }
+        self.message_available.notify_one();
+        self.space_available.notify_waiters();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn higher_priorities_are_written_first() {
+        let queue = OutboundQueue::new(usize::MAX);
+        queue.push(Priority::Bulk, b"block1".to_vec());
+        queue.push(Priority::Normal, b"inv".to_vec());
+        queue.push(Priority::Control, b"pong1".to_vec());
+        queue.push(Priority::Bulk, b"block2".to_vec());
+        queue.push(Priority::Control, b"pong2".to_vec());
+        assert_eq!(
+            queue.depth(),
+            QueueDepth {
+                messages: 5,
+                bytes: 25
+            }
+        );
+
+        let mut written = Vec::new();
+        while queue.depth().messages > 0 {
+            written.push(queue.pop().await.unwrap());
+        }
+        // Messages of the same priority keep their order
+        assert_eq!(
+            written,
+            [&b"pong1"[..], b"pong2", b"inv", b"block1", b"block2"]
+        );
+        assert_eq!(queue.depth(), QueueDepth::default());
+    }
+
+    #[tokio::test]
+    async fn space_is_available_below_the_limit() {
+        let queue = OutboundQueue::new(10);
+        assert!(queue.has_space());
+        queue.push(Priority::Normal, vec![0; 4]);
+        assert!(queue.has_space());
+        queue.push(Priority::Normal, vec![0; 6]);
+        assert!(!queue.has_space());
+        queue.pop().await.unwrap();
+        assert!(queue.has_space());
+
+        // A single message above the limit is accepted into an empty queue
+        let queue = OutboundQueue::new(10);
+        queue.push(Priority::Bulk, vec![0; 100]);
+        assert!(!queue.has_space());
+        queue.pop().await.unwrap();
+        assert!(queue.has_space());
+    }
+
+    #[tokio::test]
+    async fn waiting_senders_are_woken_up() {
+        let queue = Arc::new(OutboundQueue::new(1));
+        queue.push(Priority::Normal, vec![0]);
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait_for_space().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+        queue.pop().await.unwrap();
+        assert!(waiting.await.unwrap());
+
+        // Closing the queue releases the senders without space
+        queue.push(Priority::Normal, vec![0]);
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait_for_space().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        queue.close();
+        assert!(!waiting.await.unwrap());
+        assert_eq!(queue.depth(), QueueDepth::default());
+        assert_eq!(queue.pop().await, None);
+        queue.push(Priority::Control, vec![0]);
+        assert_eq!(queue.depth(), QueueDepth::default());
+    }
+}
@@ -0,0 +1,55 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use super::frame;
+use super::outbound_queue::OutboundQueue;
+use super::pcap::{self, Direction, SharedCapture};
+use super::stats::StatsRecorder;
+
+/// The outbound writer drains the outbound queue into the socket, highest priority first.
+#[derive(Debug)]
+pub(crate) struct OutboundWriter {
+    queue: Arc<OutboundQueue>,
+    write_stream: TcpStream,
+    capture: Option<SharedCapture>,
+    stats: StatsRecorder,
+}
+
+impl OutboundWriter {
+    pub(crate) fn new(
+        queue: Arc<OutboundQueue>,
+        write_stream: TcpStream,
+        capture: Option<SharedCapture>,
+        stats: StatsRecorder,
+    ) -> Self {
+        Self {
+            queue,
+            write_stream,
+            capture,
+            stats,
+        }
+    }
+
+    /// Write queued messages to the stream until the queue is closed or the stream breaks.
+    pub(crate) async fn write_to_node(&mut self) {
+        while let Some(frame) = self.queue.pop().await {
+            if let Err(err) = self.blocking_write(&frame) {
+                tracing::error!("Failed to write message to the stream: {:?}", err);
+                break;
+            }
+            if let Some(command) = frame::command_of(&frame) {
+                self.stats.record_outbound(command.as_ref(), frame.len());
+            }
+        }
+    }
+
+    fn blocking_write(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        tokio::task::block_in_place(|| {
+            self.write_stream.write_all(frame)?;
+            // Only what reached the socket is part of the captured stream
+            pcap::record(&self.capture, Direction::Outbound, frame);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,453 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use settings::CaptureSettings;
+
+/// The capture is shared between the read and write halves of the connection.
+pub(crate) type SharedCapture = Arc<Mutex<PcapWriter>>;
+
+/// LINKTYPE_RAW, the packets start directly with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u16 = 101;
+/// Keep the synthesized segments at a typical ethernet MSS so the TCP reassembly looks natural.
+const MAX_SEGMENT_SIZE: usize = 1460;
+const TCP_HEADER_LEN: usize = 20;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+/// Tells apart the captures of connections to the same peer started in the same second.
+static CAPTURES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum Direction {
+    /// Traffic from the remote node to us.
+    Inbound,
+    /// Traffic from us to the remote node.
+    Outbound,
+}
+
+/// Writes the connection traffic as a pcapng file with synthesized TCP/IP headers.
+///
+/// Only the TCP payload is real, the IP and TCP headers are generated so that Wireshark can
+/// reassemble the stream and hand it to its built-in Bitcoin dissector.
+#[derive(Debug)]
+pub(crate) struct PcapWriter {
+    base_path: PathBuf,
+    max_file_size: u64,
+    file_index: u32,
+    written: u64,
+    file: BufWriter<File>,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// Next sequence number we send.
+    local_seq: u32,
+    /// Next sequence number the remote node sends.
+    remote_seq: u32,
+}
+
+impl PcapWriter {
+    /// Start a capture file of its own for the connection, named after the configured path,
+    /// the peer, the time and a counter.
+    pub(crate) fn new(
+        settings: &CaptureSettings,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> std::io::Result<Self> {
+        let base_path = connection_path(
+            settings.path(),
+            remote,
+            chrono::Utc::now(),
+            CAPTURES.fetch_add(1, Ordering::Relaxed),
+        );
+        if let Some(parent) = base_path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let file = BufWriter::new(File::create(&base_path)?);
+        let mut writer = Self {
+            base_path,
+            max_file_size: settings.max_file_size(),
+            file_index: 0,
+            written: 0,
+            file,
+            local,
+            remote,
+            local_seq: rand::random(),
+            remote_seq: rand::random(),
+        };
+        writer.write_file_header()?;
+        Ok(writer)
+    }
+
+    /// The file of the connection, rotated files are numbered after it.
+    pub(crate) fn path(&self) -> &Path {
+        &self.base_path
+    }
+
+    pub(crate) fn shared(self) -> SharedCapture {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Record a chunk of the TCP stream. Large chunks are split into multiple segments.
+    pub(crate) fn record(&mut self, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        if self.written >= self.max_file_size {
+            self.rotate()?;
+        }
+        let timestamp = timestamp_micros();
+        for segment in data.chunks(MAX_SEGMENT_SIZE) {
+            let packet = self.build_packet(direction, segment);
+            self.write_enhanced_packet(timestamp, &packet)?;
+        }
+        self.file.flush()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file_index += 1;
+        let path = rotated_path(&self.base_path, self.file_index);
+        tracing::info!("Rotating packet capture to {}", path.display());
+        self.file = BufWriter::new(File::create(path)?);
+        self.written = 0;
+        self.write_file_header()
+    }
+
+    fn write_file_header(&mut self) -> std::io::Result<()> {
+        // Section header block
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // major version
+        body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // unknown section length
+        self.write_block(BLOCK_SECTION_HEADER, &body)?;
+
+        // Interface description block
+        let mut body = Vec::with_capacity(8);
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        body.extend_from_slice(&0u32.to_le_bytes()); // no snap length limit
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn write_enhanced_packet(&mut self, timestamp: u64, packet: &[u8]) -> std::io::Result<()> {
+        let mut body = Vec::with_capacity(20 + packet.len() + 3);
+        body.extend_from_slice(&0u32.to_le_bytes()); // interface id
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+        body.extend_from_slice(packet);
+        body.resize(body.len() + padding(packet.len()), 0);
+        self.write_block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+        let total_length = (body.len() + 12) as u32;
+        self.file.write_all(&block_type.to_le_bytes())?;
+        self.file.write_all(&total_length.to_le_bytes())?;
+        self.file.write_all(body)?;
+        self.file.write_all(&total_length.to_le_bytes())?;
+        self.written += u64::from(total_length);
+        Ok(())
+    }
+
+    /// Build an IP packet carrying a single TCP segment and advance the sequence numbers.
+    fn build_packet(&mut self, direction: Direction, payload: &[u8]) -> Vec<u8> {
+        let (source, destination, seq, ack) = match direction {
+            Direction::Outbound => (self.local, self.remote, self.local_seq, self.remote_seq),
+            Direction::Inbound => (self.remote, self.local, self.remote_seq, self.local_seq),
+        };
+        let tcp = tcp_header(source.port(), destination.port(), seq, ack);
+
+        let mut packet = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => ipv4_header(
+                source.octets(),
+                destination.octets(),
+                tcp.len() + payload.len(),
+            ),
+            (source, destination) => ipv6_header(
+                to_ipv6_octets(source),
+                to_ipv6_octets(destination),
+                tcp.len() + payload.len(),
+            ),
+        };
+        packet.extend_from_slice(&tcp);
+        packet.extend_from_slice(payload);
+
+        let advanced = seq.wrapping_add(payload.len() as u32);
+        match direction {
+            Direction::Outbound => self.local_seq = advanced,
+            Direction::Inbound => self.remote_seq = advanced,
+        }
+        packet
+    }
+}
+
+/// Record the data into the capture, logging instead of failing the connection on errors.
+pub(crate) fn record(capture: &Option<SharedCapture>, direction: Direction, data: &[u8]) {
+    let Some(capture) = capture else {
+        return;
+    };
+    let Ok(mut capture) = capture.lock() else {
+        return;
+    };
+    if let Err(err) = capture.record(direction, data) {
+        tracing::warn!("Failed to write packet capture: {:?}", err);
+    }
+}
+
+fn tcp_header(source_port: u16, destination_port: u16, seq: u32, ack: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(TCP_HEADER_LEN);
+    header.extend_from_slice(&source_port.to_be_bytes());
+    header.extend_from_slice(&destination_port.to_be_bytes());
+    header.extend_from_slice(&seq.to_be_bytes());
+    header.extend_from_slice(&ack.to_be_bytes());
+    header.push(((TCP_HEADER_LEN / 4) as u8) << 4); // data offset
+    header.push(TCP_FLAG_PSH | TCP_FLAG_ACK);
+    header.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+    header.extend_from_slice(&0u16.to_be_bytes()); // checksum, not validated by Wireshark by default
+    header.extend_from_slice(&0u16.to_be_bytes()); // urgent pointer
+    header
+}
+
+fn ipv4_header(source: [u8; 4], destination: [u8; 4], payload_len: usize) -> Vec<u8> {
+    let total_length = (IPV4_HEADER_LEN + payload_len) as u16;
+    let mut header = Vec::with_capacity(IPV4_HEADER_LEN + payload_len);
+    header.push(0x45); // version 4, header length 5 words
+    header.push(0); // DSCP / ECN
+    header.extend_from_slice(&total_length.to_be_bytes());
+    header.extend_from_slice(&0u16.to_be_bytes()); // identification
+    header.extend_from_slice(&0x4000u16.to_be_bytes()); // don't fragment
+    header.push(64); // TTL
+    header.push(6); // TCP
+    header.extend_from_slice(&0u16.to_be_bytes()); // checksum placeholder
+    header.extend_from_slice(&source);
+    header.extend_from_slice(&destination);
+    let checksum = ipv4_checksum(&header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+fn ipv6_header(source: [u8; 16], destination: [u8; 16], payload_len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(IPV6_HEADER_LEN + payload_len);
+    header.extend_from_slice(&0x6000_0000u32.to_be_bytes()); // version 6
+    header.extend_from_slice(&(payload_len as u16).to_be_bytes());
+    header.push(6); // next header: TCP
+    header.push(64); // hop limit
+    header.extend_from_slice(&source);
+    header.extend_from_slice(&destination);
+    header
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn to_ipv6_octets(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+        IpAddr::V6(address) => address.octets(),
+    }
+}
+
+/// Blocks are padded to a 32-bit boundary.
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn timestamp_micros() -> u64 {
+    chrono::Utc::now().timestamp_micros() as u64
+}
+
+/// `captures/peer.pcapng` -> `captures/peer-127.0.0.1_8333-20240101T120000-7.pcapng`, without
+/// the characters that are not allowed in file names on every platform.
+fn connection_path(
+    path: &Path,
+    remote: SocketAddr,
+    started: chrono::DateTime<chrono::Utc>,
+    counter: u64,
+) -> PathBuf {
+    let peer: String = remote
+        .to_string()
+        .chars()
+        .filter(|character| !matches!(character, '[' | ']'))
+        .map(|character| if character == ':' { '_' } else { character })
+        .collect();
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = format!(
+        "{stem}-{peer}-{}-{counter}",
+        started.format("%Y%m%dT%H%M%S")
+    );
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem}.{}", extension.to_string_lossy()),
+        None => stem,
+    };
+    path.with_file_name(file_name)
+}
+
+/// `captures/peer.pcapng` -> `captures/peer.1.pcapng`
+fn rotated_path(base_path: &Path, index: u32) -> PathBuf {
+    let stem = base_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_name = match base_path.extension() {
+        Some(extension) => format!("{stem}.{index}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.{index}"),
+    };
+    base_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn every_connection_gets_its_own_file() {
+        let started = chrono::Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let path = Path::new("captures/peer.pcapng");
+        assert_eq!(
+            connection_path(path, "127.0.0.1:8333".parse().unwrap(), started, 7),
+            Path::new("captures/peer-127.0.0.1_8333-20240102T030405-7.pcapng")
+        );
+        assert_eq!(
+            connection_path(path, "[::1]:18444".parse().unwrap(), started, 8),
+            Path::new("captures/peer-__1_18444-20240102T030405-8.pcapng")
+        );
+        assert_eq!(
+            connection_path(
+                Path::new("peer"),
+                "10.0.0.1:8333".parse().unwrap(),
+                started,
+                0
+            ),
+            Path::new("peer-10.0.0.1_8333-20240102T030405-0")
+        );
+    }
+
+    #[test]
+    fn rotated_files_are_numbered() {
+        assert_eq!(
+            rotated_path(Path::new("captures/peer.pcapng"), 2),
+            Path::new("captures/peer.2.pcapng")
+        );
+    }
+
+    #[test]
+    fn ipv4_checksum_matches_the_reference_example() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(ipv4_checksum(&header), 0xb861);
+    }
+
+    /// The blocks of a pcapng file as `(block type, body)`, checking the trailing lengths.
+    fn blocks(mut file: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let word = |bytes: &[u8]| u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let mut blocks = Vec::new();
+        while !file.is_empty() {
+            let total_length = word(&file[4..]) as usize;
+            assert_eq!(total_length % 4, 0);
+            assert_eq!(word(&file[total_length - 4..]) as usize, total_length);
+            blocks.push((word(file), file[8..total_length - 4].to_vec()));
+            file = &file[total_length..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn packets_carry_the_stream_with_consecutive_sequence_numbers() {
+        let path = std::env::temp_dir().join(format!("capture-{}.pcapng", std::process::id()));
+        let settings = CaptureSettings::new(path, u64::MAX);
+        let local = "127.0.0.1:50000".parse().unwrap();
+        let remote = "127.0.0.2:8333".parse().unwrap();
+        let mut writer = PcapWriter::new(&settings, local, remote).unwrap();
+        let (local_seq, remote_seq) = (writer.local_seq, writer.remote_seq);
+
+        let outbound = (0..2000).map(|byte| byte as u8).collect::<Vec<_>>();
+        writer.record(Direction::Outbound, &outbound).unwrap();
+        writer.record(Direction::Inbound, b"verack").unwrap();
+        writer.record(Direction::Outbound, b"ping").unwrap();
+        let file = fs::read(writer.path()).unwrap();
+        fs::remove_file(writer.path()).unwrap();
+
+        let blocks = blocks(&file);
+        let (block_type, body) = &blocks[0];
+        assert_eq!(*block_type, BLOCK_SECTION_HEADER);
+        assert_eq!(body[..4], BYTE_ORDER_MAGIC.to_le_bytes());
+        let (block_type, body) = &blocks[1];
+        assert_eq!(*block_type, BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(body[..2], LINKTYPE_RAW.to_le_bytes());
+
+        // (source port, sequence number, acknowledgment number, payload)
+        let segments = blocks[2..]
+            .iter()
+            .map(|(block_type, body)| {
+                assert_eq!(*block_type, BLOCK_ENHANCED_PACKET);
+                let captured = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+                assert_eq!(body.len(), 20 + captured + padding(captured));
+                let packet = &body[20..20 + captured];
+                assert_eq!(packet[0], 0x45);
+                assert_eq!(
+                    usize::from(u16::from_be_bytes([packet[2], packet[3]])),
+                    captured
+                );
+                assert_eq!(ipv4_checksum(&packet[..IPV4_HEADER_LEN]), 0);
+                let tcp = &packet[IPV4_HEADER_LEN..];
+                let number = |at: usize| u32::from_be_bytes(tcp[at..at + 4].try_into().unwrap());
+                (
+                    u16::from_be_bytes([tcp[0], tcp[1]]),
+                    number(4),
+                    number(8),
+                    tcp[TCP_HEADER_LEN..].to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let first_segment = outbound[..MAX_SEGMENT_SIZE].to_vec();
+        let second_segment = outbound[MAX_SEGMENT_SIZE..].to_vec();
+        let local_seq_after = local_seq.wrapping_add(outbound.len() as u32);
+        assert_eq!(
+            segments,
+            vec![
+                (50000, local_seq, remote_seq, first_segment),
+                (
+                    50000,
+                    local_seq.wrapping_add(MAX_SEGMENT_SIZE as u32),
+                    remote_seq,
+                    second_segment
+                ),
+                (8333, remote_seq, local_seq_after, b"verack".to_vec()),
+                (
+                    50000,
+                    local_seq_after,
+                    remote_seq.wrapping_add(6),
+                    b"ping".to_vec()
+                ),
+            ]
+        );
+    }
+}
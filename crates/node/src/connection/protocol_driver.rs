@@ -1,31 +1,169 @@
-use std::io::Write;
-use std::net::TcpStream;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
 
-use bitcoin::consensus::encode;
+use bitcoin::hashes::Hash;
 use bitcoin::network::constants;
 use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_compact_blocks::SendCmpct;
+use bitcoin::util::bip152::HeaderAndShortIds;
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{BlockHash, BlockHeader, Transaction, Txid};
 use futures::{pin_mut, Stream, StreamExt};
+use settings::{FeeFilterSettings, ReconciliationSettings, Settings};
+use tokio::sync::watch;
 
+use super::announced::Announced;
+use super::compact_blocks::{self, CompactBlockReconstructor, Reconstruction};
+use super::custom_message::MessageRegistry;
+use super::filter_server::{self, FilterRequestError};
+use super::frame::{self, FrameError};
 use super::handle::ToConnectionHandle;
+use super::message_handler::{
+    ConnectionState, HandlerContext, HandlerFactory, HandlerMessage, MessageHandlers,
+};
+use super::misbehavior::{self, DisconnectReason, Misbehavior, MisbehaviorTracker};
+use super::outbound_queue::{OutboundQueue, Priority};
+use super::reconciliation::Reconciliation;
+use super::stats::StatsRecorder;
+use super::tip_tracker::{HeadersOutcome, TipTracker};
 use crate::error::Error;
+use crate::filter_index::{FilterIndex, FilterIndexError};
+use crate::network_time::NetworkTime;
 use crate::FromConnectionHandle;
 
+/// Headers further ahead of the network-adjusted time are not accepted (bitcoin core's
+/// MAX_FUTURE_BLOCK_TIME).
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+/// A `headers` message this long means the peer has more headers for us.
+const MAX_HEADERS_RESULTS: usize = 2000;
+/// The first protocol version that knows `sendheaders`.
+const SEND_HEADERS_VERSION: u32 = 70012;
+/// The first protocol version that knows `feefilter`.
+const FEE_FILTER_VERSION: u32 = 70013;
+
 /// The protocol driver is responsible for handling and responding to the protocol
 /// messages (from the node) and actionable commands (from the user).
 #[derive(Debug)]
 pub(crate) struct ProtocolDriver {
-    pub(crate) write_stream: TcpStream,
+    /// Messages are not written directly, they are queued and written by the outbound writer.
+    pub(crate) outbound: Arc<OutboundQueue>,
     pub(crate) network: constants::Network,
+    /// The messages of the peer that passed the handlers and the decisions taken by the
+    /// driver, e.g. disconnects, go to the outside world here.
+    pub(crate) events: tokio::sync::broadcast::Sender<FromConnectionHandle>,
+    pub(crate) misbehavior: MisbehaviorTracker,
+    /// Blocks that we have asked for, any other block is unsolicited.
+    pub(crate) requested_blocks: HashSet<BlockHash>,
+    /// Transactions that we have asked for, their answers don't count as flooding.
+    pub(crate) requested_transactions: HashSet<Inventory>,
+    /// Transactions we announced, the peer may only ask for those.
+    pub(crate) announced: Announced,
+    pub(crate) stats: StatsRecorder,
+    pub(crate) ping_interval: Duration,
+    /// Handshake and ping state, kept by the built-in message handlers.
+    pub(crate) state: ConnectionState,
+    pub(crate) handlers: MessageHandlers,
+    /// Decodes the messages rust-bitcoin does not know.
+    pub(crate) message_registry: MessageRegistry,
+    pub(crate) reconstructor: CompactBlockReconstructor,
+    /// The last merkle block and the matched transactions the peer has not sent yet.
+    pub(crate) filtered_block: Option<(MerkleBlock, Vec<Txid>)>,
+    /// Answers filter requests of the peer, requests are ignored when missing.
+    pub(crate) filter_index: Option<FilterIndex>,
+    /// BIP330 transaction reconciliation, inactive unless both sides negotiated it.
+    pub(crate) reconciliation: Reconciliation,
+    /// Decodes the difference of a reconciliation on the blocking thread pool.
+    pub(crate) sketch_decoding: Option<tokio::task::JoinHandle<Option<Vec<u32>>>>,
+    pub(crate) fee_filter: FeeFilterSettings,
+    /// Our version asked the peer to relay transactions.
+    pub(crate) relay: bool,
+    /// Transactions below this fee rate (sat/kvB) are not announced to the peer.
+    pub(crate) peer_fee_filter: u64,
+    /// The best header the peer announced.
+    pub(crate) tips: TipTracker,
+    pub(crate) network_time: NetworkTime,
+    /// Reloaded settings, only the ping interval is applied to a live connection.
+    pub(crate) settings_updates: Option<watch::Receiver<Settings>>,
 }
 
 impl ProtocolDriver {
-    pub(crate) fn new(write_stream: TcpStream, network: constants::Network) -> Self {
+    pub(crate) fn new(
+        outbound: Arc<OutboundQueue>,
+        network: constants::Network,
+        events: tokio::sync::broadcast::Sender<FromConnectionHandle>,
+        misbehavior: MisbehaviorTracker,
+        stats: StatsRecorder,
+        ping_interval: Duration,
+        reconstructor: CompactBlockReconstructor,
+    ) -> Self {
         Self {
-            write_stream,
+            outbound,
             network,
+            events,
+            misbehavior,
+            requested_blocks: HashSet::new(),
+            requested_transactions: HashSet::new(),
+            announced: Announced::default(),
+            stats,
+            ping_interval,
+            state: ConnectionState::default(),
+            handlers: MessageHandlers::new(&[]),
+            message_registry: MessageRegistry::new(),
+            reconstructor,
+            filtered_block: None,
+            filter_index: None,
+            reconciliation: Reconciliation::new(ReconciliationSettings::default(), false),
+            sketch_decoding: None,
+            fee_filter: FeeFilterSettings::default(),
+            relay: false,
+            peer_fee_filter: 0,
+            tips: TipTracker::new(network),
+            network_time: NetworkTime::default(),
+            settings_updates: None,
         }
     }
 
+    pub(crate) fn with_filter_index(mut self, filter_index: Option<FilterIndex>) -> Self {
+        self.filter_index = filter_index;
+        self
+    }
+
+    /// Add custom message handlers after the built-in ones.
+    pub(crate) fn with_message_handlers(mut self, factories: &[HandlerFactory]) -> Self {
+        self.handlers = MessageHandlers::new(factories);
+        self
+    }
+
+    pub(crate) fn with_reconciliation(mut self, reconciliation: Reconciliation) -> Self {
+        self.reconciliation = reconciliation;
+        self
+    }
+
+    pub(crate) fn with_fee_filter(mut self, fee_filter: FeeFilterSettings) -> Self {
+        self.fee_filter = fee_filter;
+        self
+    }
+
+    pub(crate) fn with_network_time(mut self, network_time: NetworkTime) -> Self {
+        self.network_time = network_time;
+        self
+    }
+
+    pub(crate) fn with_message_registry(mut self, message_registry: MessageRegistry) -> Self {
+        self.message_registry = message_registry;
+        self
+    }
+
+    pub(crate) fn with_settings_updates(
+        mut self,
+        settings_updates: Option<watch::Receiver<Settings>>,
+    ) -> Self {
+        self.settings_updates = settings_updates;
+        self
+    }
+
     /// Handle incoming messages from the connected node and process commands from the user.
     /// This is the main loop for processing messages, this is the part that actually tries to implement the protocol.
     ///
@@ -37,10 +175,35 @@ impl ProtocolDriver {
     ) {
         pin_mut!(incoming_commands);
         pin_mut!(from_node);
+        let mut ping_timer = tokio::time::interval(self.ping_interval);
+        ping_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut settings_updates = self.settings_updates.take();
+        let mut reconciliation_timer =
+            tokio::time::interval(self.reconciliation.settings().request_interval());
+        reconciliation_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
             let success = tokio::select! {
                 msg = incoming_commands.next() => self.handle_incoming_commands(msg),
                 msg = from_node.next() => self.handle_messages_from_node(msg),
+                _ = ping_timer.tick() => self.send_ping(),
+                _ = reconciliation_timer.tick() => self.request_reconciliation(),
+                difference = decoded_sketch(&mut self.sketch_decoding) => {
+                    self.sketch_decoding = None;
+                    self.receive_decoded_sketch(difference)
+                }
+                ping_interval = next_ping_interval(&mut settings_updates) => {
+                    if ping_interval != self.ping_interval {
+                        tracing::info!("Pinging every {:?}", ping_interval);
+                        self.ping_interval = ping_interval;
+                        ping_timer = tokio::time::interval_at(
+                            tokio::time::Instant::now() + ping_interval,
+                            ping_interval,
+                        );
+                        ping_timer
+                            .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                    }
+                    Ok(())
+                }
                 else => {
                     tracing::warn!("Both streams are closed");
                     break;
@@ -62,22 +225,289 @@ impl ProtocolDriver {
         &mut self,
         msg: Option<FromConnectionHandle>,
     ) -> Result<(), Error> {
-        match msg {
-            Some(FromConnectionHandle::FromBitcoinNode(msg)) => {
-                match msg {
-                    NetworkMessage::Version(_version) => {
-                        // NOTE: we can add extra version validation here
-                        self.send_blocking(self.verack_message())
+        let Some(msg) = msg else {
+            return Err(Error::ActorUnavailable);
+        };
+        if let Err(reason) = self.score_message(&msg) {
+            return Err(self.disconnect(reason));
+        }
+
+        let message = match msg {
+            FromConnectionHandle::FromBitcoinNode(message) => message,
+            FromConnectionHandle::Unknown { command, payload } => {
+                NetworkMessage::Unknown { command, payload }
+            }
+            // Malformed messages are only reported to the user
+            event => {
+                let _ = self.events.send(event);
+                return Ok(());
+            }
+        };
+        let Some(message) = self.handle_inbound(message)? else {
+            return Ok(());
+        };
+        let message = match message {
+            NetworkMessage::Inv(inventory) => match self.consistent_inventory(inventory) {
+                Some(inventory) => NetworkMessage::Inv(inventory),
+                None => return Ok(()),
+            },
+            message => message,
+        };
+        self.publish(message.clone())?;
+
+        match message {
+            NetworkMessage::Verack => {
+                self.reconciliation.receive_verack(self.state.wtxid_relay());
+                self.negotiate_headers_announcements()?;
+                self.negotiate_compact_blocks()?;
+                self.send_fee_filter()
+            }
+            NetworkMessage::FeeFilter(fee_rate) => {
+                // Negative fee rates filter nothing
+                self.peer_fee_filter = u64::try_from(fee_rate).unwrap_or(0);
+                self.stats.record_peer_fee_filter(self.peer_fee_filter);
+                Ok(())
+            }
+            NetworkMessage::Headers(headers) => self.receive_headers(&headers),
+            NetworkMessage::Inv(inventory) => self.receive_block_announcements(&inventory),
+            NetworkMessage::MerkleBlock(merkle_block) => {
+                self.receive_merkle_block(merkle_block);
+                Ok(())
+            }
+            NetworkMessage::Tx(transaction) => {
+                self.receive_filtered_transaction(&transaction);
+                self.reconciliation.forget(&transaction.wtxid());
+                self.reconstructor.mempool().insert(transaction);
+                Ok(())
+            }
+            NetworkMessage::CmpctBlock(message) if self.reconstructor.settings().enabled() => {
+                self.receive_compact_block(message.compact_block)
+            }
+            NetworkMessage::BlockTxn(message) => {
+                match self
+                    .reconstructor
+                    .on_block_transactions(message.transactions)
+                {
+                    Some(reconstruction) => self.handle_reconstruction(reconstruction),
+                    None => Ok(()),
+                }
+            }
+            NetworkMessage::GetData(inventory) => self.serve_transactions(&inventory),
+            NetworkMessage::Unknown { command, payload } => {
+                let handshake_complete = self.state.verack_received;
+                match self
+                    .reconciliation
+                    .receive(&command, &payload, handshake_complete)
+                {
+                    Some(Ok(replies)) => {
+                        if let Some(sketch) = self.reconciliation.take_sketch() {
+                            self.sketch_decoding =
+                                Some(tokio::task::spawn_blocking(move || sketch.decode()));
+                        }
+                        replies
+                            .into_iter()
+                            .try_for_each(|reply| self.send_outbound(reply))
                     }
-                    // NOTE: add handling for other messages here
-                    _ => {
-                        // not acting on other messages for now
-                        Ok(())
+                    Some(Err(err)) => {
+                        tracing::warn!("Failed to decode {} message: {}", command, err);
+                        self.misbehavior
+                            .record(Misbehavior::InvalidPayload)
+                            .map_err(|reason| self.disconnect(reason))
                     }
+                    None => Ok(()),
                 }
             }
-            None => Err(Error::ActorUnavailable),
+            NetworkMessage::GetCFilters(request) => {
+                self.serve_filters(|index| filter_server::filters(index, &request))
+            }
+            NetworkMessage::GetCFHeaders(request) => self.serve_filters(|index| {
+                filter_server::filter_headers(index, &request).map(|response| vec![response])
+            }),
+            NetworkMessage::GetCFCheckpt(request) => self.serve_filters(|index| {
+                filter_server::checkpoints(index, &request).map(|response| vec![response])
+            }),
+            // NOTE: add handling for other messages here
+            _ => {
+                // not acting on other messages for now
+                Ok(())
+            }
+        }
+    }
+
+    /// Let the outside world know about a message from the peer. Messages with a registered
+    /// command are decoded here, after the handlers have seen them.
+    fn publish(&mut self, message: NetworkMessage) -> Result<(), Error> {
+        let event = match message {
+            NetworkMessage::Unknown { command, payload } => {
+                match self.message_registry.decode(&command, &payload) {
+                    Some(Ok(message)) => FromConnectionHandle::Custom(message),
+                    Some(Err(err)) => {
+                        let error = FrameError::InvalidPayload(err.to_string());
+                        tracing::warn!("Failed to decode {} message: {:?}", command, error);
+                        self.stats.record_decode_error();
+                        if let Err(reason) = self.misbehavior.record(Misbehavior::from(&error)) {
+                            return Err(self.disconnect(reason));
+                        }
+                        FromConnectionHandle::Malformed {
+                            command,
+                            error,
+                            decode_errors: self.stats.snapshot().decode_errors,
+                        }
+                    }
+                    None => FromConnectionHandle::Unknown { command, payload },
+                }
+            }
+            message => FromConnectionHandle::FromBitcoinNode(message),
+        };
+        let _ = self.events.send(event);
+        Ok(())
+    }
+
+    /// Drop the transactions announced by the identifier that was not negotiated, so peers
+    /// announce by wtxid exactly when `wtxidrelay` was exchanged (BIP339). `None` when nothing
+    /// is left of the `inv`.
+    fn consistent_inventory(&self, inventory: Vec<Inventory>) -> Option<Vec<Inventory>> {
+        let wtxid_relay = self.state.wtxid_relay();
+        let announced = inventory.len();
+        let inventory: Vec<Inventory> = inventory
+            .into_iter()
+            .filter(|item| match item {
+                Inventory::WTx(_) => wtxid_relay,
+                Inventory::Transaction(_) | Inventory::WitnessTransaction(_) => !wtxid_relay,
+                _ => true,
+            })
+            .collect();
+        if inventory.len() < announced {
+            tracing::debug!(
+                "Ignoring {} transactions announced by the wrong identifier",
+                announced - inventory.len()
+            );
+        }
+        (announced == 0 || !inventory.is_empty()).then_some(inventory)
+    }
+
+    /// Pass a message from the peer through the message handlers and send their replies.
+    /// `None` when a handler consumed the message.
+    fn handle_inbound(&mut self, message: NetworkMessage) -> Result<Option<NetworkMessage>, Error> {
+        let mut outbox = Vec::new();
+        let mut context =
+            HandlerContext::new(self.network, &self.stats, &mut self.state, &mut outbox);
+        let message = self.handlers.inbound(&mut context, message);
+        self.send_handled(outbox)?;
+        Ok(message)
+    }
+
+    /// Pass a message through the message handlers and queue what is left of it.
+    fn send_outbound(&mut self, message: NetworkMessage) -> Result<(), Error> {
+        self.send_handled(vec![HandlerMessage {
+            sender: None,
+            message,
+        }])
+    }
+
+    /// Pass the messages through the outbound handlers and queue what is left of them. The
+    /// messages the handlers send on the way follow right after the message they handled.
+    fn send_handled(&mut self, messages: Vec<HandlerMessage>) -> Result<(), Error> {
+        let mut pending: Vec<HandlerMessage> = messages.into_iter().rev().collect();
+        while let Some(message) = pending.pop() {
+            let mut outbox = Vec::new();
+            let mut context =
+                HandlerContext::new(self.network, &self.stats, &mut self.state, &mut outbox);
+            if let Some(message) = self.handlers.outbound(&mut context, message) {
+                self.send(self.raw_message(message))?;
+            }
+            pending.extend(outbox.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    /// Check the message for protocol violations and update the misbehavior score of the peer.
+    /// Answers to our requests don't count towards flooding.
+    fn score_message(&mut self, msg: &FromConnectionHandle) -> Result<(), DisconnectReason> {
+        let solicited = match msg {
+            FromConnectionHandle::FromBitcoinNode(NetworkMessage::Block(block)) => {
+                let requested = self.requested_blocks.remove(&block.block_hash());
+                if !requested {
+                    self.misbehavior.record(Misbehavior::UnsolicitedBlock)?;
+                }
+                requested
+            }
+            FromConnectionHandle::FromBitcoinNode(NetworkMessage::Tx(transaction)) => {
+                self.receive_requested_transaction(transaction)
+            }
+            FromConnectionHandle::FromBitcoinNode(NetworkMessage::NotFound(inventory)) => {
+                let mut requested = false;
+                for item in inventory {
+                    requested |= match requested_block(item) {
+                        Some(block_hash) => self.requested_blocks.remove(&block_hash),
+                        None => self.requested_transactions.remove(item),
+                    };
+                }
+                requested
+            }
+            FromConnectionHandle::FromBitcoinNode(
+                NetworkMessage::Headers(_)
+                | NetworkMessage::BlockTxn(_)
+                | NetworkMessage::MerkleBlock(_)
+                | NetworkMessage::CFilter(_)
+                | NetworkMessage::CFHeaders(_)
+                | NetworkMessage::CFCheckpt(_),
+            ) => true,
+            _ => false,
+        };
+        if !solicited {
+            self.misbehavior.record_message()?;
         }
+        match msg {
+            FromConnectionHandle::FromBitcoinNode(NetworkMessage::Headers(headers))
+                if !misbehavior::headers_are_valid(headers, self.network) =>
+            {
+                self.misbehavior.record(Misbehavior::InvalidHeaders)?;
+            }
+            FromConnectionHandle::FromBitcoinNode(NetworkMessage::CmpctBlock(message))
+                if !compact_blocks::has_valid_size(&message.compact_block) =>
+            {
+                self.misbehavior.record(Misbehavior::InvalidPayload)?;
+            }
+            FromConnectionHandle::FromBitcoinNode(NetworkMessage::MerkleBlock(merkle_block))
+                if merkle_block
+                    .extract_matches(&mut Vec::new(), &mut Vec::new())
+                    .is_err() =>
+            {
+                self.misbehavior.record(Misbehavior::InvalidMerkleBlock)?;
+            }
+            FromConnectionHandle::Malformed { error, .. } => {
+                self.misbehavior.record(Misbehavior::from(error))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether we asked for the transaction, by `getdata` or as a match of the last merkle
+    /// block.
+    fn receive_requested_transaction(&mut self, transaction: &Transaction) -> bool {
+        let txid = transaction.txid();
+        let requested = [
+            Inventory::Transaction(txid),
+            Inventory::WitnessTransaction(txid),
+            Inventory::WTx(transaction.wtxid()),
+        ]
+        .iter()
+        .fold(false, |requested, item| {
+            self.requested_transactions.remove(item) | requested
+        });
+        requested || matches!(&self.filtered_block, Some((_, matches)) if matches.contains(&txid))
+    }
+
+    /// Ban the peer and let the outside world know why the connection is going away.
+    fn disconnect(&mut self, reason: DisconnectReason) -> Error {
+        tracing::warn!("Disconnecting from the peer: {:?}", reason);
+        self.misbehavior.ban(&reason);
+        let _ = self
+            .events
+            .send(FromConnectionHandle::Disconnected(reason.clone()));
+        Error::Disconnected(reason)
     }
 
     /// Process commands from the user.
@@ -91,43 +521,352 @@ impl ProtocolDriver {
         msg: Option<ToConnectionHandle>,
     ) -> Result<(), Error> {
         match msg {
-            Some(msg) => {
-                match msg {
-                    ToConnectionHandle::ToBitcoinNode(msg) => self.send_blocking(msg),
-                    ToConnectionHandle::InitHandshake { version } => {
-                        // Send version
-                        let message = self.version_message(version);
-                        self.send_blocking(message)
-                    }
-                }
+            Some(ToConnectionHandle::ToBitcoinNode(msg)) => self.send_outbound(msg.payload),
+            // The handshake handler sends the verack once the peer sent its version
+            Some(ToConnectionHandle::InitHandshake { version }) => {
+                self.relay = version.relay;
+                let negotiation = self.reconciliation.negotiation(&version);
+                self.state.negotiation.extend(negotiation);
+                self.send_outbound(NetworkMessage::Version(version))
+            }
+            Some(ToConnectionHandle::AnnounceTransaction { transaction, fee }) => {
+                self.announce_transaction(transaction, fee)
             }
             None => Err(Error::ActorUnavailable),
         }
     }
 
-    pub fn send_blocking(&mut self, msg: RawNetworkMessage) -> Result<(), Error> {
-        tokio::task::block_in_place(|| {
-            let msg = encode::serialize(&msg);
-            self.write_stream.write_all(msg.as_slice())
-        })
-        .map_err(|_| Error::ActorSendError)
+    /// Queue the message for the outbound writer. Control messages jump ahead of bulk data.
+    pub fn send(&mut self, msg: RawNetworkMessage) -> Result<(), Error> {
+        if self.outbound.is_closed() {
+            return Err(Error::ActorSendError);
+        }
+        match &msg.payload {
+            NetworkMessage::GetData(inventory) => {
+                self.requested_blocks
+                    .extend(inventory.iter().filter_map(requested_block));
+                self.requested_transactions
+                    .extend(inventory.iter().filter(|item| is_transaction(item)));
+            }
+            NetworkMessage::Inv(inventory) => self.announced.record(inventory),
+            _ => {}
+        }
+        let priority = Priority::of(&msg.payload);
+        self.outbound.push(priority, frame::serialize(&msg));
+        Ok(())
     }
 
-    pub fn version_message(
-        &self,
-        version: bitcoin::network::message_network::VersionMessage,
-    ) -> RawNetworkMessage {
-        RawNetworkMessage {
-            magic: self.network.magic(),
-            payload: NetworkMessage::Version(version),
+    /// The peer follows a merkle block with the matched transactions it did not send before.
+    /// Matches that are already in the mempool are emitted right away, the peer won't send them
+    /// again.
+    fn receive_merkle_block(&mut self, merkle_block: MerkleBlock) {
+        let mut matches = Vec::new();
+        if merkle_block
+            .extract_matches(&mut matches, &mut Vec::new())
+            .is_err()
+        {
+            // Already scored, see `score_message`
+            return;
+        }
+        let mempool = self.reconstructor.mempool();
+        matches.retain(|txid| match mempool.get_by_txid(txid) {
+            Some(transaction) => {
+                let _ = self.events.send(FromConnectionHandle::FilteredTransaction {
+                    transaction,
+                    merkle_block: merkle_block.clone(),
+                });
+                false
+            }
+            None => true,
+        });
+        self.filtered_block = (!matches.is_empty()).then_some((merkle_block, matches));
+    }
+
+    fn receive_filtered_transaction(&mut self, transaction: &Transaction) {
+        let Some((merkle_block, matches)) = &mut self.filtered_block else {
+            return;
+        };
+        let txid = transaction.txid();
+        let Some(position) = matches.iter().position(|matched| *matched == txid) else {
+            return;
+        };
+        matches.remove(position);
+        let _ = self.events.send(FromConnectionHandle::FilteredTransaction {
+            transaction: transaction.clone(),
+            merkle_block: merkle_block.clone(),
+        });
+        if matches.is_empty() {
+            self.filtered_block = None;
+        }
+    }
+
+    /// Ask the peer to announce new blocks with their headers (BIP130) instead of `inv`.
+    fn negotiate_headers_announcements(&mut self) -> Result<(), Error> {
+        let Some(version) = &self.state.peer_version else {
+            return Ok(());
+        };
+        if version.version < SEND_HEADERS_VERSION {
+            return Ok(());
+        }
+        self.send_outbound(NetworkMessage::SendHeaders)
+    }
+
+    /// Follow the best header of the peer and let the outside world know about new tips.
+    /// Headers that don't connect are requested from the last ones we know, and a full batch
+    /// is followed by the next one. Headers from too far in the future are ignored.
+    fn receive_headers(&mut self, headers: &[BlockHeader]) -> Result<(), Error> {
+        if !misbehavior::headers_are_valid(headers, self.network) {
+            // Already scored, see `score_message`
+            return Ok(());
+        }
+        let max_time = self.network_time.adjusted_now().timestamp() + MAX_FUTURE_BLOCK_TIME;
+        if headers
+            .iter()
+            .any(|header| i64::from(header.time) > max_time)
+        {
+            tracing::debug!("Ignoring headers from too far in the future");
+            return Ok(());
+        }
+        match self.tips.receive_headers(headers) {
+            HeadersOutcome::Connected(new_tip) => {
+                if let Some((block_hash, height)) = new_tip {
+                    tracing::debug!("New tip {} at height {}", block_hash, height);
+                    self.stats.record_best_header(block_hash, height);
+                    let _ = self
+                        .events
+                        .send(FromConnectionHandle::NewTip { block_hash, height });
+                }
+                if headers.len() == MAX_HEADERS_RESULTS {
+                    return self.request_headers(BlockHash::all_zeros());
+                }
+                Ok(())
+            }
+            HeadersOutcome::Disconnected => self.request_headers(BlockHash::all_zeros()),
+        }
+    }
+
+    /// Peers that don't announce headers announce blocks with `inv`, ask for the headers of
+    /// the blocks we don't know.
+    fn receive_block_announcements(&mut self, inventory: &[Inventory]) -> Result<(), Error> {
+        let last_unknown = inventory
+            .iter()
+            .filter_map(|item| match item {
+                Inventory::Block(hash) | Inventory::WitnessBlock(hash) => Some(*hash),
+                _ => None,
+            })
+            .rfind(|hash| !self.tips.is_known(hash));
+        match last_unknown {
+            Some(block_hash) => self.request_headers(block_hash),
+            None => Ok(()),
+        }
+    }
+
+    /// Ask for the headers after the ones we know, up to `stop_hash`. Before the first headers
+    /// connected the locator only holds the genesis block.
+    fn request_headers(&mut self, stop_hash: BlockHash) -> Result<(), Error> {
+        let locator = self.tips.locator();
+        self.send_outbound(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+            locator, stop_hash,
+        )))
+    }
+
+    /// Announce that we want to receive blocks as BIP152 compact blocks. Bitcoin core expects
+    /// `sendcmpct` after the handshake is complete.
+    fn negotiate_compact_blocks(&mut self) -> Result<(), Error> {
+        let settings = self.reconstructor.settings();
+        if !settings.enabled() {
+            return Ok(());
+        }
+        self.send_outbound(NetworkMessage::SendCmpct(SendCmpct {
+            send_compact: settings.high_bandwidth(),
+            version: compact_blocks::COMPACT_BLOCK_VERSION,
+        }))
+    }
+
+    fn receive_compact_block(&mut self, compact_block: HeaderAndShortIds) -> Result<(), Error> {
+        let reconstruction = self.reconstructor.on_compact_block(compact_block);
+        self.handle_reconstruction(reconstruction)
+    }
+
+    fn handle_reconstruction(&mut self, reconstruction: Reconstruction) -> Result<(), Error> {
+        match reconstruction {
+            Reconstruction::Complete(block) => {
+                self.requested_blocks.remove(&block.block_hash());
+                let _ = self
+                    .events
+                    .send(FromConnectionHandle::ReconstructedBlock(block));
+                Ok(())
+            }
+            Reconstruction::Incomplete(request) => {
+                self.send_outbound(NetworkMessage::GetBlockTxn(request))
+            }
+            Reconstruction::Failed(block_hash) => {
+                // Fall back to downloading the full block
+                self.send_outbound(NetworkMessage::GetData(vec![Inventory::WitnessBlock(
+                    block_hash,
+                )]))
+            }
+            Reconstruction::Invalid(block_hash) => {
+                // Already scored, see `score_message`
+                tracing::debug!("Ignoring invalid compact block {}", block_hash);
+                Ok(())
+            }
+        }
+    }
+
+    /// Answer a BIP157 request. Computing filters is CPU and I/O heavy, so it is moved off the
+    /// async worker. Invalid requests are ignored.
+    fn serve_filters(
+        &mut self,
+        respond: impl FnOnce(&FilterIndex) -> Result<Vec<NetworkMessage>, FilterRequestError>,
+    ) -> Result<(), Error> {
+        let Some(index) = &self.filter_index else {
+            return Ok(());
+        };
+        match tokio::task::block_in_place(|| respond(index)) {
+            Ok(responses) => responses
+                .into_iter()
+                .try_for_each(|response| self.send_outbound(response)),
+            Err(FilterRequestError::Index(FilterIndexError::NotIndexed(height))) => {
+                tracing::debug!("Ignoring filter request, height {} is not indexed", height);
+                index.spawn_sync();
+                Ok(())
+            }
+            Err(err) => {
+                tracing::warn!("Ignoring filter request: {}", err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Ask the peer not to announce transactions below our minimum fee rate (BIP133). Peers
+    /// that don't relay transactions to us don't need to know.
+    fn send_fee_filter(&mut self) -> Result<(), Error> {
+        let supported = match &self.state.peer_version {
+            Some(version) => version.version >= FEE_FILTER_VERSION,
+            None => false,
+        };
+        if !self.fee_filter.enabled() || !self.relay || !supported {
+            return Ok(());
+        }
+        let fee_rate = i64::try_from(self.fee_filter.min_fee_rate()).unwrap_or(i64::MAX);
+        self.send_outbound(NetworkMessage::FeeFilter(fee_rate))
+    }
+
+    /// Announce a transaction to the peer and keep it in the mempool to answer the `getdata`.
+    /// Peers that reconcile hear about it in the next reconciliation, the others right away.
+    /// Transactions with a known fee below the fee filter of the peer are not announced.
+    fn announce_transaction(
+        &mut self,
+        transaction: Transaction,
+        fee: Option<u64>,
+    ) -> Result<(), Error> {
+        if let Some(fee) = fee {
+            let fee_rate = fee_rate(&transaction, fee);
+            if fee_rate < self.peer_fee_filter {
+                tracing::debug!(
+                    "Not announcing {}, its fee rate {} is below the fee filter of the peer",
+                    transaction.txid(),
+                    fee_rate
+                );
+                self.reconstructor.mempool().insert(transaction);
+                return Ok(());
+            }
+        }
+        let txid = transaction.txid();
+        let wtxid = transaction.wtxid();
+        self.reconstructor.mempool().insert(transaction);
+        if self.reconciliation.announce(wtxid) {
+            return Ok(());
+        }
+        let inventory = match self.state.wtxid_relay() {
+            true => Inventory::WTx(wtxid),
+            false => Inventory::Transaction(txid),
+        };
+        self.send_outbound(NetworkMessage::Inv(vec![inventory]))
+    }
+
+    fn receive_decoded_sketch(&mut self, difference: Option<Vec<u32>>) -> Result<(), Error> {
+        self.reconciliation
+            .receive_decoded(difference)
+            .into_iter()
+            .try_for_each(|reply| self.send_outbound(reply))
+    }
+
+    /// Flood the set of a round the peer did not answer in time, then start the next one.
+    fn request_reconciliation(&mut self) -> Result<(), Error> {
+        let now = std::time::Instant::now();
+        let mut messages = self.reconciliation.expire(now);
+        messages.extend(self.reconciliation.request(now));
+        messages
+            .into_iter()
+            .try_for_each(|message| self.send_outbound(message))
+    }
+
+    /// Send the requested transactions that we announced to the peer and are still in the
+    /// mempool, `notfound` for the rest.
+    fn serve_transactions(&mut self, inventory: &[Inventory]) -> Result<(), Error> {
+        let mempool = self.reconstructor.mempool().clone();
+        let mut not_found = Vec::new();
+        for item in inventory {
+            if !is_transaction(item) {
+                // We don't serve blocks
+                continue;
+            }
+            let transaction = match item {
+                _ if !self.announced.contains(item) => None,
+                Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+                    mempool.get_by_txid(txid)
+                }
+                Inventory::WTx(wtxid) => mempool.get(wtxid),
+                _ => None,
+            };
+            match transaction {
+                Some(transaction) => self.send_outbound(NetworkMessage::Tx(transaction))?,
+                None => not_found.push(*item),
+            }
+        }
+        if not_found.is_empty() {
+            return Ok(());
         }
+        self.send_outbound(NetworkMessage::NotFound(not_found))
     }
 
-    pub(crate) fn verack_message(&self) -> RawNetworkMessage {
+    /// Ping the node to measure the latency, one ping at a time. The ping handler matches the
+    /// pong.
+    fn send_ping(&mut self) -> Result<(), Error> {
+        if !self.state.verack_received || self.state.pending_ping.is_some() {
+            return Ok(());
+        }
+        self.send_outbound(NetworkMessage::Ping(rand::random()))
+    }
+
+    fn raw_message(&self, payload: NetworkMessage) -> RawNetworkMessage {
         RawNetworkMessage {
             magic: self.network.magic(),
-            payload: NetworkMessage::Verack,
+            payload,
+        }
+    }
+}
+
+/// Fee rate in satoshis per 1000 virtual bytes.
+fn fee_rate(transaction: &Transaction, fee: u64) -> u64 {
+    fee.saturating_mul(1000) / (transaction.vsize() as u64).max(1)
+}
+
+fn is_transaction(inventory: &Inventory) -> bool {
+    matches!(
+        inventory,
+        Inventory::Transaction(_) | Inventory::WitnessTransaction(_) | Inventory::WTx(_)
+    )
+}
+
+fn requested_block(inventory: &Inventory) -> Option<BlockHash> {
+    match inventory {
+        Inventory::Block(hash) | Inventory::WitnessBlock(hash) | Inventory::CompactBlock(hash) => {
+            Some(*hash)
         }
+        _ => None,
     }
 }
 
@@ -142,13 +881,144 @@ pub(crate) fn mpsc_to_stream<T>(
     }
 }
 
-/// Utility function to help to convert tokio::broadcast channels to a stream.
-pub(crate) fn broadcast_to_stream<T: Clone>(
-    mut receiver: tokio::sync::broadcast::Receiver<T>,
-) -> impl Stream<Item = T> {
-    async_stream::stream! {
-        while let Ok(msg) = receiver.recv().await {
-            yield msg;
+/// The difference decoded from the sketch of the peer, `None` when it could not be decoded.
+async fn decoded_sketch(
+    decoding: &mut Option<tokio::task::JoinHandle<Option<Vec<u32>>>>,
+) -> Option<Vec<u32>> {
+    match decoding {
+        Some(decoding) => decoding.await.ok().flatten(),
+        None => std::future::pending().await,
+    }
+}
+
+/// The ping interval of the next reloaded settings, never resolves without updates.
+async fn next_ping_interval(updates: &mut Option<watch::Receiver<Settings>>) -> Duration {
+    let Some(receiver) = updates else {
+        return std::future::pending().await;
+    };
+    if receiver.changed().await.is_err() {
+        // Nobody publishes settings anymore
+        *updates = None;
+        return std::future::pending().await;
+    }
+    receiver.borrow().ping_interval()
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, TxIn, Witness};
+    use settings::{CompactBlockSettings, MisbehaviorSettings};
+
+    use super::*;
+    use crate::ban_list::BanList;
+    use crate::mempool::Mempool;
+
+    fn transaction(n: u8) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([n; 32]), 0),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: Vec::new(),
+        }
+    }
+
+    fn driver(
+        mempool: Mempool,
+    ) -> (
+        ProtocolDriver,
+        tokio::sync::broadcast::Receiver<FromConnectionHandle>,
+    ) {
+        let peer = "127.0.0.1:8333".parse().unwrap();
+        let (events, receiver) = tokio::sync::broadcast::channel(16);
+        let stats = StatsRecorder::new(peer);
+        let driver = ProtocolDriver::new(
+            Arc::new(OutboundQueue::new(1_000_000)),
+            constants::Network::Regtest,
+            events,
+            MisbehaviorTracker::new(
+                peer.ip(),
+                BanList::in_memory(),
+                &MisbehaviorSettings::default(),
+            ),
+            stats.clone(),
+            Duration::from_secs(120),
+            CompactBlockReconstructor::new(CompactBlockSettings::default(), mempool, stats),
+        );
+        (driver, receiver)
+    }
+
+    fn filtered_transaction(
+        receiver: &mut tokio::sync::broadcast::Receiver<FromConnectionHandle>,
+    ) -> Option<Txid> {
+        match receiver.try_recv() {
+            Ok(FromConnectionHandle::FilteredTransaction { transaction, .. }) => {
+                Some(transaction.txid())
+            }
+            _ => None,
         }
     }
+
+    #[test]
+    fn matched_transactions_in_the_mempool_are_emitted_with_the_merkle_block() {
+        let mut block = genesis_block(constants::Network::Regtest);
+        block.txdata.extend([transaction(1), transaction(2)]);
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let (relayed, sent) = (transaction(1).txid(), transaction(2).txid());
+        let merkle_block =
+            MerkleBlock::from_block_with_predicate(&block, |txid| [relayed, sent].contains(txid));
+
+        let mempool = Mempool::new();
+        mempool.insert(transaction(1));
+        let (mut driver, mut receiver) = driver(mempool);
+        driver.receive_merkle_block(merkle_block);
+        assert_eq!(filtered_transaction(&mut receiver), Some(relayed));
+        assert_eq!(filtered_transaction(&mut receiver), None);
+
+        // The peer sends the one it did not relay before
+        driver.receive_filtered_transaction(&transaction(2));
+        assert_eq!(filtered_transaction(&mut receiver), Some(sent));
+        assert!(driver.filtered_block.is_none());
+    }
+
+    #[test]
+    fn answers_to_our_requests_are_not_counted_as_flooding() {
+        let (mut driver, _receiver) = driver(Mempool::new());
+        let message = |payload| FromConnectionHandle::FromBitcoinNode(payload);
+        for nonce in 0..MisbehaviorSettings::default().max_messages_per_second() {
+            assert!(driver
+                .score_message(&message(NetworkMessage::Ping(u64::from(nonce))))
+                .is_ok());
+        }
+
+        let requested = [
+            Inventory::WTx(transaction(1).wtxid()),
+            Inventory::WTx(transaction(2).wtxid()),
+        ];
+        let get_data = RawNetworkMessage {
+            magic: constants::Network::Regtest.magic(),
+            payload: NetworkMessage::GetData(requested.to_vec()),
+        };
+        driver.send(get_data).unwrap();
+        let answers = [
+            NetworkMessage::Tx(transaction(1)),
+            NetworkMessage::Headers(Vec::new()),
+            NetworkMessage::NotFound(vec![requested[1]]),
+        ];
+        for answer in answers {
+            assert!(driver.score_message(&message(answer)).is_ok());
+        }
+        assert_eq!(driver.misbehavior.score(), 0);
+
+        // The transaction was answered, sending it again is unsolicited
+        assert!(driver
+            .score_message(&message(NetworkMessage::Tx(transaction(1))))
+            .is_ok());
+        assert_eq!(driver.misbehavior.score(), Misbehavior::Flooding.score());
+    }
 }
@@ -0,0 +1,568 @@
+//! BIP330 (Erlay) transaction reconciliation.
+//!
+//! Instead of flooding every transaction to the peer, both sides collect the transactions the
+//! other side has not heard about from them. The side that made the connection regularly asks
+//! for a sketch of the peer's set, the difference of the sets decoded from the sketches is
+//! announced in both directions. Failed reconciliations fall back to flooding the sets.
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bitcoin::consensus::{encode, Decodable, Encodable};
+use bitcoin::hashes::{sha256, siphash24, Hash, HashEngine};
+use bitcoin::network::message::{CommandString, NetworkMessage};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::{VarInt, Wtxid};
+use settings::ReconciliationSettings;
+
+use super::custom_message::CustomMessage;
+use crate::minisketch::Minisketch;
+
+/// The only version of the protocol so far.
+const RECONCILIATION_VERSION: u32 = 1;
+/// Precision of the `q` coefficient in `reqrecon`.
+const Q_PRECISION: u16 = (2 << 14) - 1;
+/// Coefficient of the expected difference of the sets, same as bitcoin core.
+const Q: f64 = 0.25;
+/// Sketches larger than this are not computed, the reconciliation fails instead. Larger
+/// sketches of the peer are rejected, decoding is quadratic in the capacity and the peer
+/// chooses it.
+const MAX_SKETCH_CAPACITY: usize = 1 << 9;
+/// Transactions beyond this many in the set are flooded, same as bitcoin core.
+const MAX_SET_SIZE: usize = 3000;
+/// A round the peer does not answer within this time fails and the set is flooded.
+const ROUND_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum number of entries in an `inv` message.
+const MAX_INV_SIZE: usize = 50_000;
+
+/// `sendtxrcncl`: the peer supports reconciliation, sent between `version` and `verack`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct SendTxRcncl {
+    pub(crate) version: u32,
+    pub(crate) salt: u64,
+}
+
+/// `reqrecon`: ask the peer for a sketch of its set.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct ReqRecon {
+    pub(crate) set_size: u16,
+    /// The coefficient of the expected difference, scaled by [`Q_PRECISION`].
+    pub(crate) q: u16,
+}
+
+/// `sketch`: the minisketch of the set of the peer, empty when it refused to compute one.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Sketch {
+    pub(crate) skdata: Vec<u8>,
+}
+
+/// `reconcildiff`: the outcome of the reconciliation and the short ids we are missing.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct ReconcilDiff {
+    pub(crate) success: bool,
+    pub(crate) ask_shortids: Vec<u32>,
+}
+
+impl CustomMessage for SendTxRcncl {
+    const COMMAND: &'static str = "sendtxrcncl";
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = encode::serialize(&self.version);
+        payload.extend(encode::serialize(&self.salt));
+        payload
+    }
+
+    fn decode(mut payload: &[u8]) -> Result<Self, encode::Error> {
+        Ok(Self {
+            version: Decodable::consensus_decode(&mut payload)?,
+            salt: Decodable::consensus_decode(&mut payload)?,
+        })
+    }
+}
+
+impl CustomMessage for ReqRecon {
+    const COMMAND: &'static str = "reqrecon";
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = encode::serialize(&self.set_size);
+        payload.extend(encode::serialize(&self.q));
+        payload
+    }
+
+    fn decode(mut payload: &[u8]) -> Result<Self, encode::Error> {
+        Ok(Self {
+            set_size: Decodable::consensus_decode(&mut payload)?,
+            q: Decodable::consensus_decode(&mut payload)?,
+        })
+    }
+}
+
+impl CustomMessage for Sketch {
+    const COMMAND: &'static str = "sketch";
+
+    fn encode(&self) -> Vec<u8> {
+        encode::serialize(&self.skdata)
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, encode::Error> {
+        let skdata: Vec<u8> = encode::deserialize(payload)?;
+        if skdata.len() > MAX_SKETCH_CAPACITY * 4 {
+            return Err(encode::Error::ParseFailed(
+                "sketch exceeds the maximum capacity",
+            ));
+        }
+        Ok(Self { skdata })
+    }
+}
+
+impl CustomMessage for ReconcilDiff {
+    const COMMAND: &'static str = "reconcildiff";
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![u8::from(self.success)];
+        VarInt(self.ask_shortids.len() as u64)
+            .consensus_encode(&mut payload)
+            .expect("Writing to a vec can't fail");
+        for short_id in &self.ask_shortids {
+            payload.extend(short_id.to_le_bytes());
+        }
+        payload
+    }
+
+    fn decode(mut payload: &[u8]) -> Result<Self, encode::Error> {
+        let success = u8::consensus_decode(&mut payload)? != 0;
+        let count = VarInt::consensus_decode(&mut payload)?.0;
+        // Don't trust the count to allocate
+        if count > (payload.len() / 4) as u64 {
+            return Err(encode::Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let ask_shortids = (0..count)
+            .map(|_| u32::consensus_decode(&mut payload))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            success,
+            ask_shortids,
+        })
+    }
+}
+
+/// Where the reconciliation with the peer stands.
+#[derive(Debug)]
+enum Round {
+    Idle,
+    /// We asked for a sketch at this time.
+    Requested(Instant),
+    /// The difference of our set and the sketch of the peer is being decoded.
+    Decoding(HashMap<u32, Wtxid>),
+    /// We sent a sketch of these transactions at this time and wait for the difference.
+    Responded(HashMap<u32, Wtxid>, Instant),
+}
+
+/// The reconciliation state of a connection.
+#[derive(Debug)]
+pub(crate) struct Reconciliation {
+    settings: ReconciliationSettings,
+    /// The peer connected to us, so it asks for the reconciliations.
+    inbound: bool,
+    salt: u64,
+    /// We sent `sendtxrcncl`.
+    negotiated: bool,
+    peer_salt: Option<u64>,
+    /// The SipHash keys of the short ids, known once both sides negotiated reconciliation.
+    keys: Option<(u64, u64)>,
+    /// Transactions to announce to the peer in the next reconciliation, by short id.
+    set: HashMap<u32, Wtxid>,
+    round: Round,
+    /// The merged sketch to decode, see [`Self::take_sketch`].
+    sketch: Option<Minisketch>,
+}
+
+impl Reconciliation {
+    pub(crate) fn new(settings: ReconciliationSettings, inbound: bool) -> Self {
+        Self {
+            settings,
+            inbound,
+            salt: rand::random(),
+            negotiated: false,
+            peer_salt: None,
+            keys: None,
+            set: HashMap::new(),
+            round: Round::Idle,
+            sketch: None,
+        }
+    }
+
+    pub(crate) fn settings(&self) -> &ReconciliationSettings {
+        &self.settings
+    }
+
+    /// The messages that go out between our `version` and `verack`. Peers that don't relay
+    /// transactions don't reconcile. The handshake takes care of `wtxidrelay`.
+    pub(crate) fn negotiation(&mut self, version: &VersionMessage) -> Vec<NetworkMessage> {
+        if !self.settings.enabled() || !version.relay {
+            return Vec::new();
+        }
+        self.negotiated = true;
+        let send_tx_rcncl = SendTxRcncl {
+            version: RECONCILIATION_VERSION,
+            salt: self.salt,
+        };
+        vec![send_tx_rcncl.to_network_message()]
+    }
+
+    /// Start reconciling once the peer completed the handshake, when both sides support it.
+    /// Reconciliation needs both sides to announce transactions by wtxid.
+    pub(crate) fn receive_verack(&mut self, wtxid_relay: bool) {
+        let Some(peer_salt) = self.peer_salt else {
+            return;
+        };
+        if !self.negotiated || !wtxid_relay {
+            tracing::debug!("The peer sent sendtxrcncl without wtxidrelay");
+            return;
+        }
+        tracing::info!("Reconciling transactions with the peer");
+        self.keys = Some(siphash_keys(self.salt, peer_salt));
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// Handle a reconciliation message of the peer, `None` when the command is not part of
+    /// the protocol. The reply is sent to the peer.
+    pub(crate) fn receive(
+        &mut self,
+        command: &CommandString,
+        payload: &[u8],
+        handshake_complete: bool,
+    ) -> Option<Result<Vec<NetworkMessage>, encode::Error>> {
+        let reply = match command.as_ref() {
+            SendTxRcncl::COMMAND => SendTxRcncl::decode(payload).map(|message| {
+                self.receive_send_tx_rcncl(message, handshake_complete);
+                Vec::new()
+            }),
+            ReqRecon::COMMAND => ReqRecon::decode(payload)
+                .map(|message| self.receive_request(message, Instant::now())),
+            Sketch::COMMAND => Sketch::decode(payload).map(|message| self.receive_sketch(message)),
+            ReconcilDiff::COMMAND => {
+                ReconcilDiff::decode(payload).map(|message| self.receive_difference(message))
+            }
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    fn receive_send_tx_rcncl(&mut self, message: SendTxRcncl, handshake_complete: bool) {
+        if handshake_complete || message.version < RECONCILIATION_VERSION {
+            tracing::debug!("Ignoring {:?}", message);
+            return;
+        }
+        self.peer_salt = Some(message.salt);
+    }
+
+    /// Announce a transaction in the next reconciliation, `false` when the peer does not
+    /// reconcile or the set is full and the transaction has to be flooded.
+    pub(crate) fn announce(&mut self, wtxid: Wtxid) -> bool {
+        let Some(keys) = self.keys else {
+            return false;
+        };
+        if self.set.len() >= MAX_SET_SIZE {
+            return false;
+        }
+        self.set.insert(short_id(keys, &wtxid), wtxid);
+        true
+    }
+
+    /// The peer sent us the transaction, it does not need to hear about it.
+    pub(crate) fn forget(&mut self, wtxid: &Wtxid) {
+        if let Some(keys) = self.keys {
+            self.set.remove(&short_id(keys, wtxid));
+        }
+    }
+
+    /// Ask the peer for a sketch, when we connected to it and no reconciliation is running.
+    pub(crate) fn request(&mut self, now: Instant) -> Option<NetworkMessage> {
+        if !self.is_active() || self.inbound || !matches!(self.round, Round::Idle) {
+            return None;
+        }
+        self.round = Round::Requested(now);
+        let request = ReqRecon {
+            set_size: self.set.len().min(usize::from(u16::MAX)) as u16,
+            q: (Q * f64::from(Q_PRECISION)) as u16,
+        };
+        Some(request.to_network_message())
+    }
+
+    /// Answer a request with the sketch of our set. The set is kept aside until the peer
+    /// tells us what it is missing.
+    fn receive_request(&mut self, request: ReqRecon, now: Instant) -> Vec<NetworkMessage> {
+        if !self.is_active() || !self.inbound || !matches!(self.round, Round::Idle) {
+            tracing::debug!("Ignoring unexpected {:?}", request);
+            return Vec::new();
+        }
+        let set = std::mem::take(&mut self.set);
+        let capacity = capacity(set.len(), usize::from(request.set_size), request.q);
+        let skdata = if capacity > MAX_SKETCH_CAPACITY {
+            Vec::new()
+        } else {
+            let mut sketch = Minisketch::new(capacity);
+            set.keys().for_each(|short_id| sketch.add(*short_id));
+            sketch.serialize()
+        };
+        self.round = Round::Responded(set, now);
+        vec![Sketch { skdata }.to_network_message()]
+    }
+
+    /// Merge the sketch of the peer with the sketch of our set. Decoding the difference is too
+    /// slow for the driver, it is left to the caller: see [`Self::take_sketch`] and
+    /// [`Self::receive_decoded`].
+    fn receive_sketch(&mut self, sketch: Sketch) -> Vec<NetworkMessage> {
+        if !matches!(self.round, Round::Requested(_)) {
+            tracing::debug!("Ignoring unexpected sketch");
+            return Vec::new();
+        }
+        let set = std::mem::take(&mut self.set);
+        let merged = Minisketch::deserialize(&sketch.skdata)
+            .filter(|sketch| sketch.capacity() > 0)
+            .map(|mut sketch| {
+                let mut local = Minisketch::new(sketch.capacity());
+                set.keys().for_each(|short_id| local.add(*short_id));
+                sketch.merge(&local);
+                sketch
+            });
+        self.round = Round::Decoding(set);
+        match merged {
+            Some(merged) => {
+                self.sketch = Some(merged);
+                Vec::new()
+            }
+            None => self.receive_decoded(None),
+        }
+    }
+
+    /// The merged sketch waiting to be decoded, the result goes to [`Self::receive_decoded`].
+    pub(crate) fn take_sketch(&mut self) -> Option<Minisketch> {
+        self.sketch.take()
+    }
+
+    /// We announce the transactions the peer is missing and ask for the ones we are missing,
+    /// or flood our set when the difference could not be decoded.
+    pub(crate) fn receive_decoded(&mut self, difference: Option<Vec<u32>>) -> Vec<NetworkMessage> {
+        let Round::Decoding(set) = std::mem::replace(&mut self.round, Round::Idle) else {
+            return Vec::new();
+        };
+        let Some(difference) = difference else {
+            tracing::debug!("Reconciliation failed, flooding {} transactions", set.len());
+            let failure = ReconcilDiff {
+                success: false,
+                ask_shortids: Vec::new(),
+            };
+            let mut messages = vec![failure.to_network_message()];
+            messages.extend(inventory(set.into_values()));
+            return messages;
+        };
+
+        let (local, missing): (Vec<u32>, Vec<u32>) = difference
+            .into_iter()
+            .partition(|short_id| set.contains_key(short_id));
+        tracing::debug!(
+            "Reconciled: {} transactions to announce, {} to ask for",
+            local.len(),
+            missing.len()
+        );
+        let success = ReconcilDiff {
+            success: true,
+            ask_shortids: missing,
+        };
+        let mut messages = vec![success.to_network_message()];
+        messages.extend(inventory(
+            local
+                .iter()
+                .filter_map(|short_id| set.get(short_id).copied()),
+        ));
+        messages
+    }
+
+    /// Fail the round when the peer did not answer in time and flood the transactions it was
+    /// meant to reconcile. Late answers of the peer are ignored.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<NetworkMessage> {
+        let timed_out = match &self.round {
+            Round::Requested(since) | Round::Responded(_, since) => {
+                now.saturating_duration_since(*since) >= ROUND_TIMEOUT
+            }
+            Round::Idle | Round::Decoding(_) => false,
+        };
+        if !timed_out {
+            return Vec::new();
+        }
+        let set = match std::mem::replace(&mut self.round, Round::Idle) {
+            Round::Responded(set, _) => set,
+            _ => std::mem::take(&mut self.set),
+        };
+        tracing::debug!(
+            "Reconciliation timed out, flooding {} transactions",
+            set.len()
+        );
+        inventory(set.into_values())
+    }
+
+    /// Announce what the peer asked for, or the whole set when the reconciliation failed.
+    fn receive_difference(&mut self, difference: ReconcilDiff) -> Vec<NetworkMessage> {
+        let Round::Responded(set, _) = std::mem::replace(&mut self.round, Round::Idle) else {
+            tracing::debug!("Ignoring unexpected {:?}", difference);
+            return Vec::new();
+        };
+        if !difference.success {
+            return inventory(set.into_values());
+        }
+        inventory(
+            difference
+                .ask_shortids
+                .iter()
+                .filter_map(|short_id| set.get(short_id).copied()),
+        )
+    }
+}
+
+/// Combine both salts into the SipHash keys, the tagged hash of the salts in ascending order.
+fn siphash_keys(salt: u64, peer_salt: u64) -> (u64, u64) {
+    let tag = sha256::Hash::hash(b"Tx Relay Salting");
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine.input(&salt.min(peer_salt).to_le_bytes());
+    engine.input(&salt.max(peer_salt).to_le_bytes());
+    let hash = sha256::Hash::from_engine(engine).into_inner();
+    let key = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().expect("8 bytes"));
+    (key(&hash[..8]), key(&hash[8..16]))
+}
+
+/// The 32-bit short id of a transaction, never 0.
+fn short_id((k0, k1): (u64, u64), wtxid: &Wtxid) -> u32 {
+    let hash = siphash24::Hash::hash_with_keys(k0, k1, &wtxid[..]).as_u64();
+    1 + (hash % u64::from(u32::MAX)) as u32
+}
+
+/// The capacity of the sketch: the expected difference of the sets plus one element, so that
+/// a larger difference is unlikely to decode to a wrong set.
+fn capacity(local_size: usize, remote_size: usize, q: u16) -> usize {
+    let weighted_min_size =
+        (f64::from(q) * local_size.min(remote_size) as f64 / f64::from(Q_PRECISION)) as usize;
+    let expected_difference = 1 + weighted_min_size + local_size.abs_diff(remote_size);
+    expected_difference + 1
+}
+
+/// `inv` messages announcing the transactions by wtxid.
+fn inventory(wtxids: impl IntoIterator<Item = Wtxid>) -> Vec<NetworkMessage> {
+    let inventory: Vec<Inventory> = wtxids.into_iter().map(Inventory::WTx).collect();
+    inventory
+        .chunks(MAX_INV_SIZE)
+        .map(|chunk| NetworkMessage::Inv(chunk.to_vec()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salts_and_short_ids_match_bip330() {
+        // Computed independently from the BIP330 definitions: the salts are ordered, tagged
+        // with SHA256("Tx Relay Salting") and the short id is 1 + SipHash-2-4 mod 2^32-1
+        let keys = siphash_keys(1, 2);
+        assert_eq!(keys, (0x5a63_d274_39e0_52a4, 0xc8da_f59f_8d69_21b9));
+        assert_eq!(siphash_keys(2, 1), keys);
+
+        let mut bytes = [0; 32];
+        bytes.iter_mut().zip(0..).for_each(|(byte, i)| *byte = i);
+        assert_eq!(short_id(keys, &Wtxid::from_inner(bytes)), 961_547_771);
+    }
+
+    #[test]
+    fn sketches_above_the_maximum_capacity_are_rejected() {
+        let sketch = Sketch {
+            skdata: vec![0; MAX_SKETCH_CAPACITY * 4],
+        };
+        assert_eq!(Sketch::decode(&sketch.encode()).ok(), Some(sketch));
+
+        let oversized = Sketch {
+            skdata: vec![0; (MAX_SKETCH_CAPACITY + 1) * 4],
+        };
+        assert!(Sketch::decode(&oversized.encode()).is_err());
+    }
+
+    fn active(inbound: bool) -> Reconciliation {
+        let mut reconciliation = Reconciliation::new(ReconciliationSettings::new(true), inbound);
+        reconciliation.keys = Some((1, 2));
+        reconciliation
+    }
+
+    fn wtxid(n: u8) -> Wtxid {
+        Wtxid::from_inner([n; 32])
+    }
+
+    fn flooded(messages: &[NetworkMessage]) -> Vec<Wtxid> {
+        messages
+            .iter()
+            .flat_map(|message| match message {
+                NetworkMessage::Inv(inventory) => inventory.clone(),
+                _ => Vec::new(),
+            })
+            .filter_map(|item| match item {
+                Inventory::WTx(wtxid) => Some(wtxid),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        let mut reconciliation = active(false);
+        assert!(reconciliation.announce(wtxid(1)));
+        let now = Instant::now();
+        assert!(reconciliation.request(now).is_some());
+        assert!(reconciliation.request(now).is_none());
+
+        assert!(reconciliation.expire(now + ROUND_TIMEOUT / 2).is_empty());
+        let messages = reconciliation.expire(now + ROUND_TIMEOUT);
+        assert_eq!(flooded(&messages), vec![wtxid(1)]);
+
+        // The late sketch is ignored and the next round starts
+        let late = Sketch { skdata: Vec::new() };
+        let command = CommandString::try_from_static(Sketch::COMMAND).unwrap();
+        let reply = reconciliation.receive(&command, &late.encode(), true);
+        assert!(matches!(reply, Some(Ok(reply)) if reply.is_empty()));
+        assert!(reconciliation.request(now + ROUND_TIMEOUT).is_some());
+    }
+
+    #[test]
+    fn unanswered_sketches_time_out() {
+        let mut reconciliation = active(true);
+        assert!(reconciliation.announce(wtxid(1)));
+        let now = Instant::now();
+        let request = ReqRecon {
+            set_size: 1,
+            q: (Q * f64::from(Q_PRECISION)) as u16,
+        };
+        assert_eq!(reconciliation.receive_request(request, now).len(), 1);
+        assert!(reconciliation.announce(wtxid(2)));
+
+        assert!(reconciliation.expire(now + ROUND_TIMEOUT / 2).is_empty());
+        let messages = reconciliation.expire(now + ROUND_TIMEOUT);
+        assert_eq!(flooded(&messages), vec![wtxid(1)]);
+        assert!(matches!(reconciliation.round, Round::Idle));
+        assert_eq!(reconciliation.set.len(), 1);
+    }
+
+    #[test]
+    fn full_sets_are_flooded() {
+        let mut reconciliation = active(false);
+        for n in 0..MAX_SET_SIZE as u32 {
+            let mut bytes = [0; 32];
+            bytes[..4].copy_from_slice(&n.to_le_bytes());
+            assert!(reconciliation.announce(Wtxid::from_inner(bytes)));
+        }
+        assert!(!reconciliation.announce(wtxid(0xff)));
+    }
+}
@@ -0,0 +1,149 @@
+//! Minimal SOCKS5 client (RFC 1928), enough to reach peers through Tor.
+use std::io;
+use std::net::SocketAddr;
+
+use settings::PeerAddress;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// Connect to `target` through the proxy. The returned stream is tunneled to the target, host
+/// names are resolved by the proxy.
+pub(super) async fn connect(proxy: SocketAddr, target: &PeerAddress) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [VERSION, NO_AUTHENTICATION] {
+        return Err(proxy_error(
+            io::ErrorKind::PermissionDenied,
+            "the proxy requires authentication",
+        ));
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target {
+        PeerAddress::Socket(SocketAddr::V4(address)) => {
+            request.push(ADDRESS_IPV4);
+            request.extend(address.ip().octets());
+        }
+        PeerAddress::Socket(SocketAddr::V6(address)) => {
+            request.push(ADDRESS_IPV6);
+            request.extend(address.ip().octets());
+        }
+        PeerAddress::Host { host, .. } => {
+            let Ok(len) = u8::try_from(host.len()) else {
+                return Err(proxy_error(
+                    io::ErrorKind::InvalidInput,
+                    "the host name is longer than 255 bytes",
+                ));
+            };
+            request.extend([ADDRESS_DOMAIN, len]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(
+            io::ErrorKind::ConnectionRefused,
+            reply_message(reply[1]),
+        ));
+    }
+    // Skip the address the proxy bound to
+    let bound_address_len = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => usize::from(stream.read_u8().await?),
+        _ => {
+            return Err(proxy_error(
+                io::ErrorKind::InvalidData,
+                "unknown address type",
+            ))
+        }
+    };
+    let mut bound_address = vec![0; bound_address_len + 2];
+    stream.read_exact(&mut bound_address).await?;
+    Ok(stream)
+}
+
+fn proxy_error(kind: io::ErrorKind, message: &str) -> io::Error {
+    io::Error::new(kind, format!("SOCKS5 proxy: {message}"))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Accept one connection and answer like a proxy that reached the target, return the
+    /// connect request.
+    async fn proxy(listener: TcpListener) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream
+            .write_all(&[VERSION, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        let mut request = vec![0; 512];
+        let len = stream.read(&mut request).await.unwrap();
+        request.truncate(len);
+        stream
+            .write_all(&[VERSION, 0, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        request
+    }
+
+    async fn connect_request(target: &PeerAddress) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(proxy(listener));
+        connect(address, target).await.unwrap();
+        proxy.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn host_names_are_resolved_by_the_proxy() {
+        let onion = "example.onion:8333".parse().unwrap();
+        let mut expected = vec![VERSION, CONNECT, 0, ADDRESS_DOMAIN, 13];
+        expected.extend(b"example.onion");
+        expected.extend(8333u16.to_be_bytes());
+        assert_eq!(connect_request(&onion).await, expected);
+    }
+
+    #[tokio::test]
+    async fn addresses_are_sent_as_they_are() {
+        let address = "10.1.2.3:8333".parse().unwrap();
+        assert_eq!(
+            connect_request(&address).await,
+            vec![VERSION, CONNECT, 0, ADDRESS_IPV4, 10, 1, 2, 3, 0x20, 0x8d]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use bitcoin::BlockHash;
+use chrono::{DateTime, Utc};
+
+/// Commands counted under their own name: the messages rust-bitcoin decodes and BIP330's.
+/// Peers choose the commands they send, so any other command is counted as
+/// [`OTHER_COMMAND`] and the statistics can't grow without bound.
+const KNOWN_COMMANDS: &[&str] = &[
+    "version",
+    "verack",
+    "addr",
+    "inv",
+    "getdata",
+    "notfound",
+    "getblocks",
+    "getheaders",
+    "mempool",
+    "tx",
+    "block",
+    "headers",
+    "sendheaders",
+    "getaddr",
+    "ping",
+    "pong",
+    "merkleblock",
+    "filterload",
+    "filteradd",
+    "filterclear",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "sendcmpct",
+    "cmpctblock",
+    "getblocktxn",
+    "blocktxn",
+    "alert",
+    "reject",
+    "feefilter",
+    "wtxidrelay",
+    "addrv2",
+    "sendaddrv2",
+    "sendtxrcncl",
+    "reqrecon",
+    "sketch",
+    "reconcildiff",
+];
+
+/// The key of the commands that are not in the list of known commands.
+pub const OTHER_COMMAND: &str = "other";
+
+/// Traffic counters of a single command.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct CommandStats {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+/// Outcomes of the BIP152 compact block reconstruction.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct CompactBlockStats {
+    pub received: u64,
+    /// Blocks rebuilt from the mempool alone.
+    pub reconstructed: u64,
+    /// Blocks that needed a `getblocktxn` round trip.
+    pub reconstructed_after_round_trip: u64,
+    /// Blocks that had to be downloaded in full.
+    pub failed: u64,
+    /// Transactions found in the mempool.
+    pub mempool_hits: u64,
+    /// Transactions that had to be requested from the peer.
+    pub mempool_misses: u64,
+}
+
+/// A snapshot of the traffic statistics of a connection.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ConnectionStats {
+    pub peer_address: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    /// Counters keyed by the command string, unknown commands are counted as
+    /// [`OTHER_COMMAND`].
+    pub commands: BTreeMap<String, CommandStats>,
+    /// Frames that could not be decoded.
+    pub decode_errors: u64,
+    pub last_send: Option<DateTime<Utc>>,
+    pub last_receive: Option<DateTime<Utc>>,
+    /// Round trip time of the last answered ping.
+    pub ping_latency: Option<Duration>,
+    pub compact_blocks: CompactBlockStats,
+    /// The minimum fee rate (sat/kvB) of the transactions the peer wants to hear about, from
+    /// its BIP133 `feefilter`.
+    pub peer_fee_filter: Option<u64>,
+    /// Both sides announce transactions by wtxid (BIP339), known after the handshake.
+    pub wtxid_relay: bool,
+    /// The best block the peer announced and its height.
+    pub best_header: Option<(BlockHash, u32)>,
+}
+
+impl ConnectionStats {
+    /// Counters summed over all commands.
+    pub fn total(&self) -> CommandStats {
+        self.commands
+            .values()
+            .fold(CommandStats::default(), |total, stats| CommandStats {
+                messages_in: total.messages_in + stats.messages_in,
+                bytes_in: total.bytes_in + stats.bytes_in,
+                messages_out: total.messages_out + stats.messages_out,
+                bytes_out: total.bytes_out + stats.bytes_out,
+            })
+    }
+}
+
+/// Shared, cheaply clonable recorder of the connection statistics. The tasks of the connection
+/// record into it, the handle takes snapshots.
+#[derive(Clone, Debug)]
+pub struct StatsRecorder {
+    stats: Arc<Mutex<ConnectionStats>>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn new(peer_address: SocketAddr) -> Self {
+        Self {
+            stats: Arc::new(Mutex::new(ConnectionStats {
+                peer_address,
+                connected_at: Utc::now(),
+                commands: BTreeMap::new(),
+                decode_errors: 0,
+                last_send: None,
+                last_receive: None,
+                ping_latency: None,
+                compact_blocks: CompactBlockStats::default(),
+                peer_fee_filter: None,
+                wtxid_relay: false,
+                best_header: None,
+            })),
+        }
+    }
+
+    pub(crate) fn peer_address(&self) -> SocketAddr {
+        self.lock().peer_address
+    }
+
+    pub fn snapshot(&self) -> ConnectionStats {
+        self.lock().clone()
+    }
+
+    pub(crate) fn record_inbound(&self, command: &str, bytes: usize) {
+        let mut stats = self.lock();
+        stats.last_receive = Some(Utc::now());
+        let command = stats.commands.entry(command_key(command)).or_default();
+        command.messages_in += 1;
+        command.bytes_in += bytes as u64;
+    }
+
+    pub(crate) fn record_outbound(&self, command: &str, bytes: usize) {
+        let mut stats = self.lock();
+        stats.last_send = Some(Utc::now());
+        let command = stats.commands.entry(command_key(command)).or_default();
+        command.messages_out += 1;
+        command.bytes_out += bytes as u64;
+    }
+
+    pub(crate) fn record_decode_error(&self) {
+        self.lock().decode_errors += 1;
+    }
+
+    pub(crate) fn record_ping_latency(&self, latency: Duration) {
+        self.lock().ping_latency = Some(latency);
+    }
+
+    pub(crate) fn record_peer_fee_filter(&self, fee_rate: u64) {
+        self.lock().peer_fee_filter = Some(fee_rate);
+    }
+
+    pub(crate) fn record_wtxid_relay(&self, wtxid_relay: bool) {
+        self.lock().wtxid_relay = wtxid_relay;
+    }
+
+    pub(crate) fn record_best_header(&self, block_hash: BlockHash, height: u32) {
+        self.lock().best_header = Some((block_hash, height));
+    }
+
+    pub(crate) fn record_compact_block_received(&self) {
+        self.lock().compact_blocks.received += 1;
+    }
+
+    pub(crate) fn record_compact_block_transactions(&self, hits: u64, misses: u64) {
+        let mut stats = self.lock();
+        stats.compact_blocks.mempool_hits += hits;
+        stats.compact_blocks.mempool_misses += misses;
+    }
+
+    pub(crate) fn record_compact_block_reconstructed(&self, round_trip: bool) {
+        let mut stats = self.lock();
+        if round_trip {
+            stats.compact_blocks.reconstructed_after_round_trip += 1;
+        } else {
+            stats.compact_blocks.reconstructed += 1;
+        }
+    }
+
+    pub(crate) fn record_compact_block_failed(&self) {
+        self.lock().compact_blocks.failed += 1;
+    }
+
+    /// The shared statistics, the metrics exporter tells closed connections apart by being the
+    /// last owner.
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn shared(&self) -> Arc<Mutex<ConnectionStats>> {
+        self.stats.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ConnectionStats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn command_key(command: &str) -> String {
+    match KNOWN_COMMANDS.contains(&command) {
+        true => command.to_string(),
+        false => OTHER_COMMAND.to_string(),
+    }
+}
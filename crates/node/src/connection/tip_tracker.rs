@@ -0,0 +1,260 @@
+//! The best block the peer told us about, from BIP130 `headers` and `inv` announcements.
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHash, BlockHeader};
+use futures::Stream;
+
+use crate::FromConnectionHandle;
+
+/// Headers that are remembered to connect announcements, older ones are forgotten.
+const MAX_KNOWN_HEADERS: usize = 2016;
+/// The most recent headers are all in the locator, the steps back double after them.
+const DENSE_LOCATOR_HASHES: usize = 10;
+
+/// A new best block of a peer.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct NewTip {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    pub peer: SocketAddr,
+}
+
+/// What the driver has to do about announced headers.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) enum HeadersOutcome {
+    /// The headers connect, the last one is the new best header when its chain has more work
+    /// than the best one before.
+    Connected(Option<(BlockHash, u32)>),
+    /// The headers don't connect to the headers we know, ask for the missing ones.
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct KnownHeader {
+    height: u32,
+    prev_blockhash: BlockHash,
+    /// The work of the chain up to and including this header.
+    chainwork: Uint256,
+}
+
+/// Tracks the best known header of a peer, the one with the most work.
+///
+/// Heights and chainwork are counted from the genesis block, only headers that connect to the
+/// ones we know are taken. The headers of a peer that is ahead are first synced from the
+/// genesis block.
+#[derive(Debug)]
+pub(crate) struct TipTracker {
+    headers: HashMap<BlockHash, KnownHeader>,
+    /// Insertion order of `headers`, to forget the oldest ones.
+    order: VecDeque<BlockHash>,
+    genesis: BlockHash,
+    best: Option<(BlockHash, u32, Uint256)>,
+}
+
+impl TipTracker {
+    pub(crate) fn new(network: constants::Network) -> Self {
+        let genesis = genesis_block(network).header;
+        let mut tracker = Self {
+            headers: HashMap::new(),
+            order: VecDeque::new(),
+            genesis: genesis.block_hash(),
+            best: None,
+        };
+        let header = KnownHeader {
+            height: 0,
+            prev_blockhash: BlockHash::all_zeros(),
+            chainwork: genesis.work(),
+        };
+        tracker.insert(genesis.block_hash(), header);
+        tracker
+    }
+
+    pub(crate) fn is_known(&self, block_hash: &BlockHash) -> bool {
+        self.headers.contains_key(block_hash)
+    }
+
+    /// Headers the peer announced or sent on request, in chain order.
+    pub(crate) fn receive_headers(&mut self, headers: &[BlockHeader]) -> HeadersOutcome {
+        let Some(first) = headers.first() else {
+            return HeadersOutcome::Connected(None);
+        };
+        let Some(parent) = self.headers.get(&first.prev_blockhash) else {
+            return HeadersOutcome::Disconnected;
+        };
+        let (mut height, mut chainwork) = (parent.height, parent.chainwork);
+        for header in headers {
+            height += 1;
+            chainwork = chainwork + header.work();
+            let known = KnownHeader {
+                height,
+                prev_blockhash: header.prev_blockhash,
+                chainwork,
+            };
+            self.insert(header.block_hash(), known);
+        }
+        let tip = headers[headers.len() - 1].block_hash();
+        match self.best {
+            Some((_, _, best_chainwork)) if best_chainwork >= chainwork => {
+                HeadersOutcome::Connected(None)
+            }
+            _ => {
+                self.best = Some((tip, height, chainwork));
+                HeadersOutcome::Connected(Some((tip, height)))
+            }
+        }
+    }
+
+    /// Hashes of our view of the peer's chain for `getheaders`, from the best header back,
+    /// only the genesis block before the first headers connected.
+    pub(crate) fn locator(&self) -> Vec<BlockHash> {
+        let mut block_hash = match self.best {
+            Some((block_hash, _, _)) => block_hash,
+            None => self.genesis,
+        };
+        let mut chain = Vec::new();
+        while let Some(header) = self.headers.get(&block_hash) {
+            chain.push(block_hash);
+            block_hash = header.prev_blockhash;
+        }
+        let mut locator = Vec::new();
+        let mut index = 0;
+        let mut step = 1;
+        while index < chain.len() {
+            locator.push(chain[index]);
+            if locator.len() >= DENSE_LOCATOR_HASHES {
+                step *= 2;
+            }
+            index += step;
+        }
+        // Always end with the oldest header, the genesis block when we know the whole chain
+        if let Some(oldest) = chain.last() {
+            if locator.last() != Some(oldest) {
+                locator.push(*oldest);
+            }
+        }
+        locator
+    }
+
+    fn insert(&mut self, block_hash: BlockHash, header: KnownHeader) {
+        if self.headers.insert(block_hash, header).is_some() {
+            return;
+        }
+        self.order.push_back(block_hash);
+        if self.order.len() > MAX_KNOWN_HEADERS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.headers.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The new tips among the events of a connection to `peer`.
+pub(crate) fn new_tips(
+    mut events: tokio::sync::broadcast::Receiver<FromConnectionHandle>,
+    peer: SocketAddr,
+) -> impl Stream<Item = NewTip> {
+    async_stream::stream! {
+        loop {
+            match events.recv().await {
+                Ok(FromConnectionHandle::NewTip { block_hash, height }) => {
+                    yield NewTip { block_hash, height, peer };
+                }
+                Ok(_) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Tip receiver fell behind, skipped {} messages", skipped);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::network::constants::Network;
+
+    use super::*;
+
+    /// A regtest header after `prev_blockhash` with enough proof of work for `bits`.
+    fn mine(prev_blockhash: BlockHash, bits: u32) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 4,
+            prev_blockhash,
+            merkle_root: Hash::all_zeros(),
+            time: 1_700_000_000,
+            bits,
+            nonce: 0,
+        };
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn chain(prev_blockhash: BlockHash, bits: u32, count: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for _ in 0..count {
+            let prev = headers.last().map_or(prev_blockhash, |h| h.block_hash());
+            headers.push(mine(prev, bits));
+        }
+        headers
+    }
+
+    #[test]
+    fn connected_headers_extend_the_tip() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let mut tracker = TipTracker::new(Network::Regtest);
+        let headers = chain(genesis, 0x207fffff, 3);
+        assert_eq!(
+            tracker.receive_headers(&headers),
+            HeadersOutcome::Connected(Some((headers[2].block_hash(), 3)))
+        );
+        assert_eq!(tracker.locator()[0], headers[2].block_hash());
+        assert_eq!(tracker.locator().last(), Some(&genesis));
+
+        let unknown = chain(BlockHash::all_zeros(), 0x207fffff, 1);
+        assert_eq!(
+            tracker.receive_headers(&unknown),
+            HeadersOutcome::Disconnected
+        );
+    }
+
+    #[test]
+    fn the_tip_has_the_most_work_not_the_most_headers() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let mut tracker = TipTracker::new(Network::Regtest);
+        let heavy = chain(genesis, 0x1f00ffff, 1);
+        tracker.receive_headers(&heavy);
+
+        let long = chain(genesis, 0x207fffff, 5);
+        assert_eq!(
+            tracker.receive_headers(&long),
+            HeadersOutcome::Connected(None)
+        );
+        assert_eq!(tracker.locator()[0], heavy[0].block_hash());
+    }
+
+    #[test]
+    fn headers_are_synced_from_the_genesis_block() {
+        let genesis = genesis_block(Network::Regtest).block_hash();
+        let mut tracker = TipTracker::new(Network::Regtest);
+        assert_eq!(tracker.locator(), vec![genesis]);
+
+        // The first announcement of a peer that is ahead does not connect
+        let headers = chain(genesis, 0x207fffff, 3);
+        assert_eq!(
+            tracker.receive_headers(&headers[2..]),
+            HeadersOutcome::Disconnected
+        );
+        assert_eq!(tracker.locator(), vec![genesis]);
+        assert_eq!(
+            tracker.receive_headers(&headers),
+            HeadersOutcome::Connected(Some((headers[2].block_hash(), 3)))
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::network::Address;
+use settings::{HandshakeOverrides, HandshakeSettings};
+
+/// Builds the version message we introduce ourselves with.
+///
+/// The defaults come from the `[handshake]` settings. The start height and the nonce are
+/// provided by functions, so they can follow a chain that is synced in the background or be
+/// made deterministic in tests.
+#[derive(Clone)]
+pub struct VersionBuilder {
+    user_agent: String,
+    services: ServiceFlags,
+    protocol_version: u32,
+    start_height: Arc<dyn Fn() -> i32 + Send + Sync>,
+    relay: bool,
+    nonce: Arc<dyn Fn() -> u64 + Send + Sync>,
+}
+
+impl VersionBuilder {
+    pub fn new(settings: &HandshakeSettings) -> Self {
+        let start_height = settings.start_height();
+        Self {
+            user_agent: user_agent(
+                settings.user_agent_name(),
+                settings.user_agent_version(),
+                settings.user_agent_comments(),
+            ),
+            services: settings.services(),
+            protocol_version: settings.protocol_version(),
+            start_height: Arc::new(move || start_height),
+            relay: settings.relay(),
+            nonce: Arc::new(rand::random),
+        }
+    }
+
+    /// Set the user agent in the BIP14 format `/name:version(comment; comment)/`.
+    pub fn user_agent<S: AsRef<str>>(mut self, name: &str, version: &str, comments: &[S]) -> Self {
+        self.user_agent = user_agent(name, version, comments);
+        self
+    }
+
+    /// Set the user agent as is, e.g. to impersonate another implementation.
+    pub fn raw_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Advertise a service in addition to the configured ones.
+    pub fn add_services(mut self, services: ServiceFlags) -> Self {
+        self.services |= services;
+        self
+    }
+
+    pub fn protocol_version(mut self, protocol_version: u32) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    pub fn start_height(self, start_height: i32) -> Self {
+        self.start_height_provider(move || start_height)
+    }
+
+    /// Ask `provider` for the height of our best block whenever a version message is built.
+    pub fn start_height_provider(
+        mut self,
+        provider: impl Fn() -> i32 + Send + Sync + 'static,
+    ) -> Self {
+        self.start_height = Arc::new(provider);
+        self
+    }
+
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Where the nonce comes from, random by default. The peer uses it to detect connections
+    /// to itself.
+    pub fn nonce_source(mut self, source: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+        self.nonce = Arc::new(source);
+        self
+    }
+
+    /// Apply the handshake overrides of a peer. The parts of the user agent that are not
+    /// overridden come from `settings`.
+    pub fn with_overrides(
+        mut self,
+        settings: &HandshakeSettings,
+        overrides: &HandshakeOverrides,
+    ) -> Self {
+        if overrides.overrides_user_agent() {
+            self = self.user_agent(
+                overrides
+                    .user_agent_name()
+                    .unwrap_or(settings.user_agent_name()),
+                overrides
+                    .user_agent_version()
+                    .unwrap_or(settings.user_agent_version()),
+                overrides
+                    .user_agent_comments()
+                    .unwrap_or(settings.user_agent_comments()),
+            );
+        }
+        if let Some(services) = overrides.services() {
+            self = self.services(services);
+        }
+        if let Some(protocol_version) = overrides.protocol_version() {
+            self = self.protocol_version(protocol_version);
+        }
+        if let Some(start_height) = overrides.start_height() {
+            self = self.start_height(start_height);
+        }
+        if let Some(relay) = overrides.relay() {
+            self = self.relay(relay);
+        }
+        self
+    }
+
+    pub fn advertised_services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    pub fn build(&self, peer_address: &SocketAddr, sender_address: &SocketAddr) -> VersionMessage {
+        VersionMessage {
+            version: self.protocol_version,
+            services: self.services,
+            timestamp: chrono::Utc::now().timestamp(),
+            // We don't know the services of the peer yet
+            receiver: Address::new(peer_address, ServiceFlags::NONE),
+            sender: Address::new(sender_address, self.services),
+            nonce: (self.nonce)(),
+            user_agent: self.user_agent.clone(),
+            start_height: (self.start_height)(),
+            relay: self.relay,
+        }
+    }
+}
+
+impl Default for VersionBuilder {
+    fn default() -> Self {
+        Self::new(&HandshakeSettings::default())
+    }
+}
+
+impl fmt::Debug for VersionBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VersionBuilder")
+            .field("user_agent", &self.user_agent)
+            .field("services", &self.services)
+            .field("protocol_version", &self.protocol_version)
+            .field("start_height", &(self.start_height)())
+            .field("relay", &self.relay)
+            .finish_non_exhaustive()
+    }
+}
+
+fn user_agent<S: AsRef<str>>(name: &str, version: &str, comments: &[S]) -> String {
+    let comments = comments
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join("; ");
+    if comments.is_empty() {
+        format!("/{name}:{version}/")
+    } else {
+        format!("/{name}:{version}({comments})/")
+    }
+}
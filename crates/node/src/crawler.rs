@@ -0,0 +1,277 @@
+//! Maps the reachable network by asking every node for the addresses it knows.
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bitcoin::network::message::NetworkMessage;
+use chrono::{DateTime, Utc};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde::Serialize;
+use settings::{CrawlerSettings, OutputFormat};
+
+use crate::connection::{BitcoinConnection, BitcoinConnector, Connected};
+use crate::error::Error;
+use crate::FromConnectionHandle;
+
+/// What we learned about a single node.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct NodeRecord {
+    pub address: SocketAddr,
+    pub visited_at: DateTime<Utc>,
+    /// Whether the handshake completed.
+    pub reachable: bool,
+    /// Why the node could not be reached.
+    pub error: Option<String>,
+    pub protocol_version: Option<u32>,
+    pub services: Option<u64>,
+    pub user_agent: Option<String>,
+    pub start_height: Option<i32>,
+    pub relay: Option<bool>,
+    /// The time of the node, seconds since the unix epoch.
+    pub timestamp: Option<i64>,
+    /// Addresses the node sent in response to `getaddr`.
+    pub addresses: usize,
+}
+
+impl NodeRecord {
+    fn unreachable(address: SocketAddr, error: String) -> Self {
+        Self {
+            address,
+            visited_at: Utc::now(),
+            reachable: false,
+            error: Some(error),
+            protocol_version: None,
+            services: None,
+            user_agent: None,
+            start_height: None,
+            relay: None,
+            timestamp: None,
+            addresses: 0,
+        }
+    }
+}
+
+/// Visits the nodes breadth first, starting from the seeds. Every node is visited once.
+#[derive(Debug)]
+pub struct Crawler {
+    connector: BitcoinConnector,
+    settings: CrawlerSettings,
+}
+
+impl Crawler {
+    /// The connector provides the options of the connections, e.g. the version message and
+    /// the ban list. Banned nodes are recorded as unreachable.
+    pub fn new(connector: BitcoinConnector, settings: CrawlerSettings) -> Self {
+        Self {
+            connector,
+            settings,
+        }
+    }
+
+    /// Crawl the network, `on_record` is called for every visited node.
+    /// Returns the number of visited nodes.
+    pub async fn run(&self, mut on_record: impl FnMut(NodeRecord)) -> usize {
+        let mut queue: VecDeque<SocketAddr> = self.resolve_seeds().await.into();
+        let mut seen: HashSet<SocketAddr> = queue.iter().copied().collect();
+        let max_nodes = self.settings.max_nodes().unwrap_or(usize::MAX);
+        let max_concurrency = self.settings.max_concurrency().max(1);
+        let mut visits = FuturesUnordered::new();
+        let mut visited = 0;
+
+        loop {
+            while visits.len() < max_concurrency && visited + visits.len() < max_nodes {
+                let Some(address) = queue.pop_front() else {
+                    break;
+                };
+                visits.push(self.visit(address));
+            }
+            let Some((record, addresses)) = visits.next().await else {
+                break;
+            };
+            visited += 1;
+            for address in addresses {
+                if seen.insert(address) {
+                    queue.push_back(address);
+                }
+            }
+            tracing::info!(
+                "Visited {} ({}), {} visited, {} queued",
+                record.address,
+                if record.reachable {
+                    "reachable"
+                } else {
+                    "unreachable"
+                },
+                visited,
+                queue.len()
+            );
+            on_record(record);
+        }
+        visited
+    }
+
+    async fn resolve_seeds(&self) -> Vec<SocketAddr> {
+        let mut addresses = Vec::new();
+        for seed in self.settings.seeds() {
+            match tokio::net::lookup_host(seed).await {
+                Ok(resolved) => addresses.extend(resolved),
+                Err(err) => tracing::warn!("Failed to resolve seed {}: {}", seed, err),
+            }
+        }
+        addresses
+    }
+
+    /// Handshake with the node and ask for the addresses it knows.
+    async fn visit(&self, address: SocketAddr) -> (NodeRecord, Vec<SocketAddr>) {
+        let handshake = async {
+            self.connector
+                .connect_to(address)
+                .await?
+                .perform_handshake()
+                .await
+        };
+        let mut node =
+            match tokio::time::timeout(self.settings.handshake_timeout(), handshake).await {
+                Ok(Ok(node)) => node,
+                Ok(Err(err)) => return (NodeRecord::unreachable(address, err.to_string()), vec![]),
+                Err(_) => {
+                    return (
+                        NodeRecord::unreachable(address, "handshake timed out".to_string()),
+                        vec![],
+                    )
+                }
+            };
+
+        let addresses = match collect_addresses(&mut node, self.settings.getaddr_timeout()).await {
+            Ok(addresses) => addresses,
+            Err(err) => {
+                tracing::debug!("Failed to get the addresses of {}: {}", address, err);
+                Vec::new()
+            }
+        };
+        let version = node.peer_version();
+        let record = NodeRecord {
+            address,
+            visited_at: Utc::now(),
+            reachable: true,
+            error: None,
+            protocol_version: Some(version.version),
+            services: Some(version.services.to_u64()),
+            user_agent: Some(version.user_agent.clone()),
+            start_height: Some(version.start_height),
+            relay: Some(version.relay),
+            timestamp: Some(version.timestamp),
+            addresses: addresses.len(),
+        };
+        (record, addresses)
+    }
+}
+
+/// Send `getaddr` and wait for the answer. Nodes announce their own address right after the
+/// handshake, so single address messages do not end the wait.
+async fn collect_addresses(
+    node: &mut BitcoinConnection<Connected>,
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>, Error> {
+    node.send_get_addr().await?;
+    let mut addresses = Vec::new();
+    let receive = async {
+        loop {
+            let learned: Vec<SocketAddr> = match node.receive().await? {
+                FromConnectionHandle::FromBitcoinNode(NetworkMessage::Addr(entries)) => entries
+                    .iter()
+                    .filter_map(|(_, address)| address.socket_addr().ok())
+                    .collect(),
+                FromConnectionHandle::FromBitcoinNode(NetworkMessage::AddrV2(entries)) => entries
+                    .iter()
+                    .filter_map(|entry| entry.socket_addr().ok())
+                    .collect(),
+                FromConnectionHandle::Disconnected(reason) => {
+                    return Err(Error::Disconnected(reason))
+                }
+                _ => continue,
+            };
+            let done = learned.len() > 1;
+            addresses.extend(learned);
+            if done {
+                return Ok(());
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, receive).await {
+        Ok(result) => result?,
+        Err(_) => tracing::debug!("Timed out waiting for addresses"),
+    }
+    Ok(addresses)
+}
+
+/// Writes the crawl results as JSON Lines or CSV.
+#[derive(Debug)]
+pub struct RecordWriter<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    header_written: bool,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        Self {
+            writer,
+            format,
+            header_written: false,
+        }
+    }
+
+    pub fn write(&mut self, record: &NodeRecord) -> std::io::Result<()> {
+        match self.format {
+            OutputFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                writeln!(self.writer)
+            }
+            OutputFormat::Csv => self.write_csv(record),
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_csv(&mut self, record: &NodeRecord) -> std::io::Result<()> {
+        if !self.header_written {
+            writeln!(
+                self.writer,
+                "address,visited_at,reachable,error,protocol_version,services,user_agent,start_height,relay,timestamp,addresses"
+            )?;
+            self.header_written = true;
+        }
+        fn optional<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(ToString::to_string).unwrap_or_default()
+        }
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            record.address,
+            record.visited_at.to_rfc3339(),
+            record.reachable,
+            csv_escape(&optional(&record.error)),
+            optional(&record.protocol_version),
+            optional(&record.services),
+            csv_escape(&optional(&record.user_agent)),
+            optional(&record.start_height),
+            optional(&record.relay),
+            optional(&record.timestamp),
+            record.addresses
+        )
+    }
+}
+
+/// Quote the field if it contains a separator, a quote or a line break.
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -1,8 +1,9 @@
 use thiserror::Error;
 
-use crate::connection::FromConnectionHandle;
+use crate::connection::{DisconnectReason, FromConnectionHandle};
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
@@ -14,4 +15,16 @@ pub enum Error {
     UnexpectedConnectionMessage(Box<FromConnectionHandle>),
     #[error("Connection died")]
     ActorUnavailable,
+    #[error("Disconnected from the peer: {0:?}")]
+    Disconnected(DisconnectReason),
+    #[error("The peer can't keep up, the outbound queue is full")]
+    Backpressure,
+    #[error("Peer {0} is banned")]
+    Banned(std::net::IpAddr),
+    #[error("The peer does not offer the {0} service")]
+    MissingService(bitcoin::network::constants::ServiceFlags),
+    #[error("Timed out")]
+    Timeout,
+    #[error("No address to connect to")]
+    NoAddress,
 }
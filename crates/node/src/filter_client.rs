@@ -0,0 +1,505 @@
+//! BIP157 client for BIP158 compact block filters.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_filter::{CFHeaders, GetCFCheckpt, GetCFHeaders, GetCFilters};
+use bitcoin::util::bip158::{self, BlockFilter};
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHash, FilterHeader, Script};
+use thiserror::Error;
+
+use crate::connection::{BitcoinConnection, BitcoinConnector, Connected};
+use crate::error::Error;
+use crate::FromConnectionHandle;
+
+/// The only filter type defined by BIP158.
+pub const BASIC_FILTER_TYPE: u8 = 0;
+
+/// Peers answer at most this many filters per `getcfilters` request.
+pub const MAX_FILTERS_PER_REQUEST: usize = 1000;
+
+/// Peers answer at most this many filter headers per `getcfheaders` request.
+pub const MAX_FILTER_HEADERS_PER_REQUEST: usize = 2000;
+
+/// How long we wait for a single response before giving up on the peer.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("Peer {0} does not serve compact block filters")]
+    MissingService(SocketAddr),
+    #[error("No peers to download the filters from")]
+    NoPeers,
+    #[error("Peers disagree on the filter headers up to block {0}")]
+    HeaderMismatch(BlockHash),
+    #[error("Filter of block {0} does not match the filter headers")]
+    InvalidFilter(BlockHash),
+    #[error("Received the filter of block {received} instead of block {expected}")]
+    UnexpectedFilter {
+        expected: BlockHash,
+        received: BlockHash,
+    },
+    #[error("Peer {peer} sent {received} filter headers for a range of {expected} blocks")]
+    WrongHeaderCount {
+        peer: SocketAddr,
+        expected: usize,
+        received: usize,
+    },
+    #[error("The block headers of the range are empty or do not form a chain")]
+    InvalidRange,
+    #[error("Block {0} does not match its header")]
+    InvalidBlock(BlockHash),
+    #[error("Range of {blocks} blocks exceeds the limit of {limit} per request")]
+    RangeTooLarge { blocks: usize, limit: usize },
+    #[error("Timed out waiting for {0}")]
+    Timeout(&'static str),
+    #[error(transparent)]
+    Filter(#[from] bip158::Error),
+    #[error(transparent)]
+    Connection(#[from] Error),
+}
+
+/// Filter headers of a range of blocks, agreed on by all peers.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct FilterHeaders {
+    pub start_height: u32,
+    pub stop_hash: BlockHash,
+    /// Hashes of the blocks, one per block starting at `start_height`, from the block headers
+    /// of the range.
+    pub block_hashes: Vec<BlockHash>,
+    /// Header of the block before `start_height`.
+    pub previous_header: FilterHeader,
+    /// Hashes of the filters, one per block starting at `start_height`.
+    pub filter_hashes: Vec<FilterHash>,
+}
+
+impl FilterHeaders {
+    fn from_response(start_height: u32, block_hashes: Vec<BlockHash>, response: CFHeaders) -> Self {
+        Self {
+            start_height,
+            stop_hash: response.stop_hash,
+            block_hashes,
+            previous_header: response.previous_filter_header,
+            filter_hashes: response.filter_hashes,
+        }
+    }
+
+    /// The filter headers of the blocks, each one committing to all previous filters.
+    pub fn headers(&self) -> Vec<FilterHeader> {
+        self.filter_hashes
+            .iter()
+            .scan(self.previous_header, |previous, filter_hash| {
+                *previous = filter_hash.filter_header(previous);
+                Some(*previous)
+            })
+            .collect()
+    }
+
+    /// The header of the last block in the range.
+    pub fn stop_header(&self) -> FilterHeader {
+        self.headers()
+            .last()
+            .copied()
+            .unwrap_or(self.previous_header)
+    }
+}
+
+/// A light client that finds the blocks relevant to a wallet with compact block filters,
+/// without revealing the wallet scripts to the peers.
+///
+/// The filter headers are cross-checked between all peers, so a single honest peer is enough
+/// to detect a peer serving wrong filters. Only peers advertising
+/// [`ServiceFlags::COMPACT_FILTERS`] are accepted.
+#[derive(Debug, Default)]
+pub struct FilterClient {
+    peers: Vec<BitcoinConnection<Connected>>,
+}
+
+impl FilterClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to the peer of the connector and add it, if it serves compact block filters.
+    pub async fn connect(&mut self, connector: BitcoinConnector) -> Result<(), FilterError> {
+        let peer = connector.connect().await?.perform_handshake().await?;
+        self.add_peer(peer)
+    }
+
+    /// Add a connected peer. Peers that do not serve compact block filters are dropped,
+    /// which closes the connection.
+    pub fn add_peer(&mut self, peer: BitcoinConnection<Connected>) -> Result<(), FilterError> {
+        if !peer.peer_services().has(ServiceFlags::COMPACT_FILTERS) {
+            return Err(FilterError::MissingService(peer.peer_address()));
+        }
+        self.peers.push(peer);
+        Ok(())
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &BitcoinConnection<Connected>> {
+        self.peers.iter()
+    }
+
+    /// The filter headers at every 1000th block up to `stop_hash`, agreed on by all peers.
+    pub async fn checkpoints(
+        &mut self,
+        stop_hash: BlockHash,
+    ) -> Result<Vec<FilterHeader>, FilterError> {
+        let request = NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash,
+        });
+        let mut agreed: Option<Vec<FilterHeader>> = None;
+        for peer in self.peers_or_err()? {
+            peer.send(request.clone()).await?;
+            let checkpoints = receive(peer, "cfcheckpt", |message| match message {
+                NetworkMessage::CFCheckpt(checkpoint)
+                    if checkpoint.filter_type == BASIC_FILTER_TYPE
+                        && checkpoint.stop_hash == stop_hash =>
+                {
+                    Some(checkpoint.filter_headers)
+                }
+                _ => None,
+            })
+            .await?;
+            match &agreed {
+                Some(agreed) if *agreed != checkpoints => {
+                    return Err(FilterError::HeaderMismatch(stop_hash));
+                }
+                Some(_) => {}
+                None => agreed = Some(checkpoints),
+            }
+        }
+        Ok(agreed.unwrap_or_default())
+    }
+
+    /// Download the filter headers of the blocks from `start_height` from all peers and check
+    /// that they agree. `block_headers` are the headers of the blocks in the range, the last
+    /// one is the stop block; every peer must answer a filter header for each of them.
+    pub async fn filter_headers(
+        &mut self,
+        start_height: u32,
+        block_headers: &[BlockHeader],
+    ) -> Result<FilterHeaders, FilterError> {
+        let block_hashes = range_block_hashes(block_headers)?;
+        if block_hashes.len() > MAX_FILTER_HEADERS_PER_REQUEST {
+            return Err(FilterError::RangeTooLarge {
+                blocks: block_hashes.len(),
+                limit: MAX_FILTER_HEADERS_PER_REQUEST,
+            });
+        }
+        let stop_hash = block_hashes[block_hashes.len() - 1];
+        let request = NetworkMessage::GetCFHeaders(GetCFHeaders {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height,
+            stop_hash,
+        });
+        let mut agreed: Option<FilterHeaders> = None;
+        for peer in self.peers_or_err()? {
+            peer.send(request.clone()).await?;
+            let response = receive(peer, "cfheaders", |message| match message {
+                NetworkMessage::CFHeaders(headers)
+                    if headers.filter_type == BASIC_FILTER_TYPE
+                        && headers.stop_hash == stop_hash =>
+                {
+                    Some(headers)
+                }
+                _ => None,
+            })
+            .await?;
+            if response.filter_hashes.len() != block_hashes.len() {
+                return Err(FilterError::WrongHeaderCount {
+                    peer: peer.peer_address(),
+                    expected: block_hashes.len(),
+                    received: response.filter_hashes.len(),
+                });
+            }
+            let headers =
+                FilterHeaders::from_response(start_height, block_hashes.clone(), response);
+            match &agreed {
+                Some(agreed) if *agreed != headers => {
+                    tracing::warn!(
+                        "Peers disagree on the filter headers up to {}: {} vs {}",
+                        stop_hash,
+                        agreed.stop_header(),
+                        headers.stop_header()
+                    );
+                    return Err(FilterError::HeaderMismatch(stop_hash));
+                }
+                Some(_) => {}
+                None => agreed = Some(headers),
+            }
+        }
+        Ok(agreed.expect("There is at least one peer"))
+    }
+
+    /// Download the filters of the blocks covered by `headers` and return the hashes of the
+    /// blocks matching any of the scripts. Every filter is checked against the filter headers
+    /// and must belong to the next block of the range. A peer that fails to serve them is
+    /// replaced by the next one.
+    pub async fn matching_blocks(
+        &mut self,
+        headers: &FilterHeaders,
+        scripts: &[Script],
+    ) -> Result<Vec<BlockHash>, FilterError> {
+        if headers.filter_hashes.len() > MAX_FILTERS_PER_REQUEST {
+            return Err(FilterError::RangeTooLarge {
+                blocks: headers.filter_hashes.len(),
+                limit: MAX_FILTERS_PER_REQUEST,
+            });
+        }
+        if headers.block_hashes.len() != headers.filter_hashes.len() {
+            return Err(FilterError::InvalidRange);
+        }
+        let mut last_error = None;
+        for peer in self.peers_or_err()? {
+            match matching_blocks_from(peer, headers, scripts).await {
+                Ok(matching) => return Ok(matching),
+                Err(err) => {
+                    tracing::warn!(
+                        "Failed to get filters from {}: {}",
+                        peer.peer_address(),
+                        err
+                    );
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(last_error.expect("There is at least one peer"))
+    }
+
+    /// Download the blocks, from the next peer when one fails to serve them.
+    pub async fn blocks(&mut self, block_hashes: &[BlockHash]) -> Result<Vec<Block>, FilterError> {
+        if block_hashes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut last_error = None;
+        for peer in self.peers_or_err()? {
+            match blocks_from(peer, block_hashes).await {
+                Ok(blocks) => return Ok(blocks),
+                Err(err) => {
+                    tracing::warn!("Failed to get blocks from {}: {}", peer.peer_address(), err);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(last_error.expect("There is at least one peer"))
+    }
+
+    /// Find and download the blocks with the `block_headers` from `start_height` on that are
+    /// relevant to the scripts. The range is limited to [`MAX_FILTERS_PER_REQUEST`] blocks.
+    pub async fn scan(
+        &mut self,
+        start_height: u32,
+        block_headers: &[BlockHeader],
+        scripts: &[Script],
+    ) -> Result<Vec<Block>, FilterError> {
+        let headers = self.filter_headers(start_height, block_headers).await?;
+        let matching = self.matching_blocks(&headers, scripts).await?;
+        tracing::info!(
+            "{} of {} blocks match the scripts",
+            matching.len(),
+            headers.filter_hashes.len()
+        );
+        self.blocks(&matching).await
+    }
+
+    fn peers_or_err(
+        &mut self,
+    ) -> Result<impl Iterator<Item = &mut BitcoinConnection<Connected>>, FilterError> {
+        if self.peers.is_empty() {
+            return Err(FilterError::NoPeers);
+        }
+        Ok(self.peers.iter_mut())
+    }
+}
+
+/// The hashes of the blocks matching any of the scripts, from the filters of one peer.
+async fn matching_blocks_from(
+    peer: &mut BitcoinConnection<Connected>,
+    headers: &FilterHeaders,
+    scripts: &[Script],
+) -> Result<Vec<BlockHash>, FilterError> {
+    peer.send(NetworkMessage::GetCFilters(GetCFilters {
+        filter_type: BASIC_FILTER_TYPE,
+        start_height: headers.start_height,
+        stop_hash: headers.stop_hash,
+    }))
+    .await?;
+
+    let mut matching = Vec::new();
+    for (expected_block, expected_hash) in headers.block_hashes.iter().zip(&headers.filter_hashes) {
+        let filter = receive(peer, "cfilter", |message| match message {
+            NetworkMessage::CFilter(filter) if filter.filter_type == BASIC_FILTER_TYPE => {
+                Some(filter)
+            }
+            _ => None,
+        })
+        .await?;
+        if filter.block_hash != *expected_block {
+            return Err(FilterError::UnexpectedFilter {
+                expected: *expected_block,
+                received: filter.block_hash,
+            });
+        }
+        if FilterHash::hash(&filter.filter) != *expected_hash {
+            return Err(FilterError::InvalidFilter(filter.block_hash));
+        }
+        let block_filter = BlockFilter::new(&filter.filter);
+        let mut query = scripts.iter().map(Script::as_bytes);
+        if block_filter.match_any(&filter.block_hash, &mut query)? {
+            matching.push(filter.block_hash);
+        }
+    }
+    Ok(matching)
+}
+
+/// Download the blocks from one peer.
+async fn blocks_from(
+    peer: &mut BitcoinConnection<Connected>,
+    block_hashes: &[BlockHash],
+) -> Result<Vec<Block>, FilterError> {
+    peer.send(NetworkMessage::GetData(
+        block_hashes
+            .iter()
+            .copied()
+            .map(Inventory::WitnessBlock)
+            .collect(),
+    ))
+    .await?;
+
+    let mut blocks = Vec::with_capacity(block_hashes.len());
+    while blocks.len() < block_hashes.len() {
+        let block = receive(peer, "block", |message| match message {
+            NetworkMessage::Block(block) if block_hashes.contains(&block.block_hash()) => {
+                Some(block)
+            }
+            _ => None,
+        })
+        .await?;
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return Err(FilterError::InvalidBlock(block.block_hash()));
+        }
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// The hashes of the blocks of a range, the headers must form a chain.
+fn range_block_hashes(block_headers: &[BlockHeader]) -> Result<Vec<BlockHash>, FilterError> {
+    let connected = block_headers
+        .windows(2)
+        .all(|pair| pair[1].prev_blockhash == pair[0].block_hash());
+    if block_headers.is_empty() || !connected {
+        return Err(FilterError::InvalidRange);
+    }
+    Ok(block_headers.iter().map(BlockHeader::block_hash).collect())
+}
+
+/// Wait for the first message from the peer that `select` picks, skipping the others.
+async fn receive<T>(
+    peer: &mut BitcoinConnection<Connected>,
+    response: &'static str,
+    mut select: impl FnMut(NetworkMessage) -> Option<T>,
+) -> Result<T, FilterError> {
+    let receive = async {
+        loop {
+            match peer.receive().await? {
+                FromConnectionHandle::FromBitcoinNode(message) => {
+                    if let Some(selected) = select(message) {
+                        return Ok(selected);
+                    }
+                }
+                FromConnectionHandle::Disconnected(reason) => {
+                    return Err(Error::Disconnected(reason).into());
+                }
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(RESPONSE_TIMEOUT, receive)
+        .await
+        .map_err(|_| FilterError::Timeout(response))?
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    use super::*;
+
+    fn next(previous: &BlockHeader) -> BlockHeader {
+        BlockHeader {
+            prev_blockhash: previous.block_hash(),
+            time: previous.time + 600,
+            ..*previous
+        }
+    }
+
+    #[test]
+    fn range_headers_must_form_a_chain() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let first = next(&genesis);
+        let second = next(&first);
+        assert_eq!(
+            range_block_hashes(&[genesis, first, second]).unwrap(),
+            vec![
+                genesis.block_hash(),
+                first.block_hash(),
+                second.block_hash()
+            ]
+        );
+        assert!(range_block_hashes(&[genesis, second]).is_err());
+        assert!(range_block_hashes(&[]).is_err());
+    }
+
+    #[test]
+    fn filter_headers_commit_to_the_previous_ones() {
+        let filter_hashes = vec![FilterHash::hash(&[1]), FilterHash::hash(&[2])];
+        let headers = FilterHeaders {
+            start_height: 1,
+            stop_hash: BlockHash::all_zeros(),
+            block_hashes: vec![BlockHash::all_zeros(); 2],
+            previous_header: FilterHeader::all_zeros(),
+            filter_hashes: filter_hashes.clone(),
+        };
+        let first = filter_hashes[0].filter_header(&FilterHeader::all_zeros());
+        let second = filter_hashes[1].filter_header(&first);
+        assert_eq!(headers.headers(), vec![first, second]);
+        assert_eq!(headers.stop_header(), second);
+    }
+
+    #[tokio::test]
+    async fn ranges_above_the_request_limits_are_rejected() {
+        let mut headers = vec![genesis_block(Network::Regtest).header];
+        while headers.len() <= MAX_FILTER_HEADERS_PER_REQUEST {
+            let header = next(&headers[headers.len() - 1]);
+            headers.push(header);
+        }
+        let mut client = FilterClient::new();
+        assert!(matches!(
+            client.filter_headers(0, &headers).await,
+            Err(FilterError::RangeTooLarge { blocks, limit: MAX_FILTER_HEADERS_PER_REQUEST })
+                if blocks == MAX_FILTER_HEADERS_PER_REQUEST + 1
+        ));
+
+        let filters = FilterHeaders {
+            start_height: 0,
+            stop_hash: BlockHash::all_zeros(),
+            block_hashes: vec![BlockHash::all_zeros(); MAX_FILTERS_PER_REQUEST + 1],
+            previous_header: FilterHeader::all_zeros(),
+            filter_hashes: vec![FilterHash::all_zeros(); MAX_FILTERS_PER_REQUEST + 1],
+        };
+        assert!(matches!(
+            client.matching_blocks(&filters, &[]).await,
+            Err(FilterError::RangeTooLarge {
+                limit: MAX_FILTERS_PER_REQUEST,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,339 @@
+//! BIP158 basic filters and filter headers computed from a [`ChainStore`].
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::hashes::Hash;
+use bitcoin::util::bip158::{self, BlockFilter};
+use bitcoin::{Block, BlockHash, FilterHash, FilterHeader, OutPoint, Script};
+use thiserror::Error;
+
+/// The blocks of the best chain, provided by the application (e.g. backed by a full node or a
+/// block database).
+pub trait ChainStore: Debug + Send + Sync {
+    /// Hash of the block at `height` in the best chain.
+    fn block_hash(&self, height: u32) -> Option<BlockHash>;
+
+    /// Height of the block, `None` if it is not part of the best chain.
+    fn height(&self, block_hash: &BlockHash) -> Option<u32>;
+
+    fn block(&self, block_hash: &BlockHash) -> Option<Block>;
+
+    /// The output script of a coin spent in the best chain. Needed because the basic filter
+    /// contains the scripts spent by a block.
+    fn spent_script(&self, outpoint: &OutPoint) -> Option<Script>;
+}
+
+#[derive(Debug, Error)]
+pub enum FilterIndexError {
+    #[error("Block {0} is not part of the best chain")]
+    UnknownBlock(BlockHash),
+    #[error("No block at height {0}")]
+    UnknownHeight(u32),
+    #[error("The filter header at height {0} is not indexed yet")]
+    NotIndexed(u32),
+    #[error(transparent)]
+    Filter(#[from] bip158::Error),
+}
+
+/// Computes the BIP158 basic filters and filter headers of the blocks in a [`ChainStore`].
+///
+/// Filter hashes and headers are indexed ahead of time by [`Self::sync`], requests are only
+/// answered from the indexed part of the chain. The index holds one entry per block of the best
+/// chain, entries of blocks that were reorged out are dropped by the next sync. Filters are
+/// computed on demand. The index is cheap to clone, clones share the entries.
+#[derive(Clone, Debug)]
+pub struct FilterIndex {
+    store: Arc<dyn ChainStore>,
+    entries: Arc<Mutex<Vec<Entry>>>,
+    /// Held by the running sync, there is no point in indexing the same blocks twice.
+    sync: Arc<Mutex<()>>,
+}
+
+/// The filter of the block at the height of the entry.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    block_hash: BlockHash,
+    filter_hash: FilterHash,
+    filter_header: FilterHeader,
+}
+
+impl FilterIndex {
+    pub fn new(store: Arc<dyn ChainStore>) -> Self {
+        Self {
+            store,
+            entries: Arc::new(Mutex::new(Vec::new())),
+            sync: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn store(&self) -> &dyn ChainStore {
+        self.store.as_ref()
+    }
+
+    /// The height of the block if it is part of the best chain.
+    pub fn height(&self, block_hash: &BlockHash) -> Result<u32, FilterIndexError> {
+        self.store
+            .height(block_hash)
+            .filter(|height| self.store.block_hash(*height).as_ref() == Some(block_hash))
+            .ok_or(FilterIndexError::UnknownBlock(*block_hash))
+    }
+
+    pub fn block_hash(&self, height: u32) -> Result<BlockHash, FilterIndexError> {
+        self.store
+            .block_hash(height)
+            .ok_or(FilterIndexError::UnknownHeight(height))
+    }
+
+    /// The height up to which filter headers are indexed, `None` before the first sync.
+    pub fn indexed_height(&self) -> Option<u32> {
+        let indexed = self.lock().len();
+        indexed.checked_sub(1).map(|height| height as u32)
+    }
+
+    /// Index the filters of the blocks the store added since the last sync, after dropping the
+    /// blocks that left the best chain. This computes the filter of every new block, call it
+    /// from a blocking thread (see [`Self::spawn_sync`]) when the best chain changes.
+    pub fn sync(&self) -> Result<Option<u32>, FilterIndexError> {
+        let _sync = self
+            .sync
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut previous = {
+            let mut entries = self.lock();
+            while let Some(entry) = entries.last() {
+                let height = (entries.len() - 1) as u32;
+                if self.store.block_hash(height) == Some(entry.block_hash) {
+                    break;
+                }
+                entries.pop();
+            }
+            entries.last().copied()
+        };
+        let mut height = self.indexed_height().map_or(0, |height| height + 1);
+        while let Some(block_hash) = self.store.block_hash(height) {
+            let filter_hash = FilterHash::hash(&self.filter(&block_hash)?.content);
+            let previous_header =
+                previous.map_or(FilterHeader::all_zeros(), |entry| entry.filter_header);
+            let entry = Entry {
+                block_hash,
+                filter_hash,
+                filter_header: filter_hash.filter_header(&previous_header),
+            };
+            self.lock().push(entry);
+            previous = Some(entry);
+            height += 1;
+        }
+        Ok(self.indexed_height())
+    }
+
+    /// Run [`Self::sync`] on the blocking thread pool, unless a sync is running already.
+    pub fn spawn_sync(&self) {
+        if self.sync.try_lock().is_err() {
+            return;
+        }
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || match index.sync() {
+            Ok(height) => tracing::debug!("Indexed filters up to height {:?}", height),
+            Err(err) => tracing::warn!("Failed to index filters: {}", err),
+        });
+    }
+
+    pub fn filter(&self, block_hash: &BlockHash) -> Result<BlockFilter, FilterIndexError> {
+        let block = self
+            .store
+            .block(block_hash)
+            .ok_or(FilterIndexError::UnknownBlock(*block_hash))?;
+        let filter = BlockFilter::new_script_filter(&block, |outpoint| {
+            self.store
+                .spent_script(outpoint)
+                .ok_or(bip158::Error::UtxoMissing(*outpoint))
+        })?;
+        Ok(filter)
+    }
+
+    /// The hash of the block the entry at `height` was indexed for. Differs from the best chain
+    /// of the store after a reorg until the next sync.
+    pub fn indexed_block_hash(&self, height: u32) -> Result<BlockHash, FilterIndexError> {
+        Ok(self.entry(height)?.block_hash)
+    }
+
+    /// The indexed filter hash of the block at `height`.
+    pub fn filter_hash(&self, height: u32) -> Result<FilterHash, FilterIndexError> {
+        Ok(self.entry(height)?.filter_hash)
+    }
+
+    /// The indexed filter header at `height`.
+    pub fn filter_header(&self, height: u32) -> Result<FilterHeader, FilterIndexError> {
+        Ok(self.entry(height)?.filter_header)
+    }
+
+    fn entry(&self, height: u32) -> Result<Entry, FilterIndexError> {
+        self.lock()
+            .get(height as usize)
+            .copied()
+            .ok_or(FilterIndexError::NotIndexed(height))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Entry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::network::constants::Network;
+    use bitcoin::{PackedLockTime, Sequence, Transaction, TxIn, TxOut, Witness};
+
+    use super::*;
+
+    /// A chain of coinbase-only blocks, coinbases spend nothing.
+    #[derive(Debug)]
+    struct Chain(Mutex<Vec<Block>>);
+
+    impl ChainStore for Chain {
+        fn block_hash(&self, height: u32) -> Option<BlockHash> {
+            let blocks = self.0.lock().unwrap();
+            blocks.get(height as usize).map(|block| block.block_hash())
+        }
+
+        fn height(&self, block_hash: &BlockHash) -> Option<u32> {
+            let blocks = self.0.lock().unwrap();
+            let height = blocks
+                .iter()
+                .position(|block| block.block_hash() == *block_hash)?;
+            Some(height as u32)
+        }
+
+        fn block(&self, block_hash: &BlockHash) -> Option<Block> {
+            let blocks = self.0.lock().unwrap();
+            blocks
+                .iter()
+                .find(|block| block.block_hash() == *block_hash)
+                .cloned()
+        }
+
+        fn spent_script(&self, _: &OutPoint) -> Option<Script> {
+            None
+        }
+    }
+
+    fn block_on(previous: &Block, tag: u8) -> Block {
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_slice(&[tag]).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Builder::new().push_slice(&[tag; 20]).into_script(),
+            }],
+        };
+        let mut block = previous.clone();
+        block.header.prev_blockhash = previous.block_hash();
+        block.txdata = vec![coinbase];
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
+    }
+
+    fn index(blocks: Vec<Block>) -> (Arc<Chain>, FilterIndex) {
+        let chain = Arc::new(Chain(Mutex::new(blocks)));
+        (chain.clone(), FilterIndex::new(chain))
+    }
+
+    #[test]
+    fn genesis_filter_matches_bip158() {
+        // The first test vector of BIP158, the testnet genesis block
+        let genesis = genesis_block(Network::Testnet);
+        let (_, index) = index(vec![genesis.clone()]);
+        assert_eq!(index.sync().unwrap(), Some(0));
+
+        let filter = index.filter(&genesis.block_hash()).unwrap();
+        assert_eq!(filter.content, vec![0x01, 0x9d, 0xfc, 0xa8]);
+        let expected = "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750";
+        assert_eq!(
+            index.filter_header(0).unwrap(),
+            FilterHeader::from_str(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn filters_match_the_output_scripts_of_the_block() {
+        let genesis = genesis_block(Network::Regtest);
+        let block = block_on(&genesis, 1);
+        let (_, index) = index(vec![genesis, block.clone()]);
+        index.sync().unwrap();
+
+        let filter = index.filter(&block.block_hash()).unwrap();
+        let script = &block.txdata[0].output[0].script_pubkey;
+        let other = Builder::new().push_slice(&[2; 20]).into_script();
+        let matches = |script: &Script| {
+            filter
+                .match_any(&block.block_hash(), &mut std::iter::once(script.as_bytes()))
+                .unwrap()
+        };
+        assert!(matches(script));
+        assert!(!matches(&other));
+    }
+
+    #[test]
+    fn only_indexed_heights_are_answered() {
+        let genesis = genesis_block(Network::Regtest);
+        let first = block_on(&genesis, 1);
+        let (chain, index) = index(vec![genesis, first.clone()]);
+        assert_eq!(index.indexed_height(), None);
+        assert!(matches!(
+            index.filter_header(0),
+            Err(FilterIndexError::NotIndexed(0))
+        ));
+
+        index.sync().unwrap();
+        chain.0.lock().unwrap().push(block_on(&first, 2));
+        assert_eq!(index.indexed_height(), Some(1));
+        assert!(matches!(
+            index.filter_hash(2),
+            Err(FilterIndexError::NotIndexed(2))
+        ));
+        assert_eq!(index.sync().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn headers_chain_and_follow_reorgs() {
+        let genesis = genesis_block(Network::Regtest);
+        let first = block_on(&genesis, 1);
+        let second = block_on(&first, 2);
+        let (chain, index) = index(vec![genesis, first.clone(), second.clone()]);
+        index.sync().unwrap();
+        for height in 1..=2 {
+            let previous = index.filter_header(height - 1).unwrap();
+            let filter_hash = index.filter_hash(height).unwrap();
+            assert_eq!(
+                index.filter_header(height).unwrap(),
+                filter_hash.filter_header(&previous)
+            );
+        }
+
+        let replaced = index.filter_header(2).unwrap();
+        let first_header = index.filter_header(1).unwrap();
+        let third = block_on(&first, 3);
+        chain.0.lock().unwrap()[2] = third.clone();
+        // Until the next sync the entries still describe the old chain
+        assert_eq!(index.indexed_block_hash(2).unwrap(), second.block_hash());
+        assert_eq!(index.sync().unwrap(), Some(2));
+        assert_eq!(index.indexed_block_hash(2).unwrap(), third.block_hash());
+        assert_eq!(index.filter_header(1).unwrap(), first_header);
+        assert_ne!(index.filter_header(2).unwrap(), replaced);
+
+        chain.0.lock().unwrap().truncate(2);
+        assert_eq!(index.sync().unwrap(), Some(1));
+    }
+}
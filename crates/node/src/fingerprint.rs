@@ -0,0 +1,171 @@
+//! Capability report of a peer, built from the messages it sends.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use serde::Serialize;
+
+use crate::connection::{BitcoinConnection, Connected};
+use crate::FromConnectionHandle;
+
+/// Services with their names, in bit order. rust-bitcoin does not know `NODE_P2P_V2` (BIP324).
+const KNOWN_SERVICES: [(&str, u64); 7] = [
+    ("NETWORK", 1 << 0),
+    ("GETUTXO", 1 << 1),
+    ("BLOOM", 1 << 2),
+    ("WITNESS", 1 << 3),
+    ("COMPACT_FILTERS", 1 << 6),
+    ("NETWORK_LIMITED", 1 << 10),
+    ("P2P_V2", 1 << 11),
+];
+
+/// Everything we learned about a peer, beyond the handshake.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct PeerReport {
+    pub address: SocketAddr,
+    pub protocol_version: u32,
+    pub services: u64,
+    /// The advertised services decoded into names, unknown bits as `UNKNOWN_<bit>`.
+    pub service_names: Vec<String>,
+    pub user_agent: String,
+    pub user_agent_components: Vec<UserAgentComponent>,
+    pub features: FeatureMessages,
+    pub best_height: i32,
+    /// How many seconds the clock of the peer is ahead of ours.
+    pub clock_skew_secs: Option<i64>,
+}
+
+impl PeerReport {
+    /// The implementation is the last component of the user agent, earlier components are the
+    /// libraries it is built on (BIP14), e.g. `Knots` in `/Satoshi:25.1.0/Knots:20231115/`.
+    pub fn implementation(&self) -> Option<&UserAgentComponent> {
+        self.user_agent_components.last()
+    }
+}
+
+/// One `name:version(comments)` part of a BIP14 user agent.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct UserAgentComponent {
+    pub name: String,
+    pub version: Option<String>,
+    pub comments: Vec<String>,
+}
+
+/// The optional feature negotiation messages the peer sent.
+#[derive(Clone, Eq, PartialEq, Debug, Default, Serialize)]
+pub struct FeatureMessages {
+    pub send_headers: bool,
+    /// Highest announced compact block version and whether high bandwidth mode was requested.
+    pub send_compact: Option<SendCompact>,
+    pub wtxid_relay: bool,
+    pub send_addr_v2: bool,
+    /// Minimum fee rate of the transactions the peer wants to hear about, in sat/kvB.
+    pub fee_filter: Option<i64>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct SendCompact {
+    pub version: u64,
+    pub high_bandwidth: bool,
+}
+
+impl FeatureMessages {
+    /// Record the message if it is a feature negotiation message.
+    pub fn observe(&mut self, message: &FromConnectionHandle) {
+        let FromConnectionHandle::FromBitcoinNode(message) = message else {
+            return;
+        };
+        match message {
+            NetworkMessage::SendHeaders => self.send_headers = true,
+            NetworkMessage::SendCmpct(send_compact) => {
+                let highest = self.send_compact.map_or(0, |existing| existing.version);
+                if send_compact.version >= highest {
+                    self.send_compact = Some(SendCompact {
+                        version: send_compact.version,
+                        high_bandwidth: send_compact.send_compact,
+                    });
+                }
+            }
+            NetworkMessage::WtxidRelay => self.wtxid_relay = true,
+            NetworkMessage::SendAddrV2 => self.send_addr_v2 = true,
+            NetworkMessage::FeeFilter(fee_rate) => self.fee_filter = Some(*fee_rate),
+            _ => {}
+        }
+    }
+}
+
+/// Receive messages from the peer for `observe_for` and report its capabilities. Peers send
+/// the feature messages right after the handshake, a few seconds are usually enough.
+pub async fn probe(node: &mut BitcoinConnection<Connected>, observe_for: Duration) -> PeerReport {
+    let mut features = FeatureMessages::default();
+    let observe = async {
+        while let Ok(message) = node.receive().await {
+            features.observe(&message);
+        }
+    };
+    let _ = tokio::time::timeout(observe_for, observe).await;
+
+    let version = node.peer_version();
+    PeerReport {
+        address: node.peer_address(),
+        protocol_version: version.version,
+        services: version.services.to_u64(),
+        service_names: service_names(version.services),
+        user_agent: version.user_agent.clone(),
+        user_agent_components: parse_user_agent(&version.user_agent),
+        features,
+        best_height: version.start_height,
+        clock_skew_secs: node.peer_clock_skew().map(|skew| skew.num_seconds()),
+    }
+}
+
+pub fn service_names(services: ServiceFlags) -> Vec<String> {
+    let bits = services.to_u64();
+    let mut names: Vec<String> = KNOWN_SERVICES
+        .iter()
+        .filter(|(_, flag)| bits & flag != 0)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let known = KNOWN_SERVICES
+        .iter()
+        .fold(0, |known, (_, flag)| known | flag);
+    names.extend(
+        (0..64)
+            .filter(|bit| bits & !known & (1 << bit) != 0)
+            .map(|bit| format!("UNKNOWN_{bit}")),
+    );
+    names
+}
+
+/// Parse a BIP14 user agent like `/Satoshi:25.0.0(comment; comment)/`. Agents that do not
+/// follow the format are returned as a single component without a version.
+pub fn parse_user_agent(user_agent: &str) -> Vec<UserAgentComponent> {
+    user_agent
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| {
+            let (component, comments) = match component.split_once('(') {
+                Some((component, comments)) => (
+                    component,
+                    comments
+                        .trim_end_matches(')')
+                        .split(';')
+                        .map(|comment| comment.trim().to_string())
+                        .filter(|comment| !comment.is_empty())
+                        .collect(),
+                ),
+                None => (component, Vec::new()),
+            };
+            let (name, version) = match component.split_once(':') {
+                Some((name, version)) => (name, Some(version.to_string())),
+                None => (component, None),
+            };
+            UserAgentComponent {
+                name: name.to_string(),
+                version,
+                comments,
+            }
+        })
+        .collect()
+}
@@ -1,6 +1,44 @@
-///! A simple library that allows to instantiate a new connection to a bitcoin node.
+//! A simple library that allows to instantiate a new connection to a bitcoin node.
+mod ban_list;
+mod bloom;
 mod connection;
+mod crawler;
 mod error;
+mod filter_client;
+mod filter_index;
+mod fingerprint;
+mod mempool;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod minisketch;
+mod network_time;
+mod peer_manager;
+mod supervisor;
 
+pub use ban_list::{BanEntry, BanList, BanListError, Subnet};
 pub use bitcoin::network;
-pub use connection::{BitcoinConnector, FromConnectionHandle};
+pub use bloom::BloomFilter;
+pub use connection::{
+    AnyMessage, BitcoinConnection, BitcoinConnector, BitcoinListener, CommandStats,
+    CompactBlockStats, Connected, ConnectionStats, CustomMessage, DisconnectReason, FrameError,
+    FromConnectionHandle, HandlerAction, HandlerContext, MessageHandler, MessageRegistry,
+    Misbehavior, NewTip, PreHandshake, Priority, QueueDepth, StatsRecorder, VersionBuilder,
+    OTHER_COMMAND,
+};
+pub use crawler::{Crawler, NodeRecord, RecordWriter};
+pub use error::Error;
+pub use filter_client::{
+    FilterClient, FilterError, FilterHeaders, BASIC_FILTER_TYPE, MAX_FILTERS_PER_REQUEST,
+    MAX_FILTER_HEADERS_PER_REQUEST,
+};
+pub use filter_index::{ChainStore, FilterIndex, FilterIndexError};
+pub use fingerprint::{
+    parse_user_agent, probe, service_names, FeatureMessages, PeerReport, SendCompact,
+    UserAgentComponent,
+};
+pub use mempool::Mempool;
+#[cfg(feature = "prometheus")]
+pub use metrics::MetricsRegistry;
+pub use network_time::NetworkTime;
+pub use peer_manager::{PeerManager, StartedPeers};
+pub use supervisor::SupervisedConnection;
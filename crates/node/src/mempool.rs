@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::{Transaction, Txid, Wtxid};
+
+/// The default number of transactions kept before the oldest ones are evicted.
+const DEFAULT_CAPACITY: usize = 50_000;
+
+/// A minimal, shared pool of unconfirmed transactions.
+///
+/// It is not a validating mempool, it only remembers the transactions relayed by the peers so
+/// that compact blocks can be reconstructed without downloading every transaction again.
+/// Clones share the same transactions.
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    transactions: HashMap<Wtxid, Transaction>,
+    /// The wtxids of the transactions by txid, to answer requests by txid.
+    txids: HashMap<Txid, Wtxid>,
+    /// Insertion order, used to evict the oldest transactions first.
+    order: VecDeque<Wtxid>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                transactions: HashMap::new(),
+                txids: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn insert(&self, transaction: Transaction) {
+        let wtxid = transaction.wtxid();
+        let txid = transaction.txid();
+        let mut inner = self.lock();
+        if inner.transactions.insert(wtxid, transaction).is_some() {
+            return;
+        }
+        inner.txids.insert(txid, wtxid);
+        inner.order.push_back(wtxid);
+        while inner.order.len() > inner.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget the transactions, e.g. because they were confirmed in a block.
+    pub fn remove_all<'a>(&self, transactions: impl IntoIterator<Item = &'a Transaction>) {
+        let mut inner = self.lock();
+        for transaction in transactions {
+            inner.remove(&transaction.wtxid());
+        }
+        let Inner {
+            transactions,
+            order,
+            ..
+        } = &mut *inner;
+        order.retain(|wtxid| transactions.contains_key(wtxid));
+    }
+
+    pub fn get(&self, wtxid: &Wtxid) -> Option<Transaction> {
+        self.lock().transactions.get(wtxid).cloned()
+    }
+
+    pub fn get_by_txid(&self, txid: &Txid) -> Option<Transaction> {
+        let inner = self.lock();
+        let wtxid = inner.txids.get(txid)?;
+        inner.transactions.get(wtxid).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run `f` over all transactions without cloning them.
+    pub fn for_each(&self, mut f: impl FnMut(&Wtxid, &Transaction)) {
+        for (wtxid, transaction) in &self.lock().transactions {
+            f(wtxid, transaction);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, wtxid: &Wtxid) {
+        if let Some(transaction) = self.transactions.remove(wtxid) {
+            self.txids.remove(&transaction.txid());
+        }
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, TxIn, Witness};
+
+    use super::*;
+
+    fn transaction(n: u8) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([n; 32]), 0),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::default(),
+            }],
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn transactions_are_found_by_txid_and_wtxid() {
+        let mempool = Mempool::new();
+        let transaction = transaction(1);
+        mempool.insert(transaction.clone());
+        assert_eq!(mempool.get(&transaction.wtxid()), Some(transaction.clone()));
+        assert_eq!(
+            mempool.get_by_txid(&transaction.txid()),
+            Some(transaction.clone())
+        );
+
+        mempool.remove_all([&transaction]);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.get_by_txid(&transaction.txid()), None);
+    }
+
+    #[test]
+    fn the_oldest_transactions_are_evicted() {
+        let mempool = Mempool::with_capacity(2);
+        (1..=3).for_each(|n| mempool.insert(transaction(n)));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.get_by_txid(&transaction(1).txid()), None);
+        assert!(mempool.get_by_txid(&transaction(3).txid()).is_some());
+    }
+}
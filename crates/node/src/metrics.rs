@@ -0,0 +1,310 @@
+//! Prometheus text format exporter for the connection statistics.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::connection::{CommandStats, CompactBlockStats, ConnectionStats, StatsRecorder};
+
+/// Aggregates the statistics of all registered connections.
+///
+/// The counters of closed connections are folded into retired totals, so the exported
+/// counters never go down.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsRegistry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    connections: Vec<Arc<Mutex<ConnectionStats>>>,
+    /// Counters of the closed connections.
+    retired: Totals,
+}
+
+/// The counters summed over connections.
+#[derive(Clone, Debug, Default)]
+struct Totals {
+    commands: BTreeMap<String, CommandStats>,
+    decode_errors: u64,
+    compact_blocks: CompactBlockStats,
+}
+
+impl Totals {
+    fn add(&mut self, stats: &ConnectionStats) {
+        for (command, stats) in &stats.commands {
+            let total = self.commands.entry(command.clone()).or_default();
+            total.messages_in += stats.messages_in;
+            total.bytes_in += stats.bytes_in;
+            total.messages_out += stats.messages_out;
+            total.bytes_out += stats.bytes_out;
+        }
+        self.decode_errors += stats.decode_errors;
+        let total = &mut self.compact_blocks;
+        let stats = stats.compact_blocks;
+        total.received += stats.received;
+        total.reconstructed += stats.reconstructed;
+        total.reconstructed_after_round_trip += stats.reconstructed_after_round_trip;
+        total.failed += stats.failed;
+        total.mempool_hits += stats.mempool_hits;
+        total.mempool_misses += stats.mempool_misses;
+    }
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, stats: &StatsRecorder) {
+        self.lock().connections.push(stats.shared());
+    }
+
+    /// Render the metrics in the Prometheus text format: gauges of the open connections and
+    /// counters of all connections ever registered.
+    pub fn render(&self) -> String {
+        let (snapshots, mut totals) = self.snapshots();
+        snapshots.iter().for_each(|snapshot| totals.add(snapshot));
+        let commands = &totals.commands;
+
+        let mut out = String::new();
+        header(
+            &mut out,
+            "bitcoin_connections",
+            "gauge",
+            "Open peer connections.",
+        );
+        let _ = writeln!(out, "bitcoin_connections {}", snapshots.len());
+
+        header(
+            &mut out,
+            "bitcoin_messages_total",
+            "counter",
+            "Messages exchanged with peers, by command and direction.",
+        );
+        for (command, stats) in commands {
+            let _ = writeln!(
+                out,
+                "bitcoin_messages_total{{command=\"{}\",direction=\"in\"}} {}",
+                escape(command),
+                stats.messages_in
+            );
+            let _ = writeln!(
+                out,
+                "bitcoin_messages_total{{command=\"{}\",direction=\"out\"}} {}",
+                escape(command),
+                stats.messages_out
+            );
+        }
+
+        header(
+            &mut out,
+            "bitcoin_bytes_total",
+            "counter",
+            "Bytes exchanged with peers, by command and direction.",
+        );
+        for (command, stats) in commands {
+            let _ = writeln!(
+                out,
+                "bitcoin_bytes_total{{command=\"{}\",direction=\"in\"}} {}",
+                escape(command),
+                stats.bytes_in
+            );
+            let _ = writeln!(
+                out,
+                "bitcoin_bytes_total{{command=\"{}\",direction=\"out\"}} {}",
+                escape(command),
+                stats.bytes_out
+            );
+        }
+
+        header(
+            &mut out,
+            "bitcoin_decode_errors_total",
+            "counter",
+            "Frames received from peers that could not be decoded.",
+        );
+        let _ = writeln!(out, "bitcoin_decode_errors_total {}", totals.decode_errors);
+
+        header(
+            &mut out,
+            "bitcoin_ping_latency_seconds",
+            "gauge",
+            "Round trip time of the last answered ping, by peer.",
+        );
+        for snapshot in &snapshots {
+            if let Some(latency) = snapshot.ping_latency {
+                let _ = writeln!(
+                    out,
+                    "bitcoin_ping_latency_seconds{{peer=\"{}\"}} {}",
+                    escape(&snapshot.peer_address.to_string()),
+                    latency.as_secs_f64()
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "bitcoin_compact_blocks_total",
+            "counter",
+            "Compact blocks received from peers, by reconstruction outcome.",
+        );
+        let compact_blocks = totals.compact_blocks;
+        for (outcome, count) in [
+            ("mempool", compact_blocks.reconstructed),
+            ("round_trip", compact_blocks.reconstructed_after_round_trip),
+            ("failed", compact_blocks.failed),
+        ] {
+            let _ = writeln!(
+                out,
+                "bitcoin_compact_blocks_total{{outcome=\"{outcome}\"}} {count}"
+            );
+        }
+
+        header(
+            &mut out,
+            "bitcoin_compact_block_transactions_total",
+            "counter",
+            "Transactions of compact blocks, by whether they were found in the mempool.",
+        );
+        let _ = writeln!(
+            out,
+            "bitcoin_compact_block_transactions_total{{source=\"mempool\"}} {}",
+            compact_blocks.mempool_hits
+        );
+        let _ = writeln!(
+            out,
+            "bitcoin_compact_block_transactions_total{{source=\"peer\"}} {}",
+            compact_blocks.mempool_misses
+        );
+        out
+    }
+
+    /// Serve the metrics over HTTP, every request gets the rendered metrics as response.
+    pub async fn serve(self, address: SocketAddr) -> std::io::Result<()> {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        tracing::info!("Serving metrics on {}", listener.local_addr()?);
+        loop {
+            let (mut socket, _) = listener.accept().await?;
+            let registry = self.clone();
+            tokio::spawn(async move {
+                // The request itself is irrelevant, there is only one thing to serve
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+                let body = registry.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if let Err(err) = socket.write_all(response.as_bytes()).await {
+                    tracing::debug!("Failed to serve metrics: {:?}", err);
+                }
+            });
+        }
+    }
+
+    /// Snapshots of the open connections and the totals of the closed ones. Connections that
+    /// are only referenced by the registry are closed, their counters are retired.
+    fn snapshots(&self) -> (Vec<ConnectionStats>, Totals) {
+        let mut inner = self.lock();
+        let Inner {
+            connections,
+            retired,
+        } = &mut *inner;
+        let mut snapshots = Vec::with_capacity(connections.len());
+        connections.retain(|stats| {
+            // Checked first, nobody records into a closed connection anymore
+            let closed = Arc::strong_count(stats) == 1;
+            let snapshot = stats
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone();
+            if closed {
+                retired.add(&snapshot);
+            } else {
+                snapshots.push(snapshot);
+            }
+            !closed
+        });
+        (snapshots, retired.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value of the text format.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value<'a>(metrics: &'a str, series: &str) -> Option<&'a str> {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+    }
+
+    #[test]
+    fn counters_of_closed_connections_are_kept() {
+        let registry = MetricsRegistry::new();
+        let stats = StatsRecorder::new("127.0.0.1:8333".parse().unwrap());
+        registry.register(&stats);
+        stats.record_inbound("ping", 32);
+        stats.record_decode_error();
+        let metrics = registry.render();
+        assert_eq!(value(&metrics, "bitcoin_connections"), Some("1"));
+
+        stats.record_inbound("ping", 32);
+        drop(stats);
+        let metrics = registry.render();
+        assert_eq!(value(&metrics, "bitcoin_connections"), Some("0"));
+        let series = "bitcoin_messages_total{command=\"ping\",direction=\"in\"}";
+        assert_eq!(value(&metrics, series), Some("2"));
+        let series = "bitcoin_bytes_total{command=\"ping\",direction=\"in\"}";
+        assert_eq!(value(&metrics, series), Some("64"));
+        assert_eq!(value(&metrics, "bitcoin_decode_errors_total"), Some("1"));
+    }
+
+    #[test]
+    fn unknown_commands_are_counted_as_other() {
+        let registry = MetricsRegistry::new();
+        let stats = StatsRecorder::new("127.0.0.1:8333".parse().unwrap());
+        registry.register(&stats);
+        stats.record_inbound("x\"} 1\nevil", 10);
+        stats.record_inbound("made-up", 10);
+        let metrics = registry.render();
+        let series = "bitcoin_messages_total{command=\"other\",direction=\"in\"}";
+        assert_eq!(value(&metrics, series), Some("2"));
+        assert!(!metrics.contains("evil"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+        assert_eq!(escape("[::1]:8333"), "[::1]:8333");
+    }
+}
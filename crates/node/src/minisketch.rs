@@ -0,0 +1,365 @@
+//! PinSketch set reconciliation over GF(2^32), compatible with the serialization of
+//! minisketch as used by BIP330.
+//!
+//! A sketch of capacity `c` holds the odd power sums `x`, `x^3`, .., `x^(2c-1)` of its
+//! elements. Merging two sketches leaves the sketch of the symmetric difference of their
+//! sets, which can be decoded as long as it has at most `c` elements.
+use rand::Rng;
+
+/// Bits of the field elements.
+const BITS: usize = 32;
+/// The field is GF(2)[x] / (x^32 + x^7 + x^3 + x^2 + 1), the low terms of the modulus.
+const MODULUS: u32 = 0x8d;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Minisketch {
+    /// The odd power sums, `syndromes[i]` is the sum of `x^(2i+1)`.
+    syndromes: Vec<u32>,
+}
+
+impl Minisketch {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            syndromes: vec![0; capacity],
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.syndromes.len()
+    }
+
+    /// Add an element, adding it again removes it. Zero can't be added.
+    pub(crate) fn add(&mut self, element: u32) {
+        debug_assert_ne!(element, 0, "0 is not a valid sketch element");
+        let square = mul(element, element);
+        let mut power = element;
+        for syndrome in &mut self.syndromes {
+            *syndrome ^= power;
+            power = mul(power, square);
+        }
+    }
+
+    /// Merge the sketch of another set, both sketches must have the same capacity.
+    pub(crate) fn merge(&mut self, other: &Minisketch) {
+        for (syndrome, other) in self.syndromes.iter_mut().zip(&other.syndromes) {
+            *syndrome ^= other;
+        }
+    }
+
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        self.syndromes
+            .iter()
+            .flat_map(|syndrome| syndrome.to_le_bytes())
+            .collect()
+    }
+
+    /// Read a serialized sketch, its capacity follows from the length.
+    pub(crate) fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let chunks = bytes.chunks_exact(BITS / 8);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        let syndromes = chunks
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        Some(Self { syndromes })
+    }
+
+    /// The elements of the set, `None` when the set is larger than the capacity.
+    pub(crate) fn decode(&self) -> Option<Vec<u32>> {
+        if self.syndromes.iter().all(|syndrome| *syndrome == 0) {
+            return Some(Vec::new());
+        }
+        // The even power sums follow from the odd ones: s(2i) = s(i)^2
+        let mut power_sums = vec![0; 2 * self.capacity()];
+        for i in 0..power_sums.len() {
+            let power = i + 1;
+            power_sums[i] = if power % 2 == 1 {
+                self.syndromes[i / 2]
+            } else {
+                let half = power_sums[power / 2 - 1];
+                mul(half, half)
+            };
+        }
+
+        // The roots of the reversed locator polynomial are the elements
+        let mut locator = berlekamp_massey(&power_sums);
+        let count = locator.len() - 1;
+        if count > self.capacity() {
+            return None;
+        }
+        locator.reverse();
+        let elements = find_roots(&locator)?;
+
+        // More elements than the capacity can decode to a wrong set, check that it adds up
+        let mut check = Minisketch::new(self.capacity());
+        elements.iter().for_each(|element| check.add(*element));
+        (check == *self).then_some(elements)
+    }
+}
+
+fn mul(mut a: u32, mut b: u32) -> u32 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 == 1 {
+            product ^= a;
+        }
+        b >>= 1;
+        a = (a << 1) ^ if a >> (BITS - 1) == 1 { MODULUS } else { 0 };
+    }
+    product
+}
+
+/// The inverse of a non-zero element: a^(2^32 - 2).
+fn inverse(a: u32) -> u32 {
+    let mut result = 1;
+    let mut square = a;
+    let mut exponent = u32::MAX - 1;
+    while exponent != 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, square);
+        }
+        square = mul(square, square);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// The shortest linear recurrence of the power sums, lowest coefficient first.
+fn berlekamp_massey(power_sums: &[u32]) -> Vec<u32> {
+    let mut current = vec![1];
+    let mut previous = vec![1];
+    let mut length = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+    for n in 0..power_sums.len() {
+        let discrepancy = (1..=length).fold(power_sums[n], |sum, i| {
+            sum ^ mul(current.get(i).copied().unwrap_or(0), power_sums[n - i])
+        });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let factor = mul(discrepancy, inverse(previous_discrepancy));
+        let updated = add_scaled(&current, &previous, factor, shift);
+        if 2 * length <= n {
+            previous = std::mem::replace(&mut current, updated);
+            length = n + 1 - length;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            current = updated;
+            shift += 1;
+        }
+    }
+    current.resize(length + 1, 0);
+    current
+}
+
+/// `a + factor * x^shift * b`
+fn add_scaled(a: &[u32], b: &[u32], factor: u32, shift: usize) -> Vec<u32> {
+    let mut sum = a.to_vec();
+    sum.resize(sum.len().max(b.len() + shift), 0);
+    for (i, coefficient) in b.iter().enumerate() {
+        sum[i + shift] ^= mul(factor, *coefficient);
+    }
+    sum
+}
+
+/// The roots of a polynomial (lowest coefficient first), `None` unless it has as many
+/// distinct non-zero roots as its degree.
+fn find_roots(polynomial: &[u32]) -> Option<Vec<u32>> {
+    let polynomial = monic(trimmed(polynomial.to_vec()));
+    if polynomial.len() < 2 || polynomial[0] == 0 {
+        return None;
+    }
+    // All roots are distinct and in the field exactly when the polynomial divides x^(2^32) - x
+    let mut frobenius = vec![0, 1];
+    for _ in 0..BITS {
+        frobenius = mul_mod(&frobenius, &frobenius, &polynomial);
+    }
+    let x = modulo(&[0, 1], &polynomial);
+    if !trimmed(add_scaled(&frobenius, &x, 1, 0)).is_empty() {
+        return None;
+    }
+    let mut roots = Vec::with_capacity(polynomial.len() - 1);
+    split(polynomial, &mut rand::thread_rng(), &mut roots);
+    Some(roots)
+}
+
+/// Split a polynomial with distinct roots in the field by the trace of a random multiple of x
+/// (Berlekamp's trace algorithm).
+fn split(polynomial: Vec<u32>, rng: &mut impl Rng, roots: &mut Vec<u32>) {
+    if polynomial.len() == 2 {
+        // x + a, the root is a
+        roots.push(polynomial[0]);
+        return;
+    }
+    loop {
+        let factor = rng.gen::<u32>().max(1);
+        let mut power = modulo(&[0, factor], &polynomial);
+        let mut trace = power.clone();
+        for _ in 1..BITS {
+            power = mul_mod(&power, &power, &polynomial);
+            trace = add_scaled(&trace, &power, 1, 0);
+        }
+        let divisor = monic(gcd(polynomial.clone(), trimmed(trace)));
+        if divisor.len() > 1 && divisor.len() < polynomial.len() {
+            let quotient = divide(&polynomial, &divisor);
+            split(divisor, rng, roots);
+            split(quotient, rng, roots);
+            return;
+        }
+    }
+}
+
+fn trimmed(mut polynomial: Vec<u32>) -> Vec<u32> {
+    while polynomial.last() == Some(&0) {
+        polynomial.pop();
+    }
+    polynomial
+}
+
+fn monic(polynomial: Vec<u32>) -> Vec<u32> {
+    let Some(&leading) = polynomial.last() else {
+        return polynomial;
+    };
+    let scale = inverse(leading);
+    polynomial
+        .into_iter()
+        .map(|coefficient| mul(coefficient, scale))
+        .collect()
+}
+
+fn mul_mod(a: &[u32], b: &[u32], modulus: &[u32]) -> Vec<u32> {
+    let mut product = vec![0; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            product[i + j] ^= mul(*x, *y);
+        }
+    }
+    modulo(&product, modulus)
+}
+
+/// The remainder of the division by a monic polynomial.
+fn modulo(a: &[u32], modulus: &[u32]) -> Vec<u32> {
+    let mut remainder = trimmed(a.to_vec());
+    let degree = modulus.len() - 1;
+    while remainder.len() > degree {
+        let leading = remainder.pop().unwrap_or(0);
+        let shift = remainder.len() - degree;
+        for (i, coefficient) in modulus[..degree].iter().enumerate() {
+            remainder[i + shift] ^= mul(leading, *coefficient);
+        }
+        remainder = trimmed(remainder);
+    }
+    remainder
+}
+
+/// The quotient of the exact division by a monic polynomial.
+fn divide(a: &[u32], divisor: &[u32]) -> Vec<u32> {
+    let degree = divisor.len() - 1;
+    let mut remainder = a.to_vec();
+    let mut quotient = vec![0; a.len() - degree];
+    for i in (0..quotient.len()).rev() {
+        let leading = remainder[i + degree];
+        quotient[i] = leading;
+        for (j, coefficient) in divisor.iter().enumerate() {
+            remainder[i + j] ^= mul(leading, *coefficient);
+        }
+    }
+    quotient
+}
+
+fn gcd(mut a: Vec<u32>, mut b: Vec<u32>) -> Vec<u32> {
+    while !b.is_empty() {
+        let remainder = modulo(&a, &monic(b.clone()));
+        a = b;
+        b = remainder;
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(capacity: usize, elements: &[u32]) -> Minisketch {
+        let mut sketch = Minisketch::new(capacity);
+        elements.iter().for_each(|element| sketch.add(*element));
+        sketch
+    }
+
+    fn sorted(mut elements: Vec<u32>) -> Vec<u32> {
+        elements.sort_unstable();
+        elements
+    }
+
+    #[test]
+    fn serialization_matches_minisketch() {
+        // Odd power sums over GF(2^32) mod x^32 + x^7 + x^3 + x^2 + 1, 4 bytes little endian
+        // each, as serialized by minisketch for 32 bit elements
+        let vectors: [(usize, &[u32], &str); 2] = [
+            (4, &[1, 2, 3], "0000000006000000120000007e000000"),
+            (3, &[0x8000_0000, 0xdead_beef], "efbead5e0768cfa43cb18ce4"),
+        ];
+        for (capacity, elements, expected) in vectors {
+            let serialized = sketch(capacity, elements).serialize();
+            let hex: String = serialized
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            assert_eq!(hex, expected);
+            assert_eq!(
+                Minisketch::deserialize(&serialized),
+                Some(sketch(capacity, elements))
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_rejects_partial_elements() {
+        assert_eq!(Minisketch::deserialize(&[0; 7]), None);
+        assert_eq!(
+            Minisketch::deserialize(&[]).map(|sketch| sketch.capacity()),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn adding_twice_removes_the_element() {
+        let mut twice = sketch(8, &[5, 6, 7]);
+        twice.add(6);
+        assert_eq!(twice, sketch(8, &[5, 7]));
+    }
+
+    #[test]
+    fn decode_returns_the_elements() {
+        let elements = vec![1, 2, 3, 0x8000_0000, 0xdead_beef, u32::MAX];
+        let decoded = sketch(elements.len(), &elements).decode();
+        assert_eq!(decoded.map(sorted), Some(sorted(elements)));
+        assert_eq!(sketch(4, &[]).decode(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn merge_leaves_the_symmetric_difference() {
+        let mut ours = sketch(10, &[10, 11, 12, 13, 14, 15]);
+        let theirs = sketch(10, &[12, 13, 14, 15, 16, 17, 18]);
+        ours.merge(&theirs);
+        assert_eq!(ours.decode().map(sorted), Some(vec![10, 11, 16, 17, 18]));
+    }
+
+    #[test]
+    fn decode_fails_above_the_capacity() {
+        // Arbitrary elements, a structured set can collide with a smaller one
+        let elements: Vec<u32> = (1..=12u32)
+            .map(|i| i.wrapping_mul(0x9e37_79b9).rotate_left(i) | 1)
+            .collect();
+        assert_eq!(sketch(6, &elements).decode(), None);
+        assert_eq!(sketch(11, &elements).decode(), None);
+        assert_eq!(
+            sketch(12, &elements).decode().map(sorted),
+            Some(sorted(elements))
+        );
+    }
+}
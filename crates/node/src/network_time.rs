@@ -0,0 +1,192 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{DateTime, Duration, Utc};
+use settings::NetworkTimeSettings;
+
+/// Offsets of fewer peers are not enough to adjust our clock.
+const MIN_SAMPLES: usize = 5;
+
+/// Network-adjusted time: our clock corrected by the median of the clock offsets of the peers
+/// we connected to, taken from the timestamps of their version messages.
+///
+/// Every peer address counts once, inbound peers are not sampled because anyone can connect
+/// to us. Clones share the same samples.
+#[derive(Clone, Debug)]
+pub struct NetworkTime {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    settings: NetworkTimeSettings,
+    offsets: HashMap<IpAddr, Duration>,
+    /// Insertion order, used to forget the oldest samples first.
+    order: VecDeque<IpAddr>,
+    /// The clock skew warning was logged, it is logged again once the skew went away.
+    warned: bool,
+}
+
+impl NetworkTime {
+    pub fn new(settings: NetworkTimeSettings) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                settings,
+                offsets: HashMap::new(),
+                order: VecDeque::new(),
+                warned: false,
+            })),
+        }
+    }
+
+    /// Apply reloaded settings, the samples are kept.
+    pub fn set_settings(&self, settings: NetworkTimeSettings) {
+        self.lock().settings = settings;
+    }
+
+    /// Record how far the clock of a peer is ahead of ours. Later samples of the same peer are
+    /// ignored, offsets beyond the maximum adjustment are clamped to it.
+    pub fn add_sample(&self, peer: IpAddr, offset: Duration) {
+        let mut inner = self.lock();
+        if inner.offsets.contains_key(&peer) {
+            return;
+        }
+        let max_adjustment = inner.max_adjustment();
+        let offset = offset.clamp(-max_adjustment, max_adjustment);
+        inner.offsets.insert(peer, offset);
+        inner.order.push_back(peer);
+        while inner.order.len() > inner.settings.max_samples() {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.offsets.remove(&oldest);
+            }
+        }
+        inner.check_skew();
+    }
+
+    pub fn samples(&self) -> usize {
+        self.lock().offsets.len()
+    }
+
+    /// The median offset of the peers, `None` while there are too few samples.
+    pub fn median_offset(&self) -> Option<Duration> {
+        self.lock().median_offset()
+    }
+
+    /// The correction applied to our clock, zero when the median offset is unknown or too
+    /// large to be trusted.
+    pub fn offset(&self) -> Duration {
+        let inner = self.lock();
+        let max_adjustment = inner.max_adjustment();
+        // A clamped median means the real one is at least as large
+        match inner.median_offset() {
+            Some(offset) if offset.abs() < max_adjustment => offset,
+            _ => Duration::zero(),
+        }
+    }
+
+    /// The current time as the network sees it, e.g. to reject block headers from too far in
+    /// the future.
+    pub fn adjusted_now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for NetworkTime {
+    fn default() -> Self {
+        Self::new(NetworkTimeSettings::default())
+    }
+}
+
+impl Inner {
+    fn max_adjustment(&self) -> Duration {
+        let secs = self.settings.max_adjustment().as_secs();
+        i64::try_from(secs)
+            .ok()
+            .and_then(Duration::try_seconds)
+            .unwrap_or(Duration::MAX)
+    }
+
+    fn median_offset(&self) -> Option<Duration> {
+        if self.offsets.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut offsets: Vec<Duration> = self.offsets.values().copied().collect();
+        offsets.sort();
+        Some(offsets[offsets.len() / 2])
+    }
+
+    /// Warn once when our clock is off by more than the threshold.
+    fn check_skew(&mut self) {
+        let Some(offset) = self.median_offset() else {
+            return;
+        };
+        let threshold = self.settings.warning_threshold().as_secs();
+        let skewed = offset.num_seconds().unsigned_abs() > threshold;
+        if skewed && !self.warned {
+            tracing::warn!(
+                "The clocks of {} peers are {}s ahead of ours in the median, check the system time",
+                self.offsets.len(),
+                offset.num_seconds()
+            );
+        }
+        self.warned = skewed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn sampled(offsets: &[Duration]) -> NetworkTime {
+        let network_time = NetworkTime::default();
+        for (i, offset) in offsets.iter().enumerate() {
+            let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i as u8));
+            network_time.add_sample(peer, *offset);
+        }
+        network_time
+    }
+
+    #[test]
+    fn median_of_five_samples_adjusts_the_clock() {
+        let network_time = sampled(&[1, 2, 3, 40, 50].map(Duration::seconds));
+        assert_eq!(network_time.offset(), Duration::seconds(3));
+    }
+
+    #[test]
+    fn too_few_samples_do_not_adjust_the_clock() {
+        let network_time = sampled(&[10, 10, 10, 10].map(Duration::seconds));
+        assert_eq!(network_time.median_offset(), None);
+        assert_eq!(network_time.offset(), Duration::zero());
+    }
+
+    #[test]
+    fn the_same_peer_counts_once() {
+        let network_time = NetworkTime::default();
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        network_time.add_sample(peer, Duration::seconds(1));
+        network_time.add_sample(peer, Duration::seconds(2));
+        assert_eq!(network_time.samples(), 1);
+    }
+
+    #[test]
+    fn extreme_offsets_are_clamped_and_not_applied() {
+        let network_time = sampled(&[Duration::MAX; 5]);
+        let max_adjustment = Duration::seconds(70 * 60);
+        assert_eq!(network_time.median_offset(), Some(max_adjustment));
+        assert_eq!(network_time.offset(), Duration::zero());
+        assert!(network_time.adjusted_now() <= Utc::now());
+
+        let network_time = sampled(&[Duration::MIN; 5]);
+        assert_eq!(network_time.median_offset(), Some(-max_adjustment));
+        assert_eq!(network_time.offset(), Duration::zero());
+    }
+}
@@ -0,0 +1,128 @@
+//! Starts the connections to the peers of the `[[peers]]` tables.
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use settings::{PeerAddress, PeerSettings, Settings, SettingsError};
+
+use crate::connection::{BitcoinConnection, BitcoinConnector, Connected};
+use crate::error::Error;
+
+/// Connects to the configured peers in priority order, within the `[connections]` limits.
+#[derive(Debug)]
+pub struct PeerManager {
+    connector: BitcoinConnector,
+}
+
+/// The connections of a [`PeerManager`] and the peers that failed in the last attempt.
+#[derive(Debug, Default)]
+pub struct StartedPeers {
+    pub connected: Vec<BitcoinConnection<Connected>>,
+    pub failed: Vec<(PeerSettings, Error)>,
+}
+
+impl PeerManager {
+    /// The peers and limits come from the settings of the connector.
+    pub fn new(connector: BitcoinConnector) -> Self {
+        Self { connector }
+    }
+
+    pub fn connector(&self) -> &BitcoinConnector {
+        &self.connector
+    }
+
+    /// The configured peers in the order they are tried, highest priority first.
+    pub fn peers(&self) -> Vec<PeerSettings> {
+        let mut peers = self.connector.settings().peers();
+        // Stable, so peers of the same priority keep the order of the config
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.priority()));
+        peers
+    }
+
+    /// Connect and handshake with up to `max_outbound` peers at the same time. When a peer
+    /// fails, the next one in priority order takes its place.
+    pub async fn start(&self) -> StartedPeers {
+        let mut started = StartedPeers::default();
+        self.connect_missing(&mut started).await;
+        started
+    }
+
+    /// Apply reloaded settings, e.g. from a [`settings::SettingsWatcher`]. Peers that were
+    /// removed or changed and peers that are banned now are disconnected, new peers are
+    /// connected while there is room below `max_outbound`. Nothing changes when the settings
+    /// can't be applied live.
+    pub async fn reload(
+        &mut self,
+        settings: Settings,
+        started: &mut StartedPeers,
+    ) -> Result<(), SettingsError> {
+        let old_peers = self.peers();
+        self.connector.update_settings(settings)?;
+        let new_peers = self.peers();
+
+        let ban_list = self.connector.ban_list();
+        started.connected.retain(|connection| {
+            let address = connection.peer_target();
+            let configured = old_peers
+                .iter()
+                .filter(|peer| peer.address() == address)
+                .all(|peer| new_peers.contains(peer));
+            let banned = ban_list.is_banned(connection.peer_address().ip());
+            if !configured || banned {
+                tracing::info!("Disconnecting peer {} after the settings changed", address);
+            }
+            configured && !banned
+        });
+        self.connect_missing(started).await;
+        Ok(())
+    }
+
+    /// Connect to the peers that are not connected yet, until `max_outbound` are connected.
+    async fn connect_missing(&self, started: &mut StartedPeers) {
+        let limits = self.connector.settings().connections();
+        let max_outbound = limits.max_outbound().max(1);
+        let connected: Vec<PeerAddress> = started
+            .connected
+            .iter()
+            .map(|connection| connection.peer_target().clone())
+            .collect();
+        let mut queue = self
+            .peers()
+            .into_iter()
+            .filter(|peer| !connected.contains(peer.address()));
+        let mut attempts = FuturesUnordered::new();
+        started.failed.clear();
+
+        loop {
+            while started.connected.len() + attempts.len() < max_outbound {
+                let Some(peer) = queue.next() else {
+                    break;
+                };
+                attempts.push(async move {
+                    let handshake = async {
+                        self.connector
+                            .connect_peer(&peer)
+                            .await?
+                            .perform_handshake()
+                            .await
+                    };
+                    let result = tokio::time::timeout(limits.connect_timeout(), handshake)
+                        .await
+                        .unwrap_or(Err(Error::Timeout));
+                    (peer, result)
+                });
+            }
+            let Some((peer, result)) = attempts.next().await else {
+                break;
+            };
+            match result {
+                Ok(connection) => {
+                    tracing::info!("Connected to peer {}", peer.address());
+                    started.connected.push(connection);
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to connect to peer {}: {}", peer.address(), err);
+                    started.failed.push((peer, err));
+                }
+            }
+        }
+    }
+}
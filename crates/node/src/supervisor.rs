@@ -0,0 +1,211 @@
+//! Connections that redial the peer when they are lost.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bitcoin::network::message::NetworkMessage;
+use futures::Stream;
+use settings::{PeerSettings, ReconnectSettings};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::connection::{new_tips, BitcoinConnection, BitcoinConnector, Connected, NewTip};
+use crate::error::Error;
+use crate::FromConnectionHandle;
+
+/// Messages waiting to be sent, e.g. while the peer is redialed.
+const COMMAND_CHANNEL_SIZE: usize = 64;
+const EVENT_CHANNEL_SIZE: usize = 4096;
+
+/// A connection that redials the peer and redoes the handshake when it is lost, waiting a
+/// jittered exponential backoff between the attempts (`[reconnect]` settings).
+///
+/// Subscribers stay attached across reconnects, they see [`FromConnectionHandle::Reconnecting`]
+/// before every attempt and [`FromConnectionHandle::Reconnected`] once the handshake is done
+/// again. The supervisor gives up when `max_attempts` attempts in a row failed or the peer got
+/// banned, `receive` then fails with [`Error::ActorUnavailable`].
+#[derive(Debug)]
+pub struct SupervisedConnection {
+    peer_address: SocketAddr,
+    messages: mpsc::Sender<NetworkMessage>,
+    receiver: broadcast::Receiver<FromConnectionHandle>,
+    supervisor: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for SupervisedConnection {
+    fn drop(&mut self) {
+        self.supervisor.abort();
+    }
+}
+
+impl SupervisedConnection {
+    /// Connect and handshake with the peer. Failing to reach the peer the first time is
+    /// returned as an error, only lost connections are redialed.
+    pub async fn connect(connector: BitcoinConnector, peer: PeerSettings) -> Result<Self, Error> {
+        let connection = connector
+            .connect_peer(&peer)
+            .await?
+            .perform_handshake()
+            .await?;
+        let (messages, messages_receiver) = mpsc::channel(COMMAND_CHANNEL_SIZE);
+        let (events, receiver) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        let supervisor = Supervisor {
+            connector,
+            peer: peer.clone(),
+            messages: messages_receiver,
+            unsent: None,
+            events,
+        };
+        Ok(Self {
+            peer_address: connection.peer_address(),
+            messages,
+            receiver,
+            supervisor: tokio::spawn(supervisor.run(connection)),
+        })
+    }
+
+    pub fn peer_address(&self) -> SocketAddr {
+        self.peer_address
+    }
+
+    /// Send a message to the peer. Messages sent while the peer is redialed are sent once the
+    /// handshake is done again.
+    pub async fn send(&self, message: NetworkMessage) -> Result<(), Error> {
+        self.messages
+            .send(message)
+            .await
+            .map_err(|_| Error::ActorUnavailable)
+    }
+
+    /// The next message or event, across reconnects.
+    pub async fn receive(&mut self) -> Result<FromConnectionHandle, Error> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Ok(event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Receiver fell behind, skipped {} messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return Err(Error::ActorUnavailable),
+            }
+        }
+    }
+
+    /// Another receiver of the messages and events from now on, e.g. for a different task.
+    pub fn subscribe(&self) -> broadcast::Receiver<FromConnectionHandle> {
+        self.receiver.resubscribe()
+    }
+
+    /// The new tips the peer announces from now on, across reconnects. See
+    /// [`BitcoinConnection::new_tip`].
+    pub fn new_tip(&self) -> impl Stream<Item = NewTip> {
+        new_tips(self.subscribe(), self.peer_address)
+    }
+}
+
+struct Supervisor {
+    connector: BitcoinConnector,
+    peer: PeerSettings,
+    messages: mpsc::Receiver<NetworkMessage>,
+    /// The message that was being sent when the connection was lost, sent again first thing
+    /// after reconnecting.
+    unsent: Option<NetworkMessage>,
+    events: broadcast::Sender<FromConnectionHandle>,
+}
+
+impl Supervisor {
+    async fn run(mut self, mut connection: BitcoinConnection<Connected>) {
+        loop {
+            self.forward(&mut connection).await;
+            tracing::warn!("Lost the connection to {}", self.peer.address());
+            match self.reconnect().await {
+                Some(reconnected) => connection = reconnected,
+                None => return,
+            }
+        }
+    }
+
+    /// Pass messages in both directions until the connection is lost.
+    async fn forward(&mut self, connection: &mut BitcoinConnection<Connected>) {
+        if let Some(message) = self.unsent.take() {
+            if connection.send(message.clone()).await.is_err() {
+                self.unsent = Some(message);
+                return;
+            }
+        }
+        loop {
+            tokio::select! {
+                message = self.messages.recv() => {
+                    let Some(message) = message else {
+                        // The supervised connection was dropped
+                        return;
+                    };
+                    if connection.send(message.clone()).await.is_err() {
+                        self.unsent = Some(message);
+                        return;
+                    }
+                }
+                event = connection.receive() => {
+                    let Ok(event) = event else {
+                        return;
+                    };
+                    let lost = matches!(event, FromConnectionHandle::Disconnected(_));
+                    let _ = self.events.send(event);
+                    if lost {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) -> Option<BitcoinConnection<Connected>> {
+        let settings = self.connector.settings().reconnect().clone();
+        let connect_timeout = self.connector.settings().connections().connect_timeout();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if matches!(settings.max_attempts(), Some(max) if attempt > max) {
+                tracing::error!(
+                    "Giving up on {} after {} attempts",
+                    self.peer.address(),
+                    attempt - 1
+                );
+                return None;
+            }
+            let delay = backoff(&settings, attempt);
+            let _ = self
+                .events
+                .send(FromConnectionHandle::Reconnecting { attempt, delay });
+            tokio::time::sleep(delay).await;
+
+            let handshake = async {
+                self.connector
+                    .connect_peer(&self.peer)
+                    .await?
+                    .perform_handshake()
+                    .await
+            };
+            match tokio::time::timeout(connect_timeout, handshake).await {
+                Ok(Ok(connection)) => {
+                    tracing::info!("Reconnected to {}", self.peer.address());
+                    let _ = self.events.send(FromConnectionHandle::Reconnected);
+                    return Some(connection);
+                }
+                Ok(Err(Error::Banned(address))) => {
+                    tracing::error!("Not reconnecting, {} is banned", address);
+                    return None;
+                }
+                Ok(Err(err)) => tracing::warn!("Reconnect attempt {} failed: {}", attempt, err),
+                Err(_) => tracing::warn!("Reconnect attempt {} timed out", attempt),
+            }
+        }
+    }
+}
+
+/// The delay doubles with every attempt up to the maximum. A random part of up to half the
+/// delay keeps many connections that were lost at the same time from redialing in lockstep.
+fn backoff(settings: &ReconnectSettings, attempt: u32) -> Duration {
+    let delay = settings
+        .initial_delay()
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(settings.max_delay());
+    delay / 2 + (delay / 2).mul_f64(rand::random())
+}
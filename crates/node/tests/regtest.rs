@@ -0,0 +1,135 @@
+//! Consensus traffic of a regtest node through the library. bitcoind is used when it is
+//! installed (`BITCOIND` or the `PATH`), a mock node otherwise.
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use node::network::message::NetworkMessage;
+use node::network::message_blockdata::Inventory;
+use node::{BitcoinConnection, BitcoinConnector, Connected, FromConnectionHandle};
+use regtest::Regtest;
+
+/// bitcoind delays transaction announcements to inbound peers by 5s on average.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn connect(regtest: &Regtest) -> Result<BitcoinConnection<Connected>> {
+    let connection = BitcoinConnector::new(regtest.settings()?)
+        .connect()
+        .await?
+        .perform_handshake()
+        .await?;
+    Ok(connection)
+}
+
+/// Fail when the future takes longer than [`TIMEOUT`].
+async fn within<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    match tokio::time::timeout(TIMEOUT, future).await {
+        Ok(result) => result,
+        Err(_) => bail!("Timed out"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn new_blocks_are_announced() -> Result<()> {
+    let regtest = Regtest::start().await?;
+    let connection = connect(&regtest).await?;
+    let tips = connection.new_tip();
+    futures::pin_mut!(tips);
+
+    let mined = regtest.mine(3).await?;
+    let height = regtest.height().await?;
+    let tip = mined[mined.len() - 1];
+    within(async {
+        while let Some(new_tip) = tips.next().await {
+            assert_eq!(new_tip.peer, regtest.p2p_address());
+            if new_tip.block_hash == tip {
+                assert_eq!(new_tip.height, height);
+                return Ok(());
+            }
+        }
+        bail!("The connection closed")
+    })
+    .await?;
+    assert_eq!(connection.stats().best_header, Some((tip, height)));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blocks_are_downloaded() -> Result<()> {
+    let regtest = Regtest::start().await?;
+    let mut connection = connect(&regtest).await?;
+
+    let block_hash = regtest.mine(1).await?[0];
+    connection
+        .send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(
+            block_hash,
+        )]))
+        .await?;
+    let block = within(async {
+        loop {
+            if let FromConnectionHandle::FromBitcoinNode(NetworkMessage::Block(block)) =
+                connection.receive().await?
+            {
+                if block.block_hash() == block_hash {
+                    return Ok(block);
+                }
+            }
+        }
+    })
+    .await?;
+    assert!(block.check_merkle_root());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transactions_are_relayed_to_us() -> Result<()> {
+    let regtest = Regtest::start().await?;
+    let mut connection = connect(&regtest).await?;
+
+    let txid = regtest.broadcast_transaction().await?;
+    within(async {
+        loop {
+            match connection.receive().await? {
+                FromConnectionHandle::FromBitcoinNode(NetworkMessage::Inv(inventory)) => {
+                    let transactions: Vec<Inventory> = inventory
+                        .into_iter()
+                        .filter(|item| {
+                            matches!(item, Inventory::Transaction(_) | Inventory::WTx(_))
+                        })
+                        .collect();
+                    if !transactions.is_empty() {
+                        connection
+                            .send(NetworkMessage::GetData(transactions))
+                            .await?;
+                    }
+                }
+                FromConnectionHandle::FromBitcoinNode(NetworkMessage::Tx(transaction))
+                    if transaction.txid() == txid =>
+                {
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transactions_are_relayed_to_the_node() -> Result<()> {
+    let regtest = Regtest::start().await?;
+    let connection = connect(&regtest).await?;
+
+    let transaction = regtest.signed_transaction().await?;
+    let txid = transaction.txid();
+    connection.announce_transaction(transaction).await?;
+    // The node asks for the transaction and accepts it to its mempool
+    within(async {
+        while !regtest.mempool_contains(&txid).await? {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,187 @@
+//! A throwaway regtest bitcoind with a funded wallet.
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use bitcoin::consensus::encode;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{BlockHash, Transaction, Txid};
+use serde_json::{json, Value};
+
+use crate::rpc::{Rpc, RpcError, RPC_IN_WARMUP};
+
+const RPC_USER: &str = "regtest";
+const RPC_PASSWORD: &str = "regtest";
+/// Coinbase outputs can be spent after 100 confirmations.
+const COINBASE_MATURITY: usize = 100;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct Bitcoind {
+    process: Child,
+    datadir: PathBuf,
+    p2p_address: SocketAddr,
+    rpc: Rpc,
+    /// Receives the coinbase outputs and the transactions we create.
+    address: String,
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.datadir);
+    }
+}
+
+impl Bitcoind {
+    /// The binary from the `BITCOIND` env variable, or `bitcoind` on the `PATH`.
+    pub(crate) fn find() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("BITCOIND") {
+            return Some(PathBuf::from(path));
+        }
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|directory| directory.join("bitcoind"))
+            .find(|path| path.is_file())
+    }
+
+    /// Start bitcoind in a fresh data directory and mine enough blocks to spend from its
+    /// wallet.
+    pub(crate) async fn start(binary: &Path) -> anyhow::Result<Self> {
+        let datadir = std::env::temp_dir().join(format!(
+            "regtest-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        std::fs::create_dir_all(&datadir)?;
+        let p2p_address = SocketAddr::from(([127, 0, 0, 1], free_port()?));
+        let rpc_address = SocketAddr::from(([127, 0, 0, 1], free_port()?));
+        let process = Command::new(binary)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-bind={p2p_address}"))
+            .arg(format!("-port={}", p2p_address.port()))
+            .arg(format!("-rpcbind={rpc_address}"))
+            .arg(format!("-rpcport={}", rpc_address.port()))
+            .arg("-rpcallowip=127.0.0.1")
+            .arg(format!("-rpcuser={RPC_USER}"))
+            .arg(format!("-rpcpassword={RPC_PASSWORD}"))
+            .args(["-server=1", "-listen=1", "-listenonion=0", "-discover=0"])
+            .args(["-dnsseed=0", "-printtoconsole=0", "-fallbackfee=0.0002"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start {}", binary.display()))?;
+        let mut bitcoind = Self {
+            process,
+            datadir,
+            p2p_address,
+            rpc: Rpc::new(rpc_address, RPC_USER, RPC_PASSWORD),
+            address: String::new(),
+        };
+        bitcoind.wait_for_rpc().await?;
+        bitcoind.call("createwallet", json!(["regtest"])).await?;
+        bitcoind.address = string(bitcoind.call("getnewaddress", json!([])).await?)?;
+        bitcoind.mine(COINBASE_MATURITY + 1).await?;
+        tracing::info!("Started bitcoind on {}", bitcoind.p2p_address);
+        Ok(bitcoind)
+    }
+
+    async fn wait_for_rpc(&mut self) -> anyhow::Result<()> {
+        let deadline = tokio::time::Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            match self.call("getblockchaininfo", json!([])).await {
+                Ok(_) => return Ok(()),
+                Err(err) if tokio::time::Instant::now() > deadline => return Err(err),
+                Err(err) => match err.downcast_ref::<RpcError>() {
+                    Some(err) if err.code != RPC_IN_WARMUP => bail!("{}", err),
+                    _ => {
+                        if let Some(status) = self.process.try_wait()? {
+                            bail!("bitcoind exited with {status}");
+                        }
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                },
+            }
+        }
+    }
+
+    pub(crate) fn p2p_address(&self) -> SocketAddr {
+        self.p2p_address
+    }
+
+    pub(crate) async fn mine(&self, blocks: usize) -> anyhow::Result<Vec<BlockHash>> {
+        let hashes = self
+            .call("generatetoaddress", json!([blocks, self.address]))
+            .await?;
+        hashes
+            .as_array()
+            .context("generatetoaddress did not return an array")?
+            .iter()
+            .map(|hash| Ok(BlockHash::from_str(&string(hash.clone())?)?))
+            .collect()
+    }
+
+    pub(crate) async fn height(&self) -> anyhow::Result<u32> {
+        let height = self.call("getblockcount", json!([])).await?;
+        Ok(u32::try_from(
+            height.as_u64().context("Invalid block count")?,
+        )?)
+    }
+
+    /// Send coins to ourselves, bitcoind relays the transaction to its peers.
+    pub(crate) async fn broadcast_transaction(&self) -> anyhow::Result<Txid> {
+        let txid = self
+            .call("sendtoaddress", json!([self.address, 1.0]))
+            .await?;
+        Ok(Txid::from_str(&string(txid)?)?)
+    }
+
+    /// A valid transaction that bitcoind has not seen yet.
+    pub(crate) async fn signed_transaction(&self) -> anyhow::Result<Transaction> {
+        let mut output = serde_json::Map::new();
+        output.insert(self.address.clone(), json!(1.0));
+        let outputs = json!([output]);
+        let raw = self
+            .call("createrawtransaction", json!([[], outputs]))
+            .await?;
+        let funded = self.call("fundrawtransaction", json!([raw])).await?;
+        let signed = self
+            .call("signrawtransactionwithwallet", json!([funded["hex"]]))
+            .await?;
+        if signed["complete"] != Value::Bool(true) {
+            bail!("Failed to sign the transaction: {}", signed["errors"]);
+        }
+        let bytes = Vec::<u8>::from_hex(&string(signed["hex"].clone())?)?;
+        Ok(encode::deserialize(&bytes)?)
+    }
+
+    pub(crate) async fn mempool_contains(&self, txid: &Txid) -> anyhow::Result<bool> {
+        let mempool = self.call("getrawmempool", json!([])).await?;
+        let txid = txid.to_string();
+        Ok(mempool
+            .as_array()
+            .context("getrawmempool did not return an array")?
+            .iter()
+            .any(|entry| entry.as_str() == Some(txid.as_str())))
+    }
+
+    async fn call(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        self.rpc.call(method, params).await
+    }
+}
+
+/// A port that was free a moment ago.
+fn free_port() -> anyhow::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn string(value: Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(string) => Ok(string),
+        value => bail!("Expected a string, got {value}"),
+    }
+}
@@ -0,0 +1,112 @@
+//! A regtest node to run the library against real consensus traffic without internet.
+//!
+//! [`Regtest::start`] launches a throwaway `bitcoind` when one is found (the `BITCOIND` env
+//! variable or the `PATH`) and falls back to a mock node otherwise. Both mine valid regtest
+//! blocks, announce blocks and transactions to their peers and answer `getheaders` and
+//! `getdata`.
+mod bitcoind;
+mod mock;
+mod rpc;
+
+use std::net::SocketAddr;
+
+use bitcoin::{BlockHash, Transaction, Txid};
+use settings::{Settings, SettingsLoader};
+
+use crate::bitcoind::Bitcoind;
+use crate::mock::MockNode;
+
+#[derive(Debug)]
+pub struct Regtest {
+    node: Node,
+}
+
+#[derive(Debug)]
+enum Node {
+    Bitcoind(Bitcoind),
+    Mock(MockNode),
+}
+
+impl Regtest {
+    /// Start bitcoind when it is installed, the mock node otherwise.
+    pub async fn start() -> anyhow::Result<Self> {
+        match Bitcoind::find() {
+            Some(binary) => Self::bitcoind(&binary).await,
+            None => {
+                tracing::info!("bitcoind not found, falling back to the mock node");
+                Self::mock().await
+            }
+        }
+    }
+
+    pub async fn bitcoind(binary: &std::path::Path) -> anyhow::Result<Self> {
+        let node = Node::Bitcoind(Bitcoind::start(binary).await?);
+        Ok(Self { node })
+    }
+
+    pub async fn mock() -> anyhow::Result<Self> {
+        let node = Node::Mock(MockNode::start().await?);
+        Ok(Self { node })
+    }
+
+    pub fn is_bitcoind(&self) -> bool {
+        matches!(self.node, Node::Bitcoind(_))
+    }
+
+    /// Where the node accepts P2P connections.
+    pub fn p2p_address(&self) -> SocketAddr {
+        match &self.node {
+            Node::Bitcoind(bitcoind) => bitcoind.p2p_address(),
+            Node::Mock(mock) => mock.p2p_address(),
+        }
+    }
+
+    /// Settings to connect to the node, asking it to relay transactions to us. The env variables
+    /// are ignored so they can't point the tests elsewhere.
+    pub fn settings(&self) -> anyhow::Result<Settings> {
+        Ok(SettingsLoader::new()
+            .env(Vec::<(String, String)>::new())
+            .set("peer_address", self.p2p_address().to_string())
+            .set("peer_network", "regtest")
+            .set("handshake.relay", "true")
+            .load()?)
+    }
+
+    /// Mine blocks on top of the chain, the node announces them to its peers.
+    pub async fn mine(&self, blocks: usize) -> anyhow::Result<Vec<BlockHash>> {
+        match &self.node {
+            Node::Bitcoind(bitcoind) => bitcoind.mine(blocks).await,
+            Node::Mock(mock) => Ok(mock.mine(blocks)),
+        }
+    }
+
+    pub async fn height(&self) -> anyhow::Result<u32> {
+        match &self.node {
+            Node::Bitcoind(bitcoind) => bitcoind.height().await,
+            Node::Mock(mock) => Ok(mock.height()),
+        }
+    }
+
+    /// Create a transaction in the node, it is relayed to the peers.
+    pub async fn broadcast_transaction(&self) -> anyhow::Result<Txid> {
+        match &self.node {
+            Node::Bitcoind(bitcoind) => bitcoind.broadcast_transaction().await,
+            Node::Mock(mock) => Ok(mock.broadcast_transaction()),
+        }
+    }
+
+    /// A transaction the node accepts but has not seen yet, e.g. to relay it to the node.
+    pub async fn signed_transaction(&self) -> anyhow::Result<Transaction> {
+        match &self.node {
+            Node::Bitcoind(bitcoind) => bitcoind.signed_transaction().await,
+            Node::Mock(mock) => Ok(mock.signed_transaction()),
+        }
+    }
+
+    pub async fn mempool_contains(&self, txid: &Txid) -> anyhow::Result<bool> {
+        match &self.node {
+            Node::Bitcoind(bitcoind) => bitcoind.mempool_contains(txid).await,
+            Node::Mock(mock) => Ok(mock.mempool_contains(txid)),
+        }
+    }
+}
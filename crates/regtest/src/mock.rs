@@ -0,0 +1,364 @@
+//! A stand-in for bitcoind: it mines valid regtest blocks, announces them and transactions to
+//! its peers and serves `getheaders` and `getdata`. It does not validate transactions.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::bail;
+use bitcoin::blockdata::constants::{genesis_block, COIN_VALUE};
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode;
+use bitcoin::hashes::Hash;
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::{
+    Block, BlockHash, BlockHeader, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn,
+    TxOut, Txid, Witness,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// `wtxidrelay` is not implemented, peers announce transactions by txid.
+const PROTOCOL_VERSION: u32 = 70015;
+const MAX_HEADERS: usize = 2000;
+/// Easiest target of regtest.
+const REGTEST_BITS: u32 = 0x207f_ffff;
+const HEADER_SIZE: usize = 24;
+
+#[derive(Debug)]
+pub(crate) struct MockNode {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    listener: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for MockNode {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    chain: Vec<Block>,
+    mempool: HashMap<Txid, Transaction>,
+    peers: Vec<Peer>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    messages: mpsc::UnboundedSender<NetworkMessage>,
+    /// The peer asked for `headers` announcements (BIP130).
+    send_headers: bool,
+}
+
+impl MockNode {
+    pub(crate) async fn start() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            chain: vec![genesis_block(Network::Regtest)],
+            mempool: HashMap::new(),
+            peers: Vec::new(),
+        }));
+        let listener = tokio::spawn(accept(listener, state.clone()));
+        tracing::info!("Started the mock node on {}", address);
+        Ok(Self {
+            address,
+            state,
+            listener,
+        })
+    }
+
+    pub(crate) fn p2p_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub(crate) fn mine(&self, blocks: usize) -> Vec<BlockHash> {
+        let mut state = lock(&self.state);
+        let mut hashes = Vec::with_capacity(blocks);
+        for _ in 0..blocks {
+            let block = next_block(&state.chain);
+            let header = block.header;
+            hashes.push(block.block_hash());
+            state.chain.push(block);
+            state.announce(|send_headers| match send_headers {
+                true => NetworkMessage::Headers(vec![header]),
+                false => NetworkMessage::Inv(vec![Inventory::Block(header.block_hash())]),
+            });
+        }
+        hashes
+    }
+
+    pub(crate) fn height(&self) -> u32 {
+        lock(&self.state).height()
+    }
+
+    pub(crate) fn broadcast_transaction(&self) -> Txid {
+        let transaction = new_transaction();
+        let txid = transaction.txid();
+        let mut state = lock(&self.state);
+        state.mempool.insert(txid, transaction);
+        state.announce(|_| NetworkMessage::Inv(vec![Inventory::Transaction(txid)]));
+        txid
+    }
+
+    /// A transaction that the node has not seen yet, it does not need to be valid.
+    pub(crate) fn signed_transaction(&self) -> Transaction {
+        new_transaction()
+    }
+
+    pub(crate) fn mempool_contains(&self, txid: &Txid) -> bool {
+        lock(&self.state).mempool.contains_key(txid)
+    }
+}
+
+impl State {
+    fn height(&self) -> u32 {
+        (self.chain.len() - 1) as u32
+    }
+
+    /// Send a message to every peer, forgetting the disconnected ones.
+    fn announce(&mut self, message: impl Fn(bool) -> NetworkMessage) {
+        self.peers
+            .retain(|peer| peer.messages.send(message(peer.send_headers)).is_ok());
+    }
+
+    /// The headers after the first locator hash we know, up to the stop hash. An empty locator
+    /// asks for the header of the stop hash.
+    fn headers(&self, request: &GetHeadersMessage) -> Vec<BlockHeader> {
+        let start = match request.locator_hashes.is_empty() {
+            true => self.position(&request.stop_hash),
+            false => request
+                .locator_hashes
+                .iter()
+                .find_map(|hash| self.position(hash))
+                .map(|position| position + 1),
+        };
+        let mut headers = Vec::new();
+        for block in self.chain.iter().skip(start.unwrap_or(self.chain.len())) {
+            headers.push(block.header);
+            if headers.len() == MAX_HEADERS || block.block_hash() == request.stop_hash {
+                break;
+            }
+        }
+        headers
+    }
+
+    fn position(&self, block_hash: &BlockHash) -> Option<usize> {
+        self.chain
+            .iter()
+            .position(|block| block.block_hash() == *block_hash)
+    }
+
+    fn get_data(&self, inventory: Vec<Inventory>) -> Vec<NetworkMessage> {
+        let mut responses = Vec::new();
+        let mut not_found = Vec::new();
+        for item in inventory {
+            let response = match item {
+                Inventory::Block(hash) | Inventory::WitnessBlock(hash) => self
+                    .position(&hash)
+                    .map(|position| NetworkMessage::Block(self.chain[position].clone())),
+                Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+                    self.mempool.get(&txid).cloned().map(NetworkMessage::Tx)
+                }
+                _ => None,
+            };
+            match response {
+                Some(response) => responses.push(response),
+                None => not_found.push(item),
+            }
+        }
+        if !not_found.is_empty() {
+            responses.push(NetworkMessage::NotFound(not_found));
+        }
+        responses
+    }
+}
+
+async fn accept(listener: TcpListener, state: Arc<Mutex<State>>) {
+    while let Ok((stream, peer_address)) = listener.accept().await {
+        tracing::debug!("Mock node accepted {}", peer_address);
+        tokio::spawn(serve(stream, peer_address, state.clone()));
+    }
+}
+
+async fn serve(stream: TcpStream, peer_address: SocketAddr, state: Arc<Mutex<State>>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (messages, mut outbox) = mpsc::unbounded_channel::<NetworkMessage>();
+    tokio::spawn(async move {
+        while let Some(payload) = outbox.recv().await {
+            let message = RawNetworkMessage {
+                magic: Network::Regtest.magic(),
+                payload,
+            };
+            if writer
+                .write_all(&encode::serialize(&message))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut peer = None;
+    loop {
+        let message = match read_message(&mut reader).await {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::debug!("Mock node lost {}: {}", peer_address, err);
+                break;
+            }
+        };
+        let mut state = lock(&state);
+        let replies = match message {
+            NetworkMessage::Version(_) => {
+                // Announcements go to the peer from now on
+                peer = Some(state.peers.len());
+                state.peers.push(Peer {
+                    messages: messages.clone(),
+                    send_headers: false,
+                });
+                vec![
+                    NetworkMessage::Version(version(peer_address, state.height())),
+                    NetworkMessage::Verack,
+                ]
+            }
+            NetworkMessage::SendHeaders => {
+                if let Some(peer) = peer.and_then(|peer| state.peers.get_mut(peer)) {
+                    peer.send_headers = true;
+                }
+                Vec::new()
+            }
+            NetworkMessage::Ping(nonce) => vec![NetworkMessage::Pong(nonce)],
+            NetworkMessage::GetHeaders(request) => {
+                vec![NetworkMessage::Headers(state.headers(&request))]
+            }
+            NetworkMessage::GetData(inventory) => state.get_data(inventory),
+            NetworkMessage::Inv(inventory) => {
+                let unknown: Vec<Inventory> = inventory
+                    .into_iter()
+                    .filter(|item| match item {
+                        Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+                            !state.mempool.contains_key(txid)
+                        }
+                        _ => false,
+                    })
+                    .collect();
+                match unknown.is_empty() {
+                    true => Vec::new(),
+                    false => vec![NetworkMessage::GetData(unknown)],
+                }
+            }
+            NetworkMessage::Tx(transaction) => {
+                state.mempool.insert(transaction.txid(), transaction);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        for reply in replies {
+            let _ = messages.send(reply);
+        }
+    }
+}
+
+async fn read_message(reader: &mut OwnedReadHalf) -> anyhow::Result<NetworkMessage> {
+    let mut frame = vec![0; HEADER_SIZE];
+    reader.read_exact(&mut frame).await?;
+    let length = u32::from_le_bytes([frame[16], frame[17], frame[18], frame[19]]) as usize;
+    if length > bitcoin::consensus::encode::MAX_VEC_SIZE {
+        bail!("Oversized message of {length} bytes");
+    }
+    frame.resize(HEADER_SIZE + length, 0);
+    reader.read_exact(&mut frame[HEADER_SIZE..]).await?;
+    let message: RawNetworkMessage = encode::deserialize(&frame)?;
+    Ok(message.payload)
+}
+
+fn version(peer_address: SocketAddr, height: u32) -> VersionMessage {
+    let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+    let mut version = VersionMessage::new(
+        services,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64),
+        Address::new(&peer_address, ServiceFlags::NONE),
+        Address::new(&peer_address, services),
+        rand::random(),
+        "/regtest-mock:0.1.0/".to_string(),
+        height as i32,
+    );
+    version.version = PROTOCOL_VERSION;
+    version.relay = true;
+    version
+}
+
+/// A block on top of the chain with only a coinbase, BIP34 needs the height in it.
+fn next_block(chain: &[Block]) -> Block {
+    let previous = &chain[chain.len() - 1].header;
+    let coinbase = Transaction {
+        version: 1,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(chain.len() as i64)
+                .push_slice(b"mock")
+                .into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut {
+            value: 50 * COIN_VALUE,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let mut block = Block {
+        header: BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: previous.block_hash(),
+            merkle_root: Hash::all_zeros(),
+            time: previous.time + 1,
+            bits: REGTEST_BITS,
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    };
+    if let Some(merkle_root) = block.compute_merkle_root() {
+        block.header.merkle_root = merkle_root;
+    }
+    while block.header.validate_pow(&block.header.target()).is_err() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+/// A transaction spending a made up output.
+fn new_transaction() -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::from_inner(rand::random()), 0),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: Script::new(),
+        }],
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,97 @@
+//! A minimal JSON-RPC client for bitcoind, one HTTP/1.0 request per call.
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// bitcoind is still starting up (`RPC_IN_WARMUP`).
+pub(crate) const RPC_IN_WARMUP: i64 = -28;
+
+/// An error returned by bitcoind.
+#[derive(Debug)]
+pub(crate) struct RpcError {
+    pub(crate) code: i64,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RPC error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Debug)]
+pub(crate) struct Rpc {
+    address: SocketAddr,
+    /// Base64 of `user:password`.
+    authorization: String,
+}
+
+impl Rpc {
+    pub(crate) fn new(address: SocketAddr, user: &str, password: &str) -> Self {
+        Self {
+            address,
+            authorization: base64(format!("{user}:{password}").as_bytes()),
+        }
+    }
+
+    pub(crate) async fn call(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let body = json!({
+            "jsonrpc": "1.0",
+            "id": "regtest",
+            "method": method,
+            "params": params,
+        })
+        .to_string();
+        let request = format!(
+            "POST / HTTP/1.0\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            self.address,
+            self.authorization,
+            body.len(),
+            body
+        );
+        let mut stream = TcpStream::connect(self.address).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let response = String::from_utf8_lossy(&response);
+        let Some((status, body)) = response.split_once("\r\n\r\n") else {
+            bail!("Incomplete response to {method}");
+        };
+        let response: Value = serde_json::from_str(body)
+            .with_context(|| format!("Invalid response to {method}: {status}"))?;
+        if let Some(error) = response.get("error").filter(|error| !error.is_null()) {
+            return Err(RpcError {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+            }
+            .into());
+        }
+        Ok(response["result"].clone())
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |buffer, (i, byte)| {
+            buffer | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(
+                    ALPHABET[(buffer >> (18 - 6 * i) & 0x3f) as usize],
+                ));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use config::ConfigError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("Failed to read the config file {path}: {reason}")]
+    File { path: PathBuf, reason: String },
+    #[error("Invalid value for `{key}`: {reason}")]
+    InvalidValue { key: String, reason: String },
+    #[error("Missing setting `{key}`")]
+    Missing { key: String },
+    #[error("Invalid command line argument {0:?}, expected --config <path> or --<key>=<value>")]
+    InvalidArgument(String),
+    #[error("`{key}` can't be changed on live connections, reconnect to apply it")]
+    RequiresReconnect { key: String },
+    #[error("Invalid settings: {0}")]
+    Other(String),
+}
+
+impl SettingsError {
+    pub(crate) fn invalid(key: &str, reason: impl ToString) -> Self {
+        SettingsError::InvalidValue {
+            key: key.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The key of the setting that caused the error, if known.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            SettingsError::InvalidValue { key, .. }
+            | SettingsError::Missing { key }
+            | SettingsError::RequiresReconnect { key } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConfigError> for SettingsError {
+    fn from(err: ConfigError) -> Self {
+        match err {
+            ConfigError::NotFound(key) => SettingsError::Missing { key },
+            ConfigError::Type {
+                key: Some(key),
+                unexpected,
+                expected,
+                ..
+            } => SettingsError::invalid(
+                &key,
+                format!("invalid type: {unexpected}, expected {expected}"),
+            ),
+            ConfigError::FileParse { uri, cause } => SettingsError::File {
+                path: uri.unwrap_or_default().into(),
+                reason: cause.to_string(),
+            },
+            ConfigError::Message(message) => match missing_field(&message) {
+                Some(key) => SettingsError::Missing {
+                    key: key.to_string(),
+                },
+                None => SettingsError::Other(message),
+            },
+            err => SettingsError::Other(err.to_string()),
+        }
+    }
+}
+
+/// Serde reports missing fields as "missing field `name`".
+fn missing_field(message: &str) -> Option<&str> {
+    message.strip_prefix("missing field `")?.strip_suffix('`')
+}
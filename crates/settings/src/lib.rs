@@ -1,4 +1,14 @@
-///! Crate for compiling and loading settings from a config file and env variables.
+//! Crate for compiling and loading settings from layered config files, env variables and command line overrides.
+mod error;
+mod loader;
 mod settings;
+mod watcher;
 
-pub use settings::Settings;
+pub use error::SettingsError;
+pub use loader::SettingsLoader;
+pub use settings::{
+    CaptureSettings, CompactBlockSettings, ConnectionSettings, CrawlerSettings, FeeFilterSettings,
+    HandshakeOverrides, HandshakeSettings, MisbehaviorSettings, NetworkTimeSettings, OutputFormat,
+    PeerAddress, PeerSettings, ReconciliationSettings, ReconnectSettings, Service, Settings,
+};
+pub use watcher::SettingsWatcher;
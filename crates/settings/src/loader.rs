@@ -0,0 +1,269 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use config::{Config, ConfigError, Environment, File, FileFormat, Map};
+use serde::de::DeserializeOwned;
+
+use crate::error::SettingsError;
+use crate::settings::{OutputFormat, PeerSettings, Service, Settings, BASE_CONFIG};
+
+/// Only env variables starting with `NODE__` are read, so unrelated ones such as `LOG_LEVEL` of
+/// other tools don't leak into the settings.
+const ENV_PREFIX: &str = "NODE";
+
+/// Loads the settings from layered sources, later ones override earlier ones:
+/// the compiled-in `config.base.toml`, a user config file, env variables and overrides,
+/// e.g. from the command line.
+///
+/// Env variables are prefixed with `NODE__` and nested keys are separated by `__`
+/// (`NODE__HANDSHAKE__RELAY=true`), overrides separate them by `.` (`--handshake.relay=true`).
+#[derive(Clone, Debug, Default)]
+pub struct SettingsLoader {
+    file: Option<PathBuf>,
+    env: Option<Map<String, String>>,
+    overrides: Vec<(String, String)>,
+}
+
+impl SettingsLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the TOML file on top of the base config.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    /// Read the env variables from `vars` instead of the process environment, e.g. none at all
+    /// in tests.
+    pub fn env<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let vars = vars
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()));
+        self.env = Some(vars.collect());
+        self
+    }
+
+    /// Override a single setting, e.g. `set("misbehavior.ban_threshold", "50")`.
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.overrides.push((key.into(), value.into()));
+        self
+    }
+
+    /// Take the config file and overrides from command line arguments (without the program
+    /// name): `--config <path>` and `--<key>=<value>` or `--<key> <value>`. Dashes in keys are
+    /// read as underscores, so `--peer-address` sets `peer_address`.
+    pub fn args<I>(mut self, args: I) -> Result<Self, SettingsError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--").filter(|option| !option.is_empty()) else {
+                return Err(SettingsError::InvalidArgument(arg));
+            };
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (option.to_string(), value),
+                    None => return Err(SettingsError::InvalidArgument(arg)),
+                },
+            };
+            let key = key.replace('-', "_");
+            if key == "config" {
+                self.file = Some(value.into());
+            } else {
+                self.overrides.push((key, value));
+            }
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn config_file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn load(self) -> Result<Settings, SettingsError> {
+        let mut builder =
+            Config::builder().add_source(File::from_str(BASE_CONFIG, FileFormat::Toml));
+        if let Some(path) = &self.file {
+            if !path.is_file() {
+                return Err(SettingsError::File {
+                    path: path.clone(),
+                    reason: "not found".to_string(),
+                });
+            }
+            builder = builder.add_source(File::from(path.as_path()).format(FileFormat::Toml));
+        }
+        builder = builder.add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("__")
+                .separator("__")
+                .source(self.env.clone()),
+        );
+        for (key, value) in self.overrides {
+            builder = builder.set_override(key, value)?;
+        }
+        let config = builder.build().map_err(|err| match (err, &self.file) {
+            // The file has been checked above, so it is the one that failed to parse
+            (ConfigError::FileParse { cause, .. }, Some(path)) => SettingsError::File {
+                path: path.clone(),
+                reason: cause.to_string(),
+            },
+            (err, _) => err.into(),
+        })?;
+
+        // Serde does not tell which key these failed on, check them one by one first
+        check::<SocketAddr>(&config, "peer_address")?;
+        check::<SocketAddr>(&config, "sender_address")?;
+        check::<bitcoin::Network>(&config, "peer_network")?;
+        check::<Vec<Service>>(&config, "handshake.services")?;
+        check::<OutputFormat>(&config, "crawler.output_format")?;
+        check_peers(&config)?;
+
+        let settings: Settings = config.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// Deserialize a single key to attach the key to the error.
+fn check<T: DeserializeOwned>(config: &Config, key: &str) -> Result<(), SettingsError> {
+    match config.get::<T>(key) {
+        Ok(_) | Err(ConfigError::NotFound(_)) => Ok(()),
+        Err(err @ ConfigError::Type { .. }) => Err(err.into()),
+        Err(err) => Err(SettingsError::invalid(key, err)),
+    }
+}
+
+/// Check the peers one by one, so the error names the peer.
+fn check_peers(config: &Config) -> Result<(), SettingsError> {
+    let peers = match config.get::<Vec<config::Value>>("peers") {
+        Ok(peers) => peers,
+        Err(ConfigError::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(SettingsError::invalid("peers", err)),
+    };
+    for (index, peer) in peers.into_iter().enumerate() {
+        let key = format!("peers[{index}]");
+        peer.try_deserialize::<PeerSettings>()
+            .map_err(|err| match SettingsError::from(err) {
+                SettingsError::Missing { key: field } => SettingsError::Missing {
+                    key: format!("{key}.{field}"),
+                },
+                SettingsError::InvalidValue { key: field, reason } => {
+                    SettingsError::invalid(&format!("{key}.{field}"), reason)
+                }
+                SettingsError::Other(reason) => SettingsError::invalid(&key, reason),
+                err => err,
+            })?;
+    }
+    Ok(())
+}
+
+impl Settings {
+    /// Load the settings with the config file at `path` on top of the base config.
+    /// See [`SettingsLoader`] for the layers.
+    pub fn load(path: Option<&Path>) -> Result<Self, SettingsError> {
+        let mut loader = SettingsLoader::new();
+        if let Some(path) = path {
+            loader = loader.file(path);
+        }
+        loader.load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<Settings, SettingsError> {
+        SettingsLoader::new()
+            .env(Vec::<(String, String)>::new())
+            .args(args.iter().copied())?
+            .load()
+    }
+
+    #[test]
+    fn only_prefixed_env_variables_are_read() {
+        let settings = SettingsLoader::new()
+            .env([
+                ("NODE__PEER_ADDRESS", "127.0.0.1:18444"),
+                ("NODE__PEER_NETWORK", "regtest"),
+                ("NODE__MISBEHAVIOR__BAN_THRESHOLD", "50"),
+                ("SEND_BUFFER_LIMIT", "0"),
+            ])
+            .load()
+            .unwrap();
+        assert_eq!(settings.peer_address(), "127.0.0.1:18444".parse().unwrap());
+        assert_eq!(settings.peer_network(), bitcoin::Network::Regtest);
+        assert_eq!(settings.misbehavior().ban_threshold(), 50);
+        assert_eq!(settings.send_buffer_limit(), 1_000_000);
+
+        // Arguments override the env variables
+        let settings = SettingsLoader::new()
+            .env([("NODE__MISBEHAVIOR__BAN_THRESHOLD", "50")])
+            .set("misbehavior.ban_threshold", "60")
+            .load()
+            .unwrap();
+        assert_eq!(settings.misbehavior().ban_threshold(), 60);
+    }
+
+    #[test]
+    fn arguments_override_the_base_config() {
+        let settings = load(&[
+            "--peer-address=127.0.0.1:18444",
+            "--peer-network",
+            "regtest",
+            "--misbehavior.ban_threshold=50",
+        ])
+        .unwrap();
+        assert_eq!(settings.peer_address(), "127.0.0.1:18444".parse().unwrap());
+        assert_eq!(settings.peer_network(), bitcoin::Network::Regtest);
+        assert_eq!(settings.misbehavior().ban_threshold(), 50);
+        // Untouched keys keep the base config
+        assert_eq!(settings.send_buffer_limit(), 1_000_000);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        for args in [&["peer_address"][..], &["--"], &["--peer-address"]] {
+            assert!(matches!(
+                SettingsLoader::new().args(args.iter().copied()),
+                Err(SettingsError::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            load(&["--config", "/nonexistent/config.toml"]),
+            Err(SettingsError::File { .. })
+        ));
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let key = |args: &[&str]| load(args).unwrap_err().key().map(str::to_string);
+        assert_eq!(
+            key(&["--peer-address=localhost"]).as_deref(),
+            Some("peer_address")
+        );
+        assert_eq!(
+            key(&["--peer-network=moonnet"]).as_deref(),
+            Some("peer_network")
+        );
+        assert_eq!(
+            key(&["--send-buffer-limit=0"]).as_deref(),
+            Some("send_buffer_limit")
+        );
+        // 18333 is the testnet port
+        assert_eq!(
+            key(&["--peer-address=127.0.0.1:18333"]).as_deref(),
+            Some("peer_address")
+        );
+    }
+}
@@ -1,27 +1,249 @@
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use config::{Config, Environment, File, FileFormat};
+use bitcoin::network::constants::ServiceFlags;
 use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
 
-const BASE_CONFIG: &str = include_str!("../../../config.base.toml");
+use crate::error::SettingsError;
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+pub(crate) const BASE_CONFIG: &str = include_str!("../../../config.base.toml");
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
 pub struct Settings {
     peer_address: SocketAddr,
     sender_address: SocketAddr,
     peer_network: bitcoin::Network,
+    /// Optional pcapng capture of the connection traffic. Disabled when the section is missing.
+    #[serde(default)]
+    capture: Option<CaptureSettings>,
+    #[serde(default)]
+    misbehavior: MisbehaviorSettings,
+    /// Bytes that may be queued for the peer before senders are pushed back.
+    #[serde(default = "Settings::default_send_buffer_limit")]
+    send_buffer_limit: usize,
+    /// How often the peer is pinged to measure the latency.
+    #[serde(default = "Settings::default_ping_interval_secs")]
+    ping_interval_secs: u64,
+    /// `tracing` filter directives, e.g. `info` or `node=debug,info`.
+    #[serde(default = "Settings::default_log_level")]
+    log_level: String,
+    #[serde(default)]
+    compact_blocks: CompactBlockSettings,
+    #[serde(default)]
+    handshake: HandshakeSettings,
+    #[serde(default)]
+    crawler: CrawlerSettings,
+    #[serde(default)]
+    connections: ConnectionSettings,
+    #[serde(default)]
+    reconnect: ReconnectSettings,
+    #[serde(default)]
+    reconciliation: ReconciliationSettings,
+    #[serde(default)]
+    fee_filter: FeeFilterSettings,
+    #[serde(default)]
+    network_time: NetworkTimeSettings,
+    /// Peers to connect to, `peer_address` is used when there are none.
+    #[serde(default)]
+    peers: Vec<PeerSettings>,
+}
+
+/// Settings for writing the connection traffic to pcapng files.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+pub struct CaptureSettings {
+    path: PathBuf,
+    /// Size in bytes after which the capture is rotated into a new file.
+    #[serde(default = "CaptureSettings::default_max_file_size")]
+    max_file_size: u64,
+}
+
+/// Settings for scoring misbehaving peers and banning them.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct MisbehaviorSettings {
+    /// Score at which the peer gets disconnected and banned.
+    ban_threshold: u32,
+    /// How long a misbehaving peer stays banned.
+    ban_duration_secs: u64,
+    /// Messages per second above which the peer is considered to be flooding us.
+    max_messages_per_second: u32,
+    /// File to persist the ban list in. The ban list is kept in memory only when missing.
+    ban_list_path: Option<PathBuf>,
+}
+
+/// Settings for BIP152 compact block relay.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct CompactBlockSettings {
+    /// Negotiate compact blocks with `sendcmpct` after the handshake.
+    enabled: bool,
+    /// Ask the peer to push new blocks as compact blocks without announcing them first.
+    high_bandwidth: bool,
+}
+
+/// What we tell the peer about ourselves in the version message.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct HandshakeSettings {
+    /// Name, version and comments of the BIP14 user agent, e.g. `/name:version(comment)/`.
+    user_agent_name: String,
+    user_agent_version: String,
+    user_agent_comments: Vec<String>,
+    /// Services we advertise to the peer.
+    services: Vec<Service>,
+    protocol_version: u32,
+    /// Height of our best block, we don't have a chain so it is a fixed value.
+    start_height: i32,
+    /// Whether the peer should announce transactions to us (BIP37).
+    relay: bool,
+}
+
+/// Services that can be advertised in the version message.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Service {
+    Network,
+    GetUtxo,
+    Bloom,
+    Witness,
+    CompactFilters,
+    NetworkLimited,
+}
+
+/// Settings of the crawler that maps the reachable network.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct CrawlerSettings {
+    /// `host:port` of the nodes to start from, DNS seeds resolve to many nodes.
+    seeds: Vec<String>,
+    /// How many nodes are visited at the same time.
+    max_concurrency: usize,
+    /// Time to connect and complete the handshake.
+    handshake_timeout_secs: u64,
+    /// Time to wait for the answer to `getaddr`.
+    getaddr_timeout_secs: u64,
+    /// Stop after visiting this many nodes, no limit when missing.
+    max_nodes: Option<usize>,
+    output_path: PathBuf,
+    output_format: OutputFormat,
+}
+
+/// Limits of the connections to the configured peers.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct ConnectionSettings {
+    /// How many peers are connected at the same time, lower priority peers are only used when
+    /// higher priority ones fail.
+    max_outbound: usize,
+    /// Time to connect and complete the handshake.
+    connect_timeout_secs: u64,
+}
+
+/// Backoff of supervised connections that redial a lost peer.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReconnectSettings {
+    /// Delay before the first attempt, doubled on every failed attempt.
+    initial_delay_ms: u64,
+    max_delay_secs: u64,
+    /// Give up after this many failed attempts in a row, retry forever when missing.
+    max_attempts: Option<u32>,
+}
+
+/// Settings for BIP330 (Erlay) transaction reconciliation.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReconciliationSettings {
+    /// Negotiate reconciliation with `sendtxrcncl` during the handshake. Transactions are
+    /// announced to the peers that support it by reconciling sets instead of flooding.
+    enabled: bool,
+    /// How often we ask the peers we connected to for a reconciliation.
+    request_interval_secs: u64,
+}
+
+/// Settings for BIP133 fee filters.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct FeeFilterSettings {
+    /// Send `feefilter` after the handshake, so the peer does not announce cheaper
+    /// transactions to us.
+    enabled: bool,
+    /// Minimum fee rate in satoshis per 1000 virtual bytes.
+    min_fee_rate: u64,
+}
+
+/// Settings for the network-adjusted time, the median of the clock offsets of the peers.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct NetworkTimeSettings {
+    /// Warn when the network time is further than this away from our clock.
+    warning_threshold_secs: u64,
+    /// Larger offsets are not trusted, our clock is used as it is.
+    max_adjustment_secs: u64,
+    /// Offsets of this many peers are kept, the oldest ones are forgotten.
+    max_samples: usize,
+}
+
+/// A peer from the `[[peers]]` tables.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+pub struct PeerSettings {
+    address: PeerAddress,
+    /// The `peer_network` when missing.
+    #[serde(default)]
+    network: Option<bitcoin::Network>,
+    /// SOCKS5 proxy to connect through, e.g. Tor.
+    #[serde(default)]
+    proxy: Option<SocketAddr>,
+    /// Peers with a higher priority are connected first.
+    #[serde(default)]
+    priority: u32,
+    #[serde(default)]
+    handshake: HandshakeOverrides,
+}
+
+/// The address of a peer, `ip:port` or `host:port`. Host names are resolved by the proxy of
+/// the peer when it has one (e.g. `.onion` addresses by Tor), otherwise by us.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PeerAddress {
+    Socket(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+/// Parts of the `[handshake]` settings that are different for a peer.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HandshakeOverrides {
+    user_agent_name: Option<String>,
+    user_agent_version: Option<String>,
+    user_agent_comments: Option<Vec<String>>,
+    services: Option<Vec<Service>>,
+    protocol_version: Option<u32>,
+    start_height: Option<i32>,
+    relay: Option<bool>,
+}
+
+/// File format of the crawl results.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// One JSON object per line.
+    JsonLines,
+    Csv,
 }
 
 impl Settings {
+    /// The base config with overrides from the environment.
+    ///
+    /// # Panics
+    ///
+    /// When the settings are invalid, use [`Settings::load`] to handle the error.
     pub fn new() -> Self {
-        Config::builder()
-            .add_source(File::from_str(BASE_CONFIG, FileFormat::Toml))
-            // Overrides from the environment
-            .add_source(Environment::default())
-            .build()
-            .unwrap()
-            .try_deserialize()
-            .unwrap()
+        Self::load(None).unwrap_or_else(|err| panic!("{err}"))
     }
 
     pub fn peer_address(&self) -> SocketAddr {
@@ -35,6 +257,229 @@ impl Settings {
     pub fn sender_address(&self) -> SocketAddr {
         self.sender_address
     }
+
+    pub fn capture(&self) -> Option<&CaptureSettings> {
+        self.capture.as_ref()
+    }
+
+    pub fn misbehavior(&self) -> &MisbehaviorSettings {
+        &self.misbehavior
+    }
+
+    pub fn send_buffer_limit(&self) -> usize {
+        self.send_buffer_limit
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn log_level(&self) -> &str {
+        &self.log_level
+    }
+
+    pub fn compact_blocks(&self) -> CompactBlockSettings {
+        self.compact_blocks
+    }
+
+    pub fn handshake(&self) -> &HandshakeSettings {
+        &self.handshake
+    }
+
+    pub fn crawler(&self) -> &CrawlerSettings {
+        &self.crawler
+    }
+
+    pub fn connections(&self) -> &ConnectionSettings {
+        &self.connections
+    }
+
+    pub fn reconnect(&self) -> &ReconnectSettings {
+        &self.reconnect
+    }
+
+    pub fn reconciliation(&self) -> &ReconciliationSettings {
+        &self.reconciliation
+    }
+
+    pub fn fee_filter(&self) -> &FeeFilterSettings {
+        &self.fee_filter
+    }
+
+    pub fn network_time(&self) -> &NetworkTimeSettings {
+        &self.network_time
+    }
+
+    /// The configured peers with their network filled in, or the `peer_address` when there are
+    /// none.
+    pub fn peers(&self) -> Vec<PeerSettings> {
+        if self.peers.is_empty() {
+            return vec![PeerSettings::new(self.peer_address, self.peer_network)];
+        }
+        self.peers
+            .iter()
+            .cloned()
+            .map(|mut peer| {
+                peer.network.get_or_insert(self.peer_network);
+                peer
+            })
+            .collect()
+    }
+
+    /// Check whether running connections can switch to the `new` settings. The peers, the
+    /// ping interval, the log level, the ban list file, the connection limits and the crawler
+    /// settings can be changed live, everything else is part of the connection or of the
+    /// handshake.
+    pub fn check_live_update(&self, new: &Settings) -> Result<(), SettingsError> {
+        let without_ban_list = |misbehavior: &MisbehaviorSettings| MisbehaviorSettings {
+            ban_list_path: None,
+            ..misbehavior.clone()
+        };
+        let requires_reconnect = [
+            ("peer_network", self.peer_network != new.peer_network),
+            ("sender_address", self.sender_address != new.sender_address),
+            (
+                "send_buffer_limit",
+                self.send_buffer_limit != new.send_buffer_limit,
+            ),
+            ("capture", self.capture != new.capture),
+            (
+                "misbehavior",
+                without_ban_list(&self.misbehavior) != without_ban_list(&new.misbehavior),
+            ),
+            ("compact_blocks", self.compact_blocks != new.compact_blocks),
+            ("handshake", self.handshake != new.handshake),
+            ("reconnect", self.reconnect != new.reconnect),
+            ("reconciliation", self.reconciliation != new.reconciliation),
+            ("fee_filter", self.fee_filter != new.fee_filter),
+        ];
+        match requires_reconnect.into_iter().find(|(_, changed)| *changed) {
+            Some((key, _)) => Err(SettingsError::RequiresReconnect {
+                key: key.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that serde can't do, e.g. that the port of the peer fits its network.
+    pub(crate) fn validate(&self) -> Result<(), SettingsError> {
+        check_port("peer_address", self.peer_address.port(), self.peer_network)?;
+        check_log_level(&self.log_level)?;
+        for (index, peer) in self.peers.iter().enumerate() {
+            let network = peer.network.unwrap_or(self.peer_network);
+            let key = format!("peers[{index}].address");
+            check_port(&key, peer.address.port(), network)?;
+            if peer.address.is_onion() && peer.proxy.is_none() {
+                return Err(SettingsError::invalid(&key, "onion peers need a proxy"));
+            }
+        }
+
+        let positive = [
+            ("send_buffer_limit", self.send_buffer_limit as u64),
+            ("ping_interval_secs", self.ping_interval_secs),
+            (
+                "misbehavior.ban_threshold",
+                u64::from(self.misbehavior.ban_threshold),
+            ),
+            (
+                "crawler.max_concurrency",
+                self.crawler.max_concurrency as u64,
+            ),
+            (
+                "connections.max_outbound",
+                self.connections.max_outbound as u64,
+            ),
+            (
+                "connections.connect_timeout_secs",
+                self.connections.connect_timeout_secs,
+            ),
+            (
+                "reconnect.initial_delay_ms",
+                self.reconnect.initial_delay_ms,
+            ),
+            (
+                "reconciliation.request_interval_secs",
+                self.reconciliation.request_interval_secs,
+            ),
+            (
+                "network_time.max_samples",
+                self.network_time.max_samples as u64,
+            ),
+            (
+                "capture.max_file_size",
+                self.capture
+                    .as_ref()
+                    .map_or(1, |capture| capture.max_file_size),
+            ),
+        ];
+        match positive.into_iter().find(|(_, value)| *value == 0) {
+            Some((key, _)) => Err(SettingsError::invalid(key, "must be greater than 0")),
+            None => Ok(()),
+        }
+    }
+
+    fn default_ping_interval_secs() -> u64 {
+        // Same as bitcoin core
+        2 * 60
+    }
+
+    fn default_log_level() -> String {
+        "info".to_string()
+    }
+
+    fn default_send_buffer_limit() -> usize {
+        // Same as the default -maxsendbuffer of bitcoin core
+        1_000_000
+    }
+}
+
+/// Fails when the port is the default port of another network than the one of the peer.
+fn check_port(key: &str, port: u16, network: bitcoin::Network) -> Result<(), SettingsError> {
+    let port_network = [
+        bitcoin::Network::Bitcoin,
+        bitcoin::Network::Testnet,
+        bitcoin::Network::Signet,
+        bitcoin::Network::Regtest,
+    ]
+    .into_iter()
+    .find(|port_network| default_port(*port_network) == port);
+    match port_network.filter(|port_network| *port_network != network) {
+        Some(port_network) => Err(SettingsError::invalid(
+            key,
+            format!(
+                "port {port} is the default port of {port_network}, but the network is {network}"
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Fails when a directive of the `log_level` filter names an unknown level, e.g. `node=loud`.
+/// Directives are `target[span{field=value}]=level`, a bare level or a bare target.
+fn check_log_level(log_level: &str) -> Result<(), SettingsError> {
+    for directive in log_level.split(',').map(str::trim) {
+        let level = match directive.rsplit_once('=') {
+            Some((_, level)) if !level.contains([']', '}']) => level,
+            _ => continue,
+        };
+        if level.parse::<LevelFilter>().is_err() {
+            return Err(SettingsError::invalid(
+                "log_level",
+                format!("unknown level {level:?} in directive {directive:?}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The port nodes of the network listen on unless configured otherwise.
+fn default_port(network: bitcoin::Network) -> u16 {
+    match network {
+        bitcoin::Network::Bitcoin => 8333,
+        bitcoin::Network::Testnet => 18333,
+        bitcoin::Network::Signet => 38333,
+        bitcoin::Network::Regtest => 18444,
+    }
 }
 
 impl Default for Settings {
@@ -42,3 +487,547 @@ impl Default for Settings {
         Self::new()
     }
 }
+
+impl CaptureSettings {
+    pub fn new(path: PathBuf, max_file_size: u64) -> Self {
+        Self {
+            path,
+            max_file_size,
+        }
+    }
+
+    fn default_max_file_size() -> u64 {
+        // 100 MiB
+        100 * 1024 * 1024
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+}
+
+impl MisbehaviorSettings {
+    pub fn ban_threshold(&self) -> u32 {
+        self.ban_threshold
+    }
+
+    pub fn ban_duration_secs(&self) -> u64 {
+        self.ban_duration_secs
+    }
+
+    pub fn max_messages_per_second(&self) -> u32 {
+        self.max_messages_per_second
+    }
+
+    pub fn ban_list_path(&self) -> Option<&Path> {
+        self.ban_list_path.as_deref()
+    }
+}
+
+impl Default for MisbehaviorSettings {
+    fn default() -> Self {
+        Self {
+            ban_threshold: 100,
+            // 24 hours, same as bitcoin core
+            ban_duration_secs: 24 * 60 * 60,
+            max_messages_per_second: 1000,
+            ban_list_path: None,
+        }
+    }
+}
+
+impl CompactBlockSettings {
+    pub fn new(enabled: bool, high_bandwidth: bool) -> Self {
+        Self {
+            enabled,
+            high_bandwidth,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn high_bandwidth(&self) -> bool {
+        self.high_bandwidth
+    }
+}
+
+impl Default for CompactBlockSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            high_bandwidth: false,
+        }
+    }
+}
+
+impl HandshakeSettings {
+    pub fn user_agent_name(&self) -> &str {
+        &self.user_agent_name
+    }
+
+    pub fn user_agent_version(&self) -> &str {
+        &self.user_agent_version
+    }
+
+    pub fn user_agent_comments(&self) -> &[String] {
+        &self.user_agent_comments
+    }
+
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+            .iter()
+            .fold(ServiceFlags::NONE, |services, service| {
+                services | service.flag()
+            })
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn start_height(&self) -> i32 {
+        self.start_height
+    }
+
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
+}
+
+impl Default for HandshakeSettings {
+    fn default() -> Self {
+        Self {
+            user_agent_name: "bitcoin-handshake".to_string(),
+            user_agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            user_agent_comments: Vec::new(),
+            services: Vec::new(),
+            // Compact blocks version 2 and wtxid relay need at least 70016
+            protocol_version: 70016,
+            start_height: 0,
+            relay: false,
+        }
+    }
+}
+
+impl Service {
+    pub fn flag(&self) -> ServiceFlags {
+        match self {
+            Service::Network => ServiceFlags::NETWORK,
+            Service::GetUtxo => ServiceFlags::GETUTXO,
+            Service::Bloom => ServiceFlags::BLOOM,
+            Service::Witness => ServiceFlags::WITNESS,
+            Service::CompactFilters => ServiceFlags::COMPACT_FILTERS,
+            Service::NetworkLimited => ServiceFlags::NETWORK_LIMITED,
+        }
+    }
+}
+
+impl CrawlerSettings {
+    pub fn seeds(&self) -> &[String] {
+        &self.seeds
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn getaddr_timeout(&self) -> Duration {
+        Duration::from_secs(self.getaddr_timeout_secs)
+    }
+
+    pub fn max_nodes(&self) -> Option<usize> {
+        self.max_nodes
+    }
+
+    pub fn output_path(&self) -> &Path {
+        &self.output_path
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+}
+
+impl Default for CrawlerSettings {
+    fn default() -> Self {
+        Self {
+            seeds: Vec::new(),
+            max_concurrency: 32,
+            handshake_timeout_secs: 10,
+            getaddr_timeout_secs: 30,
+            max_nodes: None,
+            output_path: PathBuf::from("crawl.jsonl"),
+            output_format: OutputFormat::JsonLines,
+        }
+    }
+}
+
+impl ConnectionSettings {
+    pub fn max_outbound(&self) -> usize {
+        self.max_outbound
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            // Same as the full relay outbound connections of bitcoin core
+            max_outbound: 8,
+            connect_timeout_secs: 10,
+        }
+    }
+}
+
+impl ReconnectSettings {
+    pub fn initial_delay(&self) -> Duration {
+        Duration::from_millis(self.initial_delay_ms)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs(self.max_delay_secs)
+    }
+
+    pub fn max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 500,
+            max_delay_secs: 60,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconciliationSettings {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Self::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn request_interval(&self) -> Duration {
+        Duration::from_secs(self.request_interval_secs)
+    }
+}
+
+impl Default for ReconciliationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            // Same as bitcoin core
+            request_interval_secs: 8,
+        }
+    }
+}
+
+impl FeeFilterSettings {
+    pub fn new(enabled: bool, min_fee_rate: u64) -> Self {
+        Self {
+            enabled,
+            min_fee_rate,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn min_fee_rate(&self) -> u64 {
+        self.min_fee_rate
+    }
+}
+
+impl Default for FeeFilterSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            // Same as the default -minrelaytxfee of bitcoin core
+            min_fee_rate: 1000,
+        }
+    }
+}
+
+impl NetworkTimeSettings {
+    pub fn warning_threshold(&self) -> Duration {
+        Duration::from_secs(self.warning_threshold_secs)
+    }
+
+    pub fn max_adjustment(&self) -> Duration {
+        Duration::from_secs(self.max_adjustment_secs)
+    }
+
+    pub fn max_samples(&self) -> usize {
+        self.max_samples
+    }
+}
+
+impl Default for NetworkTimeSettings {
+    fn default() -> Self {
+        Self {
+            warning_threshold_secs: 10 * 60,
+            // Same as the default -maxtimeadjustment of bitcoin core
+            max_adjustment_secs: 70 * 60,
+            max_samples: 200,
+        }
+    }
+}
+
+impl PeerSettings {
+    pub fn new(address: impl Into<PeerAddress>, network: bitcoin::Network) -> Self {
+        Self {
+            address: address.into(),
+            network: Some(network),
+            proxy: None,
+            priority: 0,
+            handshake: HandshakeOverrides::default(),
+        }
+    }
+
+    pub fn with_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn address(&self) -> &PeerAddress {
+        &self.address
+    }
+
+    /// Missing when the peer is on the `peer_network`, see [`Settings::peers`].
+    pub fn network(&self) -> Option<bitcoin::Network> {
+        self.network
+    }
+
+    pub fn proxy(&self) -> Option<SocketAddr> {
+        self.proxy
+    }
+
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    pub fn handshake(&self) -> &HandshakeOverrides {
+        &self.handshake
+    }
+}
+
+impl PeerAddress {
+    pub fn port(&self) -> u16 {
+        match self {
+            PeerAddress::Socket(address) => address.port(),
+            PeerAddress::Host { port, .. } => *port,
+        }
+    }
+
+    /// Tor onion services can only be reached through a proxy.
+    pub fn is_onion(&self) -> bool {
+        matches!(self, PeerAddress::Host { host, .. } if host.ends_with(".onion"))
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(address: SocketAddr) -> Self {
+        PeerAddress::Socket(address)
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse() {
+            return Ok(PeerAddress::Socket(address));
+        }
+        let invalid = || format!("{s:?} is neither ip:port nor host:port");
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let valid_host = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !valid_host {
+            return Err(invalid());
+        }
+        Ok(PeerAddress::Host {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for PeerAddress {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        address.parse()
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddress::Socket(address) => write!(f, "{address}"),
+            PeerAddress::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl HandshakeOverrides {
+    pub fn user_agent_name(&self) -> Option<&str> {
+        self.user_agent_name.as_deref()
+    }
+
+    pub fn user_agent_version(&self) -> Option<&str> {
+        self.user_agent_version.as_deref()
+    }
+
+    pub fn user_agent_comments(&self) -> Option<&[String]> {
+        self.user_agent_comments.as_deref()
+    }
+
+    pub fn services(&self) -> Option<ServiceFlags> {
+        self.services.as_ref().map(|services| {
+            services
+                .iter()
+                .fold(ServiceFlags::NONE, |services, service| {
+                    services | service.flag()
+                })
+        })
+    }
+
+    pub fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+
+    pub fn start_height(&self) -> Option<i32> {
+        self.start_height
+    }
+
+    pub fn relay(&self) -> Option<bool> {
+        self.relay
+    }
+
+    /// Whether any part of the user agent is overridden.
+    pub fn overrides_user_agent(&self) -> bool {
+        self.user_agent_name.is_some()
+            || self.user_agent_version.is_some()
+            || self.user_agent_comments.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SettingsLoader;
+
+    /// A loader that ignores the env variables of the process.
+    fn loader() -> SettingsLoader {
+        SettingsLoader::new().env(Vec::<(String, String)>::new())
+    }
+
+    #[test]
+    fn log_level_is_validated() {
+        let settings = loader().load().unwrap();
+        assert_eq!(settings.log_level(), "info");
+        let settings = loader().set("log_level", "node=debug,info").load().unwrap();
+        assert_eq!(settings.log_level(), "node=debug,info");
+        assert!(loader()
+            .set("log_level", "warn,node[handshake{peer=a}]=TRACE")
+            .load()
+            .is_ok());
+        assert!(matches!(
+            loader().set("log_level", "node=loud").load(),
+            Err(SettingsError::InvalidValue { key, .. }) if key == "log_level"
+        ));
+    }
+
+    #[test]
+    fn peer_addresses_are_parsed() {
+        let address = "127.0.0.1:8333".parse::<PeerAddress>().unwrap();
+        assert_eq!(
+            address,
+            PeerAddress::Socket("127.0.0.1:8333".parse().unwrap())
+        );
+        let address = "[::1]:18444".parse::<PeerAddress>().unwrap();
+        assert_eq!(address.port(), 18444);
+
+        let onion = "abcdefghijklmnop.onion:8333"
+            .parse::<PeerAddress>()
+            .unwrap();
+        assert!(onion.is_onion());
+        assert_eq!(onion.to_string(), "abcdefghijklmnop.onion:8333");
+        let host = "seed.example.org:8333".parse::<PeerAddress>().unwrap();
+        assert!(!host.is_onion());
+
+        for invalid in [
+            "example.org",
+            "example.org:port",
+            ":8333",
+            "exa mple.org:8333",
+        ] {
+            assert!(invalid.parse::<PeerAddress>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn onion_peers_need_a_proxy() {
+        let config = |peer: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "settings-peers-{}-{}.toml",
+                std::process::id(),
+                peer.len()
+            ));
+            std::fs::write(&path, format!("[[peers]]\n{peer}\n")).unwrap();
+            let settings = loader().file(&path).load();
+            std::fs::remove_file(path).unwrap();
+            settings
+        };
+        let settings =
+            config("address = \"abcdefghijklmnop.onion:8333\"\nproxy = \"127.0.0.1:9050\"")
+                .unwrap();
+        assert!(settings.peers()[0].address().is_onion());
+        assert!(matches!(
+            config("address = \"abcdefghijklmnop.onion:8333\""),
+            Err(SettingsError::InvalidValue { key, .. }) if key == "peers[0].address"
+        ));
+    }
+
+    #[test]
+    fn log_level_changes_live() {
+        let settings = loader().load().unwrap();
+        let debug = loader().set("log_level", "debug").load().unwrap();
+        assert!(settings.check_live_update(&debug).is_ok());
+
+        let relay = loader().set("handshake.relay", "true").load().unwrap();
+        assert!(matches!(
+            settings.check_live_update(&relay),
+            Err(SettingsError::RequiresReconnect { key }) if key == "handshake"
+        ));
+    }
+}
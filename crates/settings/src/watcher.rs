@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::watch;
+
+use crate::error::SettingsError;
+use crate::loader::SettingsLoader;
+use crate::settings::Settings;
+
+/// Reloads the settings when the config file changes and publishes them over a watch channel.
+///
+/// The file is polled, editors that replace the file instead of writing to it are picked up
+/// too. Reloaded settings that are invalid, or that change something running connections can't
+/// switch to (see [`Settings::check_live_update`]), are logged and ignored.
+#[derive(Clone, Debug)]
+pub struct SettingsWatcher {
+    loader: SettingsLoader,
+    poll_interval: Duration,
+}
+
+impl SettingsWatcher {
+    /// Watch the config file of the loader. Env variables and overrides are applied again on
+    /// every reload.
+    pub fn new(loader: SettingsLoader) -> Self {
+        Self {
+            loader,
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Load the settings and watch the file from a task on the current runtime. The task stops
+    /// once all receivers are dropped.
+    pub fn spawn(self) -> Result<watch::Receiver<Settings>, SettingsError> {
+        let settings = self.loader.clone().load()?;
+        let (sender, receiver) = watch::channel(settings);
+        let Some(path) = self.loader.config_file().map(Path::to_path_buf) else {
+            tracing::warn!("No config file to watch, the settings will not be reloaded");
+            return Ok(receiver);
+        };
+
+        tokio::spawn(async move {
+            let mut last_modified = modified(&path);
+            let mut interval = tokio::time::interval(self.poll_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = sender.closed() => break,
+                }
+                let modified = modified(&path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                let reloaded = self.loader.clone().load().and_then(|reloaded| {
+                    sender.borrow().check_live_update(&reloaded)?;
+                    Ok(reloaded)
+                });
+                match reloaded {
+                    Ok(reloaded) => {
+                        if sender.send_if_modified(|settings| {
+                            let changed = *settings != reloaded;
+                            *settings = reloaded;
+                            changed
+                        }) {
+                            tracing::info!("Reloaded the settings from {}", path.display());
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!("Ignoring the changes to {}: {}", path.display(), err)
+                    }
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+/// The modification time and the size, `None` while the file is missing.
+fn modified(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}